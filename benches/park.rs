@@ -0,0 +1,25 @@
+//! Measures the cost of `park` on the uncontended path, where the parker was already unparked and
+//! `park` returns without going to sleep.
+//!
+//! Run with `cargo bench --bench park`.
+use std::time::Instant;
+
+use valet_parking::Parker;
+
+const ROUNDS: usize = 10_000_000;
+
+fn main() {
+    let parker = Parker::new();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        parker.unpark();
+        parker.park(None);
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "unpark + uncontended park: {:>6.2} ns/iter",
+        elapsed.as_nanos() as f64 / ROUNDS as f64
+    );
+}
@@ -0,0 +1,54 @@
+//! Ping-pong between pairs of threads with `Parker`s, with more threads than cores. Compares going
+//! to sleep right away with spinning first, as configured with `set_default_park_spins`.
+//!
+//! Run with `cargo bench --bench park_spin`.
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use valet_parking::{set_default_park_spins, Parker};
+
+const ROUNDS: usize = 20_000;
+
+fn ping_pong(pairs: usize) -> Duration {
+    let start = Instant::now();
+    let threads: Vec<_> = (0..pairs)
+        .flat_map(|_| {
+            let parkers = Arc::new([Parker::new(), Parker::new()]);
+            (0..2).map(move |i| {
+                let parkers = parkers.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        if i == 0 {
+                            parkers[1].unpark();
+                            parkers[0].park(None);
+                        } else {
+                            parkers[1].park(None);
+                            parkers[0].unpark();
+                        }
+                    }
+                })
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(4, |n| n.get());
+    // Two threads per pair, so this runs four threads per core.
+    let pairs = cores * 2;
+    for &spins in &[0, 100, 1000] {
+        set_default_park_spins(spins);
+        let elapsed = ping_pong(pairs);
+        println!(
+            "{} threads, {:>4} spins: {:>8.2} ns/round trip",
+            pairs * 2,
+            spins,
+            elapsed.as_nanos() as f64 / (ROUNDS * pairs) as f64
+        );
+    }
+}
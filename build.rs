@@ -0,0 +1,79 @@
+//! Selects the parking backend, and passes it to the crate as `cfg(valet_backend = "...")`.
+//!
+//! It also sets `cfg(valet_futex_api)` on every platform with some futex-like interface, which is
+//! where the public `futex` module is available. That is independent of the backend: Windows and
+//! the `fallback` feature still expose it.
+//!
+//! This is the one place that decides which implementation of `Parker` and `Waiters` is used. The
+//! `fallback` feature selects the slow path on every platform that has one: the Posix condition
+//! variables on all Unix-like platforms, and NT Keyed Events on Windows. This makes it possible to
+//! test the slow path against the fast path on the same machine. Platforms without a slow path,
+//! like Hermit and WebAssembly, ignore the feature.
+use std::env;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!(
+        "cargo:rustc-check-cfg=cfg(valet_backend, values(\"bare_metal\", \"fortanix\", \
+         \"futex\", \"futex_or_posix\", \"haiku\", \"posix\", \"solaris\", \"vxworks\", \
+         \"windows\"))"
+    );
+    println!("cargo:rustc-check-cfg=cfg(valet_futex_api)");
+
+    let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let vendor = env::var("CARGO_CFG_TARGET_VENDOR").unwrap_or_default();
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    let unix = env::var_os("CARGO_CFG_UNIX").is_some();
+    let windows = env::var_os("CARGO_CFG_WINDOWS").is_some();
+    let fallback = env::var_os("CARGO_FEATURE_FALLBACK").is_some();
+    let critical_section = env::var_os("CARGO_FEATURE_CRITICAL_SECTION").is_some();
+    let wasm_atomics = arch == "wasm32" && target_features.split(',').any(|f| f == "atomics");
+
+    let backend = if windows {
+        // Picks between `WaitOnAddress` and NT Keyed Events at runtime, `fallback` forces the
+        // latter.
+        Some("windows")
+    } else if vendor == "fortanix" {
+        Some("fortanix")
+    } else if unix && fallback {
+        Some("posix")
+    } else {
+        match os.as_str() {
+            "android" | "dragonfly" | "emscripten" | "freebsd" | "fuchsia" | "hermit" | "linux"
+            | "openbsd" | "redox" => Some("futex"),
+            // Falls back to Posix at runtime on macOS before 10.12 and NetBSD before 10.
+            "ios" | "macos" | "netbsd" => Some("futex_or_posix"),
+            "haiku" => Some("haiku"),
+            "illumos" | "solaris" => Some("solaris"),
+            "vxworks" => Some("vxworks"),
+            _ if unix => Some("posix"),
+            _ if wasm_atomics => Some("futex"),
+            _ if critical_section => Some("bare_metal"),
+            _ => None,
+        }
+    };
+    let futex_api = windows
+        || wasm_atomics
+        || matches!(
+            os.as_str(),
+            "android"
+                | "dragonfly"
+                | "emscripten"
+                | "freebsd"
+                | "fuchsia"
+                | "hermit"
+                | "linux"
+                | "ios"
+                | "macos"
+                | "netbsd"
+                | "openbsd"
+                | "redox"
+        );
+    if futex_api {
+        println!("cargo:rustc-cfg=valet_futex_api");
+    }
+    if let Some(backend) = backend {
+        println!("cargo:rustc-cfg=valet_backend=\"{}\"", backend);
+    }
+}
@@ -0,0 +1,153 @@
+/// A bounded multi-producer single-consumer channel with `recv_timeout`, built on a `Parker` for
+/// the receiver and `Waiters` for the senders waiting on a full channel.
+///
+/// Doubles as a stress test for timed parking: the receiver uses very short timeouts, so sends
+/// constantly race the expiration of the timeout. No message may get lost, and `recv_timeout` must
+/// return promptly on a send and report `Timeout` when the channel stays empty.
+///
+/// Run in release mode for a useful stress test.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use valet_parking::{FreeBits, Parker, Waiters, FREE_BITS, RESERVED_MASK};
+
+const CAPACITY: usize = 16;
+const N_SENDERS: usize = 4;
+const N_MESSAGES: usize = 100_000;
+
+#[derive(Debug, PartialEq)]
+struct Timeout;
+
+struct Channel<T> {
+    queue: Mutex<VecDeque<T>>,
+    receiver: Parker,
+    // Used with `Waiters`. The receiver advances the generation in the free bits when it makes
+    // room in a full channel.
+    space: AtomicUsize,
+}
+
+impl<T> Channel<T> {
+    fn new() -> Channel<T> {
+        Channel {
+            queue: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+            receiver: Parker::new(),
+            space: AtomicUsize::new(0),
+        }
+    }
+
+    fn send(&self, value: T) {
+        let mut value = Some(value);
+        loop {
+            // Read the generation before looking at the queue, so we can't miss the receiver making
+            // room in between.
+            let generation = self.space.load(Ordering::Relaxed) & !RESERVED_MASK;
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.len() < CAPACITY {
+                    queue.push_back(value.take().unwrap());
+                    break;
+                }
+            }
+            self.space.compare_and_wait(generation);
+        }
+        self.receiver.unpark();
+    }
+
+    // Only one thread may receive.
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, Timeout> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Always check the queue once more after the deadline passed, a message that was sent
+            // while the timeout expired is received instead of lost.
+            let (value, was_full) = {
+                let mut queue = self.queue.lock().unwrap();
+                let was_full = queue.len() == CAPACITY;
+                (queue.pop_front(), was_full)
+            };
+            if let Some(value) = value {
+                if was_full {
+                    let generation = FreeBits::from_atomic(self.space.load(Ordering::Relaxed));
+                    let next = FreeBits::new((generation.get() + 1) % (1 << FREE_BITS));
+                    // Only the receiver stores to `space`, and it never touches the reserved bits.
+                    unsafe { self.space.store_and_wake(next.bits()) };
+                }
+                return Ok(value);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Timeout);
+            }
+            // A send after our check above leaves a notification, and `park` returns immediately.
+            self.receiver.park(Some(deadline - now));
+        }
+    }
+}
+
+fn main() {
+    let channel = Arc::new(Channel::<usize>::new());
+
+    // Returns `Timeout` when idle, not before the timeout expired.
+    let start = Instant::now();
+    assert_eq!(
+        channel.recv_timeout(Duration::from_millis(20)),
+        Err(Timeout)
+    );
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    // Returns promptly on a send, long before the timeout.
+    let sender = {
+        let channel = channel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            channel.send(42);
+        })
+    };
+    let start = Instant::now();
+    assert_eq!(channel.recv_timeout(Duration::from_secs(10)), Ok(42));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    sender.join().unwrap();
+
+    // Sends racing very short timeouts, with senders regularly blocking on a full channel.
+    let start = Instant::now();
+    let senders: Vec<_> = (0..N_SENDERS)
+        .map(|_| {
+            let channel = channel.clone();
+            thread::spawn(move || {
+                for i in 0..N_MESSAGES {
+                    channel.send(i);
+                    if i % 1000 == 0 {
+                        thread::sleep(Duration::from_micros(100));
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut received = 0;
+    // Summed as `u64`, the total does not fit in a 32-bit `usize`.
+    let mut sum = 0u64;
+    let mut timeouts = 0;
+    while received < N_SENDERS * N_MESSAGES {
+        match channel.recv_timeout(Duration::from_micros(10)) {
+            Ok(value) => {
+                received += 1;
+                sum += value as u64;
+            }
+            Err(Timeout) => timeouts += 1,
+        }
+    }
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    let n = N_MESSAGES as u64;
+    assert_eq!(sum, N_SENDERS as u64 * n * (n - 1) / 2);
+    assert_eq!(channel.recv_timeout(Duration::from_millis(1)), Err(Timeout));
+    println!(
+        "received {} messages with {} timeouts in {:?}",
+        received,
+        timeouts,
+        start.elapsed()
+    );
+}
@@ -0,0 +1,66 @@
+/// Wake a worker process that is parked on a `SharedParker` in shared memory.
+///
+/// The supervisor creates a file, maps it with `MAP_SHARED` and starts a copy of itself as the
+/// worker. The worker maps the same file and parks on the `SharedParker` at its start. After a
+/// moment the supervisor unparks it, and waits for the worker to exit.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux"
+))]
+fn main() {
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use memmap2::MmapMut;
+    use valet_parking::SharedParker;
+
+    let mut args = env::args_os().skip(1);
+    let (is_worker, path) = match args.next() {
+        Some(path) => (true, PathBuf::from(path)),
+        None => (false, env::temp_dir().join("valet_parking_shared_parker")),
+    };
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!is_worker)
+        .open(&path)
+        .unwrap();
+    file.set_len(4096).unwrap();
+    // `MmapMut::map_mut` maps the file with `MAP_SHARED`.
+    let mut map = unsafe { MmapMut::map_mut(&file).unwrap() };
+    let parker = unsafe { SharedParker::from_ptr(map.as_mut_ptr()) };
+
+    if is_worker {
+        println!("worker: parking");
+        parker.park(None);
+        println!("worker: unparked by the supervisor");
+    } else {
+        let mut worker = Command::new(env::current_exe().unwrap())
+            .arg(&path)
+            .spawn()
+            .unwrap();
+        sleep(Duration::from_millis(100));
+        println!("supervisor: unparking the worker");
+        parker.unpark();
+        assert!(worker.wait().unwrap().success());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux"
+)))]
+fn main() {
+    println!("`SharedParker` is not available on this platform");
+}
@@ -9,7 +9,7 @@
 use std::cell::UnsafeCell;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use valet_parking::{Waiters, RESERVED_BITS, RESERVED_MASK};
+use valet_parking::{FreeBits, Waiters};
 
 const N_THREADS: usize = 32;
 const N_ROUNDS: usize = 1_000_000;
@@ -41,9 +41,9 @@ fn thread_main(i: usize) {
     assert_eq!(RESULT.get_or_init(accum), &accum);
 }
 
-const INCOMPLETE: usize = 0 << RESERVED_BITS;
-const RUNNING: usize = 1 << RESERVED_BITS;
-const COMPLETE: usize = 2 << RESERVED_BITS;
+const INCOMPLETE: FreeBits = FreeBits::new(0);
+const RUNNING: FreeBits = FreeBits::new(1);
+const COMPLETE: FreeBits = FreeBits::new(2);
 
 struct OnceCell<T> {
     state: AtomicUsize,
@@ -53,13 +53,13 @@ struct OnceCell<T> {
 impl<T> OnceCell<T> {
     const fn new() -> OnceCell<T> {
         OnceCell {
-            state: AtomicUsize::new(INCOMPLETE),
+            state: AtomicUsize::new(INCOMPLETE.bits()),
             value: UnsafeCell::new(None),
         }
     }
 
     fn get(&self) -> Option<&T> {
-        if self.state.load(Ordering::SeqCst) == COMPLETE {
+        if FreeBits::from_atomic(self.state.load(Ordering::SeqCst)) == COMPLETE {
             unsafe { &*self.value.get() }.as_ref()
         } else {
             None
@@ -76,25 +76,27 @@ impl<T> OnceCell<T> {
     fn init(&self, value: T) -> &T {
         let mut state = self.state.load(Ordering::SeqCst);
         loop {
-            match state {
+            match FreeBits::from_atomic(state) {
                 COMPLETE => break,
                 INCOMPLETE => {
-                    let old = self
-                        .state
-                        .compare_and_swap(state, RUNNING, Ordering::SeqCst);
-                    if old != state {
+                    if let Err(old) = self.state.compare_exchange(
+                        state,
+                        RUNNING.bits(),
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    ) {
                         state = old;
                         continue;
                     }
 
                     unsafe { self.value.get().write(Some(value)) };
-                    unsafe { self.state.store_and_wake(COMPLETE) }
-                    assert!(self.state.load(Ordering::SeqCst) & !RESERVED_MASK == COMPLETE);
+                    unsafe { self.state.store_and_wake(COMPLETE.bits()) }
+                    assert!(FreeBits::from_atomic(self.state.load(Ordering::SeqCst)) == COMPLETE);
                     break;
                 }
-                _ => {
-                    assert!(state & !RESERVED_MASK == RUNNING);
-                    self.state.compare_and_wait(RUNNING);
+                free => {
+                    assert!(free == RUNNING);
+                    self.state.compare_and_wait(RUNNING.bits());
                     state = self.state.load(Ordering::SeqCst);
                 }
             }
@@ -0,0 +1,12 @@
+//! Interleave `park`, `unpark` and spurious wakeups on one futex-based `Parker`, and check that no
+//! `unpark` gets lost. Run with `cargo fuzz run park_unpark` from the root of the repository.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use valet_parking::futex::fuzz::park_unpark;
+
+fuzz_target!(|data: &[u8]| {
+    if let Err(e) = park_unpark(data) {
+        panic!("{} (input {:?})", e, data);
+    }
+});
@@ -0,0 +1,190 @@
+//! Implementation for bare-metal targets without an operating system, enabled with the
+//! `critical-section` feature.
+//!
+//! There is no way to put a thread to sleep here, so all waiting is done by spinning. Every
+//! read-modify-write operation of this module on the atomic is done inside a short critical section
+//! provided by the [`critical-section`] crate, and so is every store that has to be ordered with
+//! them.
+//!
+//! The default methods of `Waiters`, like `wait_then_claim` and `wake_all`, still use the native
+//! atomic read-modify-write operations, so the target has to support those. Only single-core
+//! targets are supported: on a multi-core target a critical section does not keep another core
+//! from doing such an operation between the load and the store of a waiter.
+//!
+//! Without a clock there is no way to honor a timeout. A `park` with a timeout spins for a bounded
+//! number of iterations, and then returns as if it woke up spuriously.
+//!
+//! [`critical-section`]: https://docs.rs/critical-section
+use core::hint;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::time::Duration;
+
+use crate::clock::Deadline;
+use crate::{WakeupReason, RESERVED_BITS, RESERVED_MASK};
+
+// Number of iterations to spin for when a timeout is supplied.
+const TIMEOUT_SPINS: usize = 1 << 16;
+
+//
+// Implementation of the Waiters trait
+//
+// We keep a count of the waiting threads in the reserved bits, so `handoff_to_one` can report if
+// there was any thread waiting.
+//
+// The highest reserved bit is flipped by `wake_only`, so spinning threads can notice a wakeup that
+// leaves the free bits unchanged. A thread that doesn't get to look at the atomic between two
+// `wake_only`s in a row misses both of them.
+const WAKE_EPOCH: usize = 1 << (RESERVED_BITS - 1);
+const COUNT_MASK: usize = RESERVED_MASK & !WAKE_EPOCH;
+
+pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
+    let epoch = critical_section::with(|_| {
+        let current = atomic.load(Relaxed);
+        if current & !RESERVED_MASK != expected {
+            return None;
+        }
+        atomic.store(current + 1, Relaxed);
+        Some(current & WAKE_EPOCH)
+    });
+    let epoch = match epoch {
+        Some(epoch) => epoch,
+        None => return,
+    };
+    loop {
+        let current = atomic.load(Relaxed);
+        if current & !RESERVED_MASK != expected || current & WAKE_EPOCH != epoch {
+            break;
+        }
+        hint::spin_loop();
+    }
+}
+
+// Spins for a bounded number of iterations, just like `park` with a timeout.
+pub(crate) fn compare_and_wait_timeout(
+    atomic: &AtomicUsize,
+    expected: usize,
+    _deadline: &mut Deadline,
+) -> bool {
+    let epoch = critical_section::with(|_| {
+        let current = atomic.load(Relaxed);
+        if current & !RESERVED_MASK != expected {
+            return None;
+        }
+        atomic.store(current + 1, Relaxed);
+        Some(current & WAKE_EPOCH)
+    });
+    let epoch = match epoch {
+        Some(epoch) => epoch,
+        None => return true,
+    };
+    for _ in 0..TIMEOUT_SPINS {
+        let current = atomic.load(Relaxed);
+        if current & !RESERVED_MASK != expected || current & WAKE_EPOCH != epoch {
+            return true;
+        }
+        hint::spin_loop();
+    }
+    // Take ourselves out of the count again, unless a store reset it in the meantime.
+    critical_section::with(|_| {
+        let current = atomic.load(Relaxed);
+        if current & !RESERVED_MASK != expected || current & WAKE_EPOCH != epoch {
+            return true;
+        }
+        if current & COUNT_MASK != 0 {
+            atomic.store(current - 1, Relaxed);
+        }
+        false
+    })
+}
+
+pub(crate) fn store_and_wake(atomic: &AtomicUsize, new: usize) {
+    // The spinning waiters will notice the store, there is nothing to wake. Done in a critical
+    // section, so it can't fall between the load and store of a thread that starts waiting.
+    critical_section::with(|_| atomic.store(new, Release));
+}
+
+pub(crate) fn compare_store_and_wake(atomic: &AtomicUsize, expected: usize, new: usize) -> bool {
+    critical_section::with(|_| {
+        if atomic.load(Relaxed) & !RESERVED_MASK != expected {
+            return false;
+        }
+        atomic.store(new, Release);
+        true
+    })
+}
+
+pub(crate) fn handoff_to_one(atomic: &AtomicUsize, new: usize) -> bool {
+    // We can't wake only one of the spinning threads, all of them will see the new value.
+    critical_section::with(|_| {
+        let waiters = atomic.load(Relaxed) & COUNT_MASK;
+        atomic.store(new, Release);
+        waiters != 0
+    })
+}
+
+pub(crate) fn wake_only(atomic: &AtomicUsize) {
+    critical_section::with(|_| {
+        let current = atomic.load(Relaxed);
+        let epoch = (current & WAKE_EPOCH) ^ WAKE_EPOCH;
+        atomic.store((current & !RESERVED_MASK) | epoch, Release);
+    })
+}
+
+// Waiters stop spinning on the next store, which also resets the count.
+pub(crate) fn waiter_count(atomic: &AtomicUsize) -> Option<usize> {
+    Some(atomic.load(Relaxed) & COUNT_MASK)
+}
+
+//
+// Implementation of the Parker trait
+//
+pub(crate) type Parker = AtomicUsize;
+
+const NOT_PARKED: usize = 0x0;
+const NOTIFIED: usize = 0x2;
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    let mut spins = 0;
+    loop {
+        let notified = critical_section::with(|_| {
+            if atomic.load(Relaxed) == NOTIFIED {
+                atomic.store(NOT_PARKED, Relaxed);
+                true
+            } else {
+                false
+            }
+        });
+        if notified {
+            return WakeupReason::WokenUp;
+        }
+        if timeout.is_some() {
+            spins += 1;
+            if spins == TIMEOUT_SPINS {
+                return WakeupReason::Unknown;
+            }
+        }
+        hint::spin_loop();
+    }
+}
+
+// The parked thread spins on its own parker, so it can't be moved to another one.
+pub(crate) fn transfer_waiter(_from: &AtomicUsize, _to: &AtomicUsize) -> bool {
+    false
+}
+
+pub(crate) fn is_notified(atomic: &AtomicUsize) -> bool {
+    atomic.load(Relaxed) == NOTIFIED
+}
+
+pub(crate) fn clear_notification(atomic: &AtomicUsize) -> bool {
+    atomic
+        .compare_exchange(NOTIFIED, NOT_PARKED, Acquire, Relaxed)
+        .is_ok()
+}
+
+// A parked thread spins on the state, there is no wake signal to issue.
+pub(crate) fn unpark(atomic: &AtomicUsize) -> bool {
+    critical_section::with(|_| atomic.store(NOTIFIED, Release));
+    false
+}
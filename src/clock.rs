@@ -0,0 +1,164 @@
+//! A monotonic clock abstraction, so deadlines work without `std::time::Instant`.
+use core::time::Duration;
+
+/// A monotonic clock, used by [`Parker::park_until_with`] to park until a deadline.
+///
+/// The crate can't read a clock by itself in `no_std`. Implement this trait on top of a hardware
+/// timer or the clock of an RTOS to get accurate deadlines on such targets. With the `std` feature
+/// [`StdClock`] implements it on top of `std::time::Instant`.
+///
+/// ```
+/// use core::time::Duration;
+/// use valet_parking::Clock;
+///
+/// // A clock counting microseconds, for example from a hardware timer.
+/// struct TickClock;
+///
+/// impl Clock for TickClock {
+///     type Instant = u64;
+///
+///     fn now(&self) -> u64 {
+///         // Read the timer here.
+///         0
+///     }
+///
+///     fn duration_since(&self, later: u64, earlier: u64) -> Duration {
+///         Duration::from_micros(later.saturating_sub(earlier))
+///     }
+/// }
+/// ```
+///
+/// [`Parker::park_until_with`]: struct.Parker.html#method.park_until_with
+/// [`StdClock`]: struct.StdClock.html
+pub trait Clock {
+    /// A point in time of this clock.
+    type Instant: Copy + Ord;
+
+    /// The current time. Must never go backwards.
+    fn now(&self) -> Self::Instant;
+
+    /// The time elapsed from `earlier` to `later`, or zero if `later` is before `earlier`.
+    fn duration_since(&self, later: Self::Instant, earlier: Self::Instant) -> Duration;
+}
+
+/// The [`Clock`] of the standard library, `std::time::Instant`. Requires the `std` feature.
+///
+/// [`Clock`]: trait.Clock.html
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    type Instant = std::time::Instant;
+
+    fn now(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+
+    fn duration_since(&self, later: std::time::Instant, earlier: std::time::Instant) -> Duration {
+        later.saturating_duration_since(earlier)
+    }
+}
+
+/// A relative timeout that is counted down from the moment it was created.
+///
+/// Waiting functions that may wait more than once, or do some work before they wait, take the
+/// timeout for the next wait from `remaining` right before they go to sleep. Time spent in between
+/// is then counted against the timeout.
+///
+/// Without the `std` feature there is no clock. The first call to `remaining` returns the whole
+/// timeout, and later calls return zero, so a loop waits once and then polls.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline {
+    timeout: Duration,
+    #[cfg(feature = "std")]
+    start: std::time::Instant,
+    #[cfg(not(feature = "std"))]
+    used: bool,
+}
+
+impl Deadline {
+    pub(crate) fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            timeout,
+            #[cfg(feature = "std")]
+            start: std::time::Instant::now(),
+            #[cfg(not(feature = "std"))]
+            used: false,
+        }
+    }
+
+    /// The timeout for the next wait, zero once the deadline passed.
+    pub(crate) fn remaining(&mut self) -> Duration {
+        #[cfg(feature = "std")]
+        {
+            // Subtracting from `timeout` instead of adding it to `start` can't overflow.
+            self.timeout.saturating_sub(self.start.elapsed())
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            if self.used {
+                Duration::from_secs(0)
+            } else {
+                self.used = true;
+                self.timeout
+            }
+        }
+    }
+
+    /// Returns `true` once the deadline passed, or without `std` once the timeout was used.
+    pub(crate) fn expired(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            self.start.elapsed() >= self.timeout
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            self.used
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Deadline;
+    use core::time::Duration;
+
+    // `compare_and_wait_timeout` and `wait_while` keep taking the timeout of their next wait from
+    // the same `Deadline`, so time spent between waits must come off the timeout.
+    #[test]
+    #[cfg(feature = "std")]
+    fn deadline_counts_down() {
+        use std::thread::sleep;
+        const TIMEOUT: Duration = Duration::from_millis(50);
+        const STEP: Duration = Duration::from_millis(20);
+
+        let mut deadline = Deadline::after(TIMEOUT);
+        sleep(STEP);
+        assert!(deadline.remaining() <= TIMEOUT - STEP);
+        sleep(TIMEOUT);
+        assert!(deadline.expired());
+        assert_eq!(deadline.remaining(), Duration::from_secs(0));
+    }
+
+    // Without a clock the whole timeout goes to the first wait, after which the deadline expired.
+    #[test]
+    #[cfg(not(feature = "std"))]
+    fn deadline_without_clock() {
+        const TIMEOUT: Duration = Duration::from_millis(50);
+
+        let mut deadline = Deadline::after(TIMEOUT);
+        assert!(!deadline.expired());
+        assert_eq!(deadline.remaining(), TIMEOUT);
+        assert!(deadline.expired());
+        assert_eq!(deadline.remaining(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn zero_deadline() {
+        let mut deadline = Deadline::after(Duration::from_secs(0));
+        assert_eq!(deadline.remaining(), Duration::from_secs(0));
+        assert!(deadline.expired());
+    }
+}
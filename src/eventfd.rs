@@ -0,0 +1,116 @@
+//! A parker that can be woken through an `eventfd`, for integration with `epoll`-based event
+//! loops. Only available on Linux and Android with the `std` feature.
+use core::time::Duration;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// A parker for one thread, that waits on an `eventfd`.
+///
+/// Event loops built on `epoll` want to be woken from the same call that watches their sockets,
+/// instead of from a separate futex. The file descriptor returned by [`as_raw_fd`] can be
+/// registered with `epoll` for readability. It becomes readable when the parker gets unparked.
+/// After the event loop got woken this way, it should call [`reset`] to consume the notification.
+///
+/// Like with [`Parker`], an `unpark` before the thread is parked is not lost: the next `park`
+/// returns immediately. Multiple unparks before a park are coalesced into one.
+///
+/// [`as_raw_fd`]: #method.as_raw_fd
+/// [`reset`]: #method.reset
+/// [`Parker`]: ../struct.Parker.html
+pub struct EventfdParker {
+    fd: RawFd,
+}
+
+impl EventfdParker {
+    /// Create a new parker, with a new `eventfd`.
+    pub fn new() -> io::Result<EventfdParker> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventfdParker { fd })
+    }
+
+    /// Parks the current thread until another thread calls [`unpark`], or until the timeout
+    /// expires.
+    ///
+    /// If `timeout` is `None` this function will only return after an [`unpark`]. With a timeout
+    /// it may also return spuriously.
+    ///
+    /// [`unpark`]: #method.unpark
+    pub fn park(&self, timeout: Option<Duration>) {
+        let timeout_ms = convert_timeout_ms(timeout);
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let r = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+            debug_assert!(
+                r >= 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EINTR),
+                "Unexpected error of poll: {}",
+                io::Error::last_os_error()
+            );
+            if self.reset() || timeout.is_some() {
+                break;
+            }
+        }
+    }
+
+    /// Unparks the parked thread, or makes the next `park` return immediately if no thread is
+    /// parked yet. Makes the file descriptor readable.
+    pub fn unpark(&self) {
+        let value: u64 = 1;
+        let r = unsafe { libc::write(self.fd, &value as *const u64 as *const libc::c_void, 8) };
+        // `EAGAIN` means the counter is about to overflow, so there is a notification pending
+        // anyway.
+        debug_assert!(
+            r == 8 || io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN),
+            "Unexpected error of write to eventfd: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    /// Consume a pending notification, without blocking. Returns whether there was one.
+    ///
+    /// Should be called by an event loop that got woken because the file descriptor became
+    /// readable.
+    pub fn reset(&self) -> bool {
+        let mut value: u64 = 0;
+        let r = unsafe { libc::read(self.fd, &mut value as *mut u64 as *mut libc::c_void, 8) };
+        r == 8
+    }
+}
+
+impl AsRawFd for EventfdParker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for EventfdParker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Timeout in milliseconds, round nanosecond values up to milliseconds. -1 is infinite.
+#[allow(clippy::manual_div_ceil)]
+fn convert_timeout_ms(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        None => -1,
+        Some(duration) => duration
+            .as_secs()
+            .checked_mul(1000)
+            .and_then(|x| x.checked_add((duration.subsec_nanos() as u64 + 999_999) / 1_000_000))
+            .map(|ms| {
+                if ms > libc::c_int::MAX as u64 {
+                    -1
+                } else {
+                    ms as libc::c_int
+                }
+            })
+            .unwrap_or(-1),
+    }
+}
@@ -1,18 +1,43 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use core::time::Duration;
 
+use std::io;
 use std::os::fortanix_sgx::thread;
 use std::os::fortanix_sgx::usercalls;
 use std::os::fortanix_sgx::usercalls::raw::{Tcs, EV_UNPARK, WAIT_INDEFINITE};
 
 use crate::waiter_queue;
-use crate::{FREE_BITS, RESERVED_MASK};
+use crate::{WakeupReason, FREE_BITS, PARKER_MOVED, RESERVED_MASK};
 
-pub(crate) use waiter_queue::{compare_and_wait, store_and_wake};
+pub(crate) use waiter_queue::{
+    compare_and_wait, compare_and_wait_timeout, compare_store_and_wake, handoff_to_one,
+    store_and_wake, waiter_count, wake_only,
+};
 
 #[repr(align(64))]
 pub struct TcsParker {
     tcs: Tcs,
+    // The atomic that currently holds the pointer to this `TcsParker`. Changes when the waiter is
+    // transferred to another parker, only changed while `NOTIFY_BIT` is set on the old owner.
+    owner: AtomicPtr<AtomicUsize>,
+    // Lets us detect a pointer to a `TcsParker` that was moved or freed while parked.
+    #[cfg(debug_assertions)]
+    canary: usize,
+}
+
+#[cfg(debug_assertions)]
+const CANARY: usize = 0x7061_726b;
+
+impl TcsParker {
+    #[inline]
+    unsafe fn debug_check_canary(ptr: *const TcsParker) {
+        #[cfg(debug_assertions)]
+        assert_eq!((*ptr).canary, CANARY, "{}", PARKER_MOVED);
+    }
+
+    fn owner(&self) -> &AtomicUsize {
+        unsafe { &*self.owner.load(Ordering::SeqCst) }
+    }
 }
 
 // # State table (of the reserved bits):
@@ -24,7 +49,7 @@ pub struct TcsParker {
 //          |            | the wakeup must be spurious and it should park itself again.
 // ---------+------------+------------------------------------------------------------------
 //   some   |     1      | Thread is still parked, but some thread is in the process of
-//          |            | waking it up.
+//          |            | waking it up or transferring it to another parker.
 // ---------+------------+------------------------------------------------------------------
 //     0    |     1      | Thread got woken up by another thread.
 // ---------+------------+------------------------------------------------------------------
@@ -32,18 +57,25 @@ pub struct TcsParker {
 const NOTIFY_BIT: usize = 1;
 const PTR_BITS: usize = RESERVED_MASK ^ NOTIFY_BIT;
 
-// Returns false if the wakeup was because of the timeout, or spurious.
-pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) {
-    if timeout.is_some() {
-        panic!("Timeouts for usercalls::wait are supported in Fortanix SGX");
-    }
+pub(crate) type Parker = AtomicUsize;
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
     let parker = TcsParker {
         tcs: thread::current(),
+        owner: AtomicPtr::new(atomic as *const AtomicUsize as *mut AtomicUsize),
+        #[cfg(debug_assertions)]
+        canary: CANARY,
     };
     let ptr = (&parker as *const TcsParker as usize) >> FREE_BITS;
 
     let mut current = atomic.load(Ordering::SeqCst);
     loop {
+        // If the old state had its `NOTIFY_BIT` set, some other thread unparked us even before we
+        // were able to park ourselves.
+        if current & RESERVED_MASK == NOTIFY_BIT {
+            atomic.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+            return WakeupReason::WokenUp;
+        }
         if current & RESERVED_MASK != 0 {
             // See https://github.com/fortanix/rust-sgx/issues/31
             panic!("Tried to call park on an atomic while another thread is already parked on it");
@@ -51,35 +83,172 @@ pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) {
         match atomic.compare_exchange_weak(
             current,
             current | ptr,
-            Ordering::Relaxed,
-            Ordering::Relaxed,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
         ) {
             Ok(_) => break,
             Err(x) => current = x,
         }
     }
+    let mut wait_timeout = convert_timeout_ns(timeout);
+    loop {
+        let r = usercalls::wait(EV_UNPARK, wait_timeout);
+        // We may have been transferred to another parker while waiting.
+        let owner = parker.owner();
+        if owner.load(Ordering::SeqCst) & RESERVED_MASK == NOTIFY_BIT {
+            // The unparking thread accessed `parker` through `ptr`, which must still point to it.
+            debug_assert_eq!(
+                (&parker as *const TcsParker as usize) >> FREE_BITS,
+                ptr,
+                "{}",
+                PARKER_MOVED
+            );
+            owner.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+            return WakeupReason::WokenUp;
+        }
+        if timeout.is_none() {
+            if let Err(e) = r {
+                debug_assert!(false, "Unexpected return value of usercalls::wait: {}", e);
+            }
+            continue;
+        }
+        // The timeout expired, the wait returned spuriously, or we were transferred while timing
+        // out. Stop waiting, unless another thread is busy waking or transferring us.
+        if try_cancel(owner) {
+            return match r {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => WakeupReason::TimedOut,
+                _ => WakeupReason::Unknown,
+            };
+        }
+        // The unparking or transferring thread may still read `parker`, and is about to send us an
+        // event. Wait for it, so it doesn't wake a later `park` instead.
+        wait_timeout = WAIT_INDEFINITE;
+    }
+}
+
+// Try to remove our pointer from the atomic after a timed wait returned. Returns `false` if another
+// thread set `NOTIFY_BIT` and is going to wake or transfer us.
+fn try_cancel(atomic: &AtomicUsize) -> bool {
+    let mut current = atomic.load(Ordering::SeqCst);
+    loop {
+        if current & NOTIFY_BIT == NOTIFY_BIT {
+            return false;
+        }
+        match atomic.compare_exchange_weak(
+            current,
+            current & !PTR_BITS,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => return true,
+            Err(x) => current = x,
+        }
+    }
+}
+
+// Timeout in nanoseconds. `usercalls::wait` treats `WAIT_NO` (0) as a poll, and
+// `WAIT_INDEFINITE` (`u64::MAX`) as no timeout, so longer timeouts also become indefinite. Std adds
+// a random deviation of up to 10% to other values, as the enclave runner is not trusted to keep
+// time.
+fn convert_timeout_ns(timeout: Option<Duration>) -> u64 {
+    match timeout {
+        None => WAIT_INDEFINITE,
+        Some(duration) => {
+            let ns = duration.as_nanos();
+            if ns >= WAIT_INDEFINITE as u128 {
+                WAIT_INDEFINITE
+            } else {
+                ns as u64
+            }
+        }
+    }
+}
+
+pub(crate) unsafe fn transfer_waiter(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    // Set the `NOTIFY_BIT`, just like `unpark`. This keeps the parked thread from returning while
+    // we access its `TcsParker`, and keeps other threads from unparking it concurrently.
+    let mut current = from.load(Ordering::SeqCst);
     loop {
-        let r = usercalls::wait(EV_UNPARK, WAIT_INDEFINITE);
-        if let Err(e) = r {
-            debug_assert!(false, "Unexpected return value of usercalls::wait: {}", e);
+        if current & PTR_BITS == 0 || current & NOTIFY_BIT == NOTIFY_BIT {
+            // No thread parked, or it is already being unparked.
+            return false;
+        }
+        match from.compare_exchange(
+            current,
+            current | NOTIFY_BIT,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => break,
+            Err(x) => current = x,
         }
-        if atomic.load(Ordering::Relaxed) & RESERVED_MASK == NOTIFY_BIT {
-            break;
+    }
+    let ptr_bits = current & PTR_BITS;
+    let ptr = (ptr_bits << FREE_BITS) as *const TcsParker;
+    TcsParker::debug_check_canary(ptr);
+    let target_tcs = (*ptr).tcs;
+
+    let mut to_current = to.load(Ordering::SeqCst);
+    let moved = loop {
+        let (new, moved) = match to_current & RESERVED_MASK {
+            0 => (to_current | ptr_bits, true),
+            // `to` was unparked before a thread parked on it. Consume the notification and wake
+            // the thread, as if it parked on `to` itself.
+            NOTIFY_BIT => (to_current & !NOTIFY_BIT, false),
+            _ => {
+                // Undo, and let the parked thread continue if it is waiting for us.
+                from.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+                let _ = usercalls::send(EV_UNPARK, Some(target_tcs));
+                panic!(
+                    "Tried to transfer a waiter to a parker that already has a thread parked on it"
+                );
+            }
+        };
+        match to.compare_exchange(to_current, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break moved,
+            Err(x) => to_current = x,
         }
+    };
+    if moved {
+        (*ptr).owner.store(
+            to as *const AtomicUsize as *mut AtomicUsize,
+            Ordering::SeqCst,
+        );
+        from.fetch_and(!RESERVED_MASK, Ordering::SeqCst);
+    } else {
+        from.fetch_and(!PTR_BITS, Ordering::SeqCst);
+    }
+    // From here on the `TcsParker` may get freed. If the waiting thread woke up from a timeout it
+    // is waiting for this event, because it saw the `NOTIFY_BIT`. After a transfer it will check
+    // the state of `to`.
+    let r = usercalls::send(EV_UNPARK, Some(target_tcs));
+    if let Err(e) = r {
+        debug_assert!(false, "Unexpected return value of usercalls::send: {}", e);
     }
+    true
+}
+
+pub(crate) fn is_notified(atomic: &AtomicUsize) -> bool {
+    atomic.load(Ordering::Relaxed) & RESERVED_MASK == NOTIFY_BIT
+}
+
+pub(crate) fn clear_notification(atomic: &AtomicUsize) -> bool {
+    atomic
+        .compare_exchange(NOTIFY_BIT, 0, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
 }
 
-pub(crate) unsafe fn unpark(atomic: &AtomicUsize) {
+pub(crate) unsafe fn unpark(atomic: &AtomicUsize) -> bool {
     let old = atomic.fetch_or(NOTIFY_BIT, Ordering::SeqCst);
     match (old & PTR_BITS, old & NOTIFY_BIT == NOTIFY_BIT) {
         (_, true) => {
             // Some other thread must be in the process of unparking the suspended thread.
             // There is nothing for us to do.
-            return;
+            return false;
         }
         (0, false) => {
             // There is no thread to wake up, maybe it didn't even get to parking itself yet.
-            return;
+            return false;
         }
         (_, false) => {} // Good to go.
     }
@@ -87,13 +256,15 @@ pub(crate) unsafe fn unpark(atomic: &AtomicUsize) {
     // The parked thread will not return from `self.park` while `NOTIFY_BIT` and a pointer is
     // set, so we can safely access data on its stack through the pointer encoded in `self`.
     let ptr = ((old & PTR_BITS) << FREE_BITS) as *const TcsParker;
+    TcsParker::debug_check_canary(ptr);
     let target_tcs = (*ptr).tcs;
 
     // Remove the pointer bits, from now on the `TcsParker` may get freed (if the thread wakes up
     // spuriously).
-    &atomic.fetch_and(!PTR_BITS, Ordering::Relaxed);
+    atomic.fetch_and(!PTR_BITS, Ordering::Relaxed);
     let r = usercalls::send(EV_UNPARK, Some(target_tcs));
     if let Err(e) = r {
         debug_assert!(false, "Unexpected return value of usercalls::send: {}", e);
     }
+    true
 }
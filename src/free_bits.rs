@@ -0,0 +1,52 @@
+use crate::{FREE_BITS, RESERVED_BITS, RESERVED_MASK};
+
+/// A value stored in the five non-reserved high order bits of an atomic used with
+/// [`Waiters`](trait.Waiters.html).
+///
+/// Does the shifting and masking, so user code doesn't have to compute `value << RESERVED_BITS`
+/// by hand:
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use valet_parking::{FreeBits, Waiters};
+///
+/// const RUNNING: FreeBits = FreeBits::new(1);
+/// const COMPLETE: FreeBits = FreeBits::new(2);
+///
+/// let state = AtomicUsize::new(RUNNING.bits());
+/// unsafe { state.store_and_wake(COMPLETE.bits()) };
+/// assert_eq!(FreeBits::from_atomic(state.load(Ordering::Relaxed)), COMPLETE);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FreeBits(usize);
+
+impl FreeBits {
+    /// Position `value` in the free bits.
+    ///
+    /// # Panics
+    /// Panics if `value` does not fit in [`FREE_BITS`](constant.FREE_BITS.html) bits. In a
+    /// `const` this is a compile-time error.
+    pub const fn new(value: usize) -> FreeBits {
+        assert!(
+            value < (1 << FREE_BITS),
+            "value does not fit in the free bits of a `Waiters` atomic"
+        );
+        FreeBits(value << RESERVED_BITS)
+    }
+
+    /// Extract the free bits from the value of an atomic, ignoring the reserved bits.
+    pub const fn from_atomic(value: usize) -> FreeBits {
+        FreeBits(value & !RESERVED_MASK)
+    }
+
+    /// The value stored in the free bits, as passed to [`new`](#method.new).
+    pub const fn get(self) -> usize {
+        self.0 >> RESERVED_BITS
+    }
+
+    /// The value positioned in the free bits with all reserved bits zero, to pass to the methods
+    /// of `Waiters`.
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+}
@@ -1,9 +1,15 @@
 //! Use the undocumented `ulock_wait` and `ulock_wake` syscalls that are available since
 //! MacOS 10.12 Sierra (Darwin 16.0).
-use core::sync::atomic::{AtomicI32, AtomicU32};
+use core::cmp;
+use core::convert::TryFrom;
+use core::ptr;
+use core::str;
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::AtomicIsize;
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
+use crate::futex::{Futex, FutexError, WakeupReason};
 use crate::utils::{errno, AtomicAsMutPtr};
 
 macro_rules! imp_futex {
@@ -16,54 +22,99 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut libc::c_void;
                 let expected = expected as u32 as u64;
-                let timeout_us = convert_timeout_us(timeout);
-                let r = unsafe { ulock_wait(UL_COMPARE_AND_WAIT, ptr, expected, timeout_us) };
-                if r >= 0 {
-                    // r is the number of threads waiting.
-                    Ok(WakeupReason::Unknown)
-                } else if r == -1 {
-                    match errno() {
-                        libc::EINTR => Ok(WakeupReason::Interrupt),
-                        libc::ETIMEDOUT if timeout_us != 0 => Ok(WakeupReason::TimedOut),
-                        e => {
-                            debug_assert!(false, "Unexpected errno of ulock_wait syscall: {}", e);
-                            Ok(WakeupReason::Unknown)
+                // `ulock_wait` takes a relative timeout. Keep track of the deadline, so we can
+                // continue waiting with the remaining time if we get interrupted by a signal, or
+                // if the timeout was too long to pass in one call.
+                let deadline = timeout.and_then(deadline_after);
+                let mut timeout_us = match deadline {
+                    Some(_) => convert_timeout_us(timeout),
+                    None => 0, // No timeout, or a deadline too far in the future to represent.
+                };
+                let mut interrupts = 0;
+                loop {
+                    let r = unsafe { ulock_wait(UL_COMPARE_AND_WAIT, ptr, expected, timeout_us) };
+                    if r >= 0 {
+                        // r is the number of threads waiting.
+                        return Ok(WakeupReason::Unknown);
+                    } else if r == -1 {
+                        let reason = match errno() {
+                            libc::EINTR => WakeupReason::Interrupt,
+                            libc::ETIMEDOUT if timeout_us != 0 => WakeupReason::TimedOut,
+                            e => return Err(FutexError::from_errno(e)),
+                        };
+                        match deadline {
+                            Some(deadline) => {
+                                let now = now_ns();
+                                if now >= deadline {
+                                    return Ok(WakeupReason::TimedOut);
+                                }
+                                let remaining = Duration::from_nanos(deadline - now);
+                                timeout_us = convert_timeout_us(Some(remaining));
+                            }
+                            // Without a deadline, wait again after an interrupt. The kernel
+                            // compares the value again, so a wake can't get lost. The retries are
+                            // bounded to still let a caller react to a stream of signals.
+                            None if reason == WakeupReason::Interrupt
+                                && interrupts < MAX_INTERRUPT_RETRIES =>
+                            {
+                                interrupts += 1;
+                            }
+                            None => return Ok(reason),
                         }
+                    } else {
+                        debug_assert!(
+                            false,
+                            "Unexpected return value of ulock_wait syscall: {}",
+                            r
+                        );
+                        return Ok(WakeupReason::Unknown);
                     }
-                } else {
-                    debug_assert!(
-                        false,
-                        "Unexpected return value of ulock_wait syscall: {}",
-                        r
-                    );
-                    Ok(WakeupReason::Unknown)
                 }
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake(&self) -> Result<usize, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut libc::c_void;
                 let r = unsafe { ulock_wake(UL_COMPARE_AND_WAIT | ULF_WAKE_ALL, ptr, 0) };
                 // Apparently the return value -1 with ENOENT means there were no threads waiting.
                 // Libdispatch considers it a success, so lets do the same.
-                if !(r == 0 || (r == -1 && errno() == libc::ENOENT)) {
-                    debug_assert!(
-                        r >= 0,
-                        "Unexpected return value of ulock_wake syscall: {}; errno: {}",
-                        r,
-                        errno()
-                    );
+                if r < 0 {
+                    match errno() {
+                        libc::ENOENT => {}
+                        e => return Err(FutexError::from_errno(e)),
+                    }
                 }
                 Ok(0) // `ulock_wake` does not return the number of woken threads.
             }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                let r = unsafe { ulock_wake(UL_COMPARE_AND_WAIT, ptr, 0) };
+                // ENOENT means there were no threads waiting, the same as with `wake`.
+                if r < 0 {
+                    match errno() {
+                        libc::ENOENT => {}
+                        e => return Err(FutexError::from_errno(e)),
+                    }
+                }
+                Ok(0)
+            }
         }
     };
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+// How often `wait` without a timeout continues after being interrupted by a signal.
+const MAX_INTERRUPT_RETRIES: u32 = 8;
 
 const UL_COMPARE_AND_WAIT: u32 = 1;
 const ULF_WAKE_ALL: u32 = 0x100;
@@ -90,7 +141,12 @@ unsafe fn ulock_wake(operation: u32, addr: *mut libc::c_void, wake_value: u64) -
     libc::syscall(SYS_ulock_wake, operation, addr, wake_value)
 }
 
+// The longest timeout we pass to `ulock_wait`, about 71 minutes. Longer waits are done in chunks.
+const MAX_TIMEOUT_US: u32 = u32::MAX - 1;
+
 // Timeout in microseconds, round nanosecond values up to microseconds.
+// 0 means an infinite timeout to `ulock_wait`, so finite timeouts are at least 1 microsecond and
+// saturate at `MAX_TIMEOUT_US`.
 fn convert_timeout_us(timeout: Option<Duration>) -> u32 {
     match timeout {
         None => 0,
@@ -98,13 +154,103 @@ fn convert_timeout_us(timeout: Option<Duration>) -> u32 {
             .as_secs()
             .checked_mul(1000_000)
             .and_then(|x| x.checked_add((duration.subsec_nanos() as u64 + 999) / 1000))
-            .map(|ms| {
-                if ms > u32::max_value() as u64 {
-                    0
-                } else {
-                    ms as u32
-                }
-            })
-            .unwrap_or(0),
+            .map(|us| cmp::min(cmp::max(us, 1), MAX_TIMEOUT_US as u64) as u32)
+            .unwrap_or(MAX_TIMEOUT_US),
+    }
+}
+
+// Monotonic time in nanoseconds, using the same clock as `ulock_wait`.
+fn now_ns() -> u64 {
+    let mut info = libc::mach_timebase_info { numer: 0, denom: 0 };
+    let ticks = unsafe {
+        libc::mach_timebase_info(&mut info);
+        libc::mach_absolute_time()
+    };
+    (ticks as u128 * info.numer as u128 / info.denom as u128) as u64
+}
+
+// Returns `None` if the deadline can't be represented, which we treat as an infinite timeout.
+fn deadline_after(timeout: Duration) -> Option<u64> {
+    let timeout_ns = u64::try_from(timeout.as_nanos()).ok()?;
+    now_ns().checked_add(timeout_ns)
+}
+
+const TRUE: usize = 0;
+const FALSE: usize = 1;
+const UNINITIALIZED: usize = 2;
+
+static HAS_ULOCK: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+
+// `ulock_wait` and `ulock_wake` are available since Darwin 16.0.
+pub(crate) fn has_futex() -> bool {
+    match HAS_ULOCK.load(Ordering::Relaxed) {
+        TRUE => true,
+        FALSE => false,
+        _ => {
+            let release = get_os_release();
+            if release.0 >= 16 {
+                HAS_ULOCK.store(TRUE, Ordering::Relaxed);
+                true
+            } else {
+                HAS_ULOCK.store(FALSE, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+fn get_os_release() -> (u16, u16, u16) {
+    let mut mib = [libc::CTL_KERN, libc::KERN_OSRELEASE];
+    let mut buf = [0u8; 20];
+    let mut len = buf.len();
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr() as *mut _,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if ret == -1 {
+        panic!("kern.osrelease sysctl failed");
+    }
+    let mut len = 0;
+    for c in buf.iter() {
+        len += 1;
+        if *c == 0 {
+            break;
+        }
+    }
+    let mut versions = [0u16; 3];
+    let release = str::from_utf8(&buf[0..len]).unwrap();
+    for (v, s) in versions.iter_mut().zip(release.split('.')) {
+        *v = s.parse().unwrap_or(0);
+    }
+    (versions[0], versions[1], versions[2])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_timeout_us, MAX_TIMEOUT_US};
+    use std::time::Duration;
+
+    #[test]
+    // 0 means infinite to `ulock_wait`, no finite timeout may convert to it.
+    fn timeout_us_stays_finite() {
+        let us = |us| convert_timeout_us(Some(Duration::from_micros(us)));
+        assert_eq!(convert_timeout_us(None), 0);
+        assert_eq!(convert_timeout_us(Some(Duration::from_nanos(0))), 1);
+        assert_eq!(convert_timeout_us(Some(Duration::from_nanos(1))), 1);
+        assert_eq!(us(MAX_TIMEOUT_US as u64 - 1), MAX_TIMEOUT_US - 1);
+        assert_eq!(us(MAX_TIMEOUT_US as u64), MAX_TIMEOUT_US);
+        assert_eq!(us(u32::MAX as u64), MAX_TIMEOUT_US);
+        assert_eq!(us(u32::MAX as u64 + 1), MAX_TIMEOUT_US);
+        assert_eq!(us(u64::MAX), MAX_TIMEOUT_US);
+        assert_eq!(
+            convert_timeout_us(Some(Duration::new(u64::MAX, 999_999_999))),
+            MAX_TIMEOUT_US
+        );
     }
 }
@@ -1,9 +1,10 @@
-use core::cmp;
 use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
-use crate::utils::{errno, AtomicAsMutPtr};
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, errno, AtomicAsMutPtr};
 
 macro_rules! imp_futex {
     ($atomic_type:ident, $int_type:ident) => {
@@ -15,20 +16,17 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut libc::c_int;
                 let ts = convert_timeout_us(timeout);
                 let r = unsafe { umtx_sleep(ptr, expected as libc::c_int, ts) };
                 match r {
-                    0 => WakeupReason::Unknown,
+                    0 => Ok(WakeupReason::Unknown),
                     -1 => match errno() {
                         libc::EBUSY => Ok(WakeupReason::NoMatch),
                         libc::EINTR => Ok(WakeupReason::Interrupt),
                         libc::EWOULDBLOCK => Ok(WakeupReason::Unknown),
-                        e => {
-                            debug_assert!(false, "Unexpected errno of umtx_sleep syscall: {}", e);
-                            Ok(WakeupReason::Unknown)
-                        }
+                        e => Err(FutexError::from_errno(e)),
                     },
                     r => {
                         debug_assert!(
@@ -42,21 +40,61 @@ macro_rules! imp_futex {
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake(&self) -> Result<usize, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut libc::c_int;
                 let r = unsafe { umtx_wakeup(ptr, 0) };
-                debug_assert!(
-                    r >= 0,
-                    "Unexpected return value of umtx_wakeup syscall: {}",
-                    r
-                );
-                Ok(cmp::max(r as usize, 0))
+                if r < 0 {
+                    return Err(FutexError::from_errno(errno()));
+                }
+                Ok(r as usize)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_int;
+                let r = unsafe { umtx_wakeup(ptr, 1) };
+                if r < 0 {
+                    return Err(FutexError::from_errno(errno()));
+                }
+                Ok(r as usize)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                // `umtx_wakeup` would wake up all threads with a count of 0.
+                if count == 0 {
+                    return Ok(0);
+                }
+                let ptr = self.as_mut_ptr() as *mut libc::c_int;
+                let r = unsafe { umtx_wakeup(ptr, clamp_count(count)) };
+                if r < 0 {
+                    return Err(FutexError::from_errno(errno()));
+                }
+                Ok(r as usize)
             }
         }
     };
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+// `umtx_sleep` and `umtx_wakeup` work on the physical address, so they are always process-shared.
+// `SharedParker` has to do a write on the atomic before it waits again after a spurious wakeup.
+pub(crate) fn wait_shared(
+    atomic: &AtomicI32,
+    expected: i32,
+    timeout: Option<Duration>,
+) -> Result<WakeupReason, FutexError> {
+    atomic.wait(expected, timeout)
+}
+
+pub(crate) fn wake_shared(atomic: &AtomicI32) -> Result<usize, FutexError> {
+    atomic.wake()
+}
 
 extern "C" {
     // Note: our function signature does not match the one from the man page, which says that `ptr`
@@ -85,7 +123,7 @@ fn convert_timeout_us(timeout: Option<Duration>) -> libc::c_int {
             .checked_mul(1000_000)
             .and_then(|x| x.checked_add((duration.subsec_nanos() as u64 + 999) / 1000))
             .map(|ms| {
-                if ms > libc::c_int::max_value() as u64 {
+                if ms > libc::c_int::MAX as u64 {
                     0
                 } else {
                     ms as libc::c_int
@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
+use core::time::Duration;
+
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, AtomicAsMutPtr};
+
+// Emscripten implements its futex functions with the `memory.atomic.wait32` and
+// `memory.atomic.notify` instructions of WebAssembly, except on the main browser thread where
+// blocking is not allowed and it busy-waits instead. Like the instructions they report precisely
+// why a wait returned, so a return value of 0 is a real wakeup.
+
+macro_rules! imp_futex {
+    ($atomic_type:ident, $int_type:ident) => {
+        impl Futex for $atomic_type {
+            type Integer = $int_type;
+
+            #[inline]
+            fn wait(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                let r = unsafe {
+                    emscripten_futex_wait(ptr, expected as u32, convert_timeout_ms(timeout))
+                };
+                match r {
+                    0 => Ok(WakeupReason::WokenUp),
+                    r if r == -libc::EWOULDBLOCK => Ok(WakeupReason::NoMatch),
+                    r if r == -libc::ETIMEDOUT => Ok(WakeupReason::TimedOut),
+                    r => Err(from_errno(-r)),
+                }
+            }
+
+            #[inline]
+            fn wake(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut libc::c_void, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut libc::c_void, 1)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
+                futex_wake(self.as_mut_ptr() as *mut libc::c_void, clamp_count(count))
+            }
+        }
+    };
+}
+imp_futex!(AtomicU32, u32);
+imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+#[inline]
+fn futex_wake(ptr: *mut libc::c_void, wake_count: libc::c_int) -> Result<usize, FutexError> {
+    let r = unsafe { emscripten_futex_wake(ptr, wake_count) };
+    if r < 0 {
+        return Err(from_errno(-r));
+    }
+    Ok(r as usize)
+}
+
+fn from_errno(errno: libc::c_int) -> FutexError {
+    match errno {
+        libc::EINVAL => FutexError::Fault,
+        e => FutexError::Os(e),
+    }
+}
+
+// Not exported by the `libc` crate. Both return 0 or a positive value on success, and a negated
+// error number on failure.
+extern "C" {
+    fn emscripten_futex_wait(
+        addr: *mut libc::c_void,
+        val: u32,
+        max_wait_milliseconds: libc::c_double,
+    ) -> libc::c_int;
+    fn emscripten_futex_wake(addr: *mut libc::c_void, count: libc::c_int) -> libc::c_int;
+}
+
+// Emscripten takes the timeout as a floating point number of milliseconds, where infinity means no
+// timeout. Durations too large for an `f64` to count in milliseconds still become a huge finite
+// value, which is as good as infinite.
+fn convert_timeout_ms(timeout: Option<Duration>) -> libc::c_double {
+    match timeout {
+        Some(duration) => duration.as_secs_f64() * 1000.0,
+        None => f64::INFINITY,
+    }
+}
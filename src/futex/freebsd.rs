@@ -1,20 +1,39 @@
-use core::cmp;
 use core::mem;
 use core::ptr;
 use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "64")]
+use core::sync::atomic::{AtomicI64, AtomicU64};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
-use crate::utils::{errno, AtomicAsMutPtr};
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, errno, AtomicAsMutPtr};
 
 // FreeBSD can take and compare an `usize` value when used with the `UMTX_OP_WAIT` and
 // `UMTX_OP_WAKE` operations. But we want to be good citizens and use `UMTX_OP_WAIT_UINT_PRIVATE`
 // and `UMTX_OP_WAKE_PRIVATE`, which allow the kernel to maintain a process-private queue of waiting
 // threads. This has the nice side effect that it takes a operates on an i32 instead, which makes it
 // the similar to futex implementations on other platforms.
+//
+// For 64-bit atomics we do use `UMTX_OP_WAIT` and `UMTX_OP_WAKE`, which compare an `u_long` (only
+// 64 bits on 64-bit platforms). There are no private variants of these operations: the kernel
+// identifies the waiters by the backing memory object, which makes them process-shared. They work
+// on atomics in memory shared between processes, at the cost of a slightly more expensive lookup.
+//
+// The `*_shared` methods of `Futex` use `UMTX_OP_WAIT_UINT` and `UMTX_OP_WAKE` for the 32-bit
+// atomics, the non-private versions of the operations above.
 
 macro_rules! imp_futex {
-    ($atomic_type:ident, $int_type:ident) => {
+    (
+        $atomic_type:ident,
+        $int_type:ident,
+        $cmp_type:ident,
+        $wait_op:ident,
+        $wake_op:ident,
+        $shared_wait_op:ident,
+        $shared_wake_op:ident
+    ) => {
         impl Futex for $atomic_type {
             type Integer = $int_type;
 
@@ -23,63 +42,185 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                umtx_wait(
+                    ptr,
+                    $wait_op,
+                    expected as $cmp_type as libc::c_long,
+                    timeout,
+                )
+            }
+
+            #[inline]
+            fn wake(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                umtx_wake(ptr, $wake_op, libc::INT_MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut libc::c_void;
-                let mut ts = convert_timeout(timeout);
-                let ts_ptr = ts
-                    .as_mut()
-                    .map(|ts_ref| ts_ref as *mut umtx_time as *mut libc::c_void)
-                    .unwrap_or(ptr::null_mut());
-                let ts_size = mem::size_of::<umtx_time>() as *mut libc::c_void;
-                let r = unsafe {
-                    umtx_op(
-                        ptr,
-                        UMTX_OP_WAIT_UINT_PRIVATE,
-                        expected as i32 as libc::c_long,
-                        ts_size,
-                        ts_ptr,
-                    )
-                };
-                match r {
-                    0 => Ok(WakeupReason::Unknown), // Can be NoMatch, WokenUp and Spurious
-                    -1 => match errno() {
-                        libc::EINTR => Ok(WakeupReason::Interrupt),
-                        libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
-                        e => {
-                            debug_assert!(false, "Unexpected errno of umtx_op syscall: {}", e);
-                            Ok(WakeupReason::Unknown)
-                        }
-                    },
-                    r => {
-                        debug_assert!(false, "Unexpected return value of umtx_op syscall: {}", r);
-                        Ok(WakeupReason::Unknown)
-                    }
+                umtx_wake(ptr, $wake_op, 1)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
                 }
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                umtx_wake(ptr, $wake_op, clamp_count(count))
+            }
+
+            #[inline]
+            fn wait_shared(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                umtx_wait(
+                    ptr,
+                    $shared_wait_op,
+                    expected as $cmp_type as libc::c_long,
+                    timeout,
+                )
+            }
+
+            #[inline]
+            fn wake_shared(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut libc::c_void;
+                umtx_wake(ptr, $shared_wake_op, libc::INT_MAX)
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake_one_shared(&self) -> Result<usize, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut libc::c_void;
-                let wake_count = libc::INT_MAX as libc::c_long;
-                let r = unsafe {
-                    umtx_op(
-                        ptr,
-                        UMTX_OP_WAKE_PRIVATE,
-                        wake_count,
-                        ptr::null_mut(),
-                        ptr::null_mut(),
-                    )
-                };
-                debug_assert!(r >= 0, "Unexpected return value of umtx_op syscall: {}", r);
-                Ok(cmp::max(r as usize, 0))
+                umtx_wake(ptr, $shared_wake_op, 1)
             }
         }
     };
 }
-imp_futex!(AtomicU32, u32);
-imp_futex!(AtomicI32, i32);
+imp_futex!(
+    AtomicU32,
+    u32,
+    i32,
+    UMTX_OP_WAIT_UINT_PRIVATE,
+    UMTX_OP_WAKE_PRIVATE,
+    UMTX_OP_WAIT_UINT,
+    UMTX_OP_WAKE
+);
+imp_futex!(
+    AtomicI32,
+    i32,
+    i32,
+    UMTX_OP_WAIT_UINT_PRIVATE,
+    UMTX_OP_WAKE_PRIVATE,
+    UMTX_OP_WAIT_UINT,
+    UMTX_OP_WAKE
+);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(
+    AtomicUsize,
+    usize,
+    i32,
+    UMTX_OP_WAIT_UINT_PRIVATE,
+    UMTX_OP_WAKE_PRIVATE,
+    UMTX_OP_WAIT_UINT,
+    UMTX_OP_WAKE
+);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(
+    AtomicIsize,
+    isize,
+    i32,
+    UMTX_OP_WAIT_UINT_PRIVATE,
+    UMTX_OP_WAKE_PRIVATE,
+    UMTX_OP_WAIT_UINT,
+    UMTX_OP_WAKE
+);
+#[cfg(target_pointer_width = "64")]
+imp_futex!(
+    AtomicU64,
+    u64,
+    u64,
+    UMTX_OP_WAIT,
+    UMTX_OP_WAKE,
+    UMTX_OP_WAIT,
+    UMTX_OP_WAKE
+);
+#[cfg(target_pointer_width = "64")]
+imp_futex!(
+    AtomicI64,
+    i64,
+    u64,
+    UMTX_OP_WAIT,
+    UMTX_OP_WAKE,
+    UMTX_OP_WAIT,
+    UMTX_OP_WAKE
+);
+
+// Process-shared variants for `SharedParker`.
+pub(crate) fn wait_shared(
+    atomic: &AtomicI32,
+    expected: i32,
+    timeout: Option<Duration>,
+) -> Result<WakeupReason, FutexError> {
+    Futex::wait_shared(atomic, expected, timeout)
+}
+
+pub(crate) fn wake_shared(atomic: &AtomicI32) -> Result<usize, FutexError> {
+    Futex::wake_shared(atomic)
+}
+
+#[inline]
+fn umtx_wait(
+    ptr: *mut libc::c_void,
+    op: libc::c_int,
+    expected: libc::c_long,
+    timeout: Option<Duration>,
+) -> Result<WakeupReason, FutexError> {
+    let mut ts = convert_timeout(timeout);
+    let ts_ptr = ts
+        .as_mut()
+        .map(|ts_ref| ts_ref as *mut umtx_time as *mut libc::c_void)
+        .unwrap_or(ptr::null_mut());
+    let ts_size = mem::size_of::<umtx_time>() as *mut libc::c_void;
+    let r = unsafe { umtx_op(ptr, op, expected, ts_size, ts_ptr) };
+    match r {
+        0 => Ok(WakeupReason::Unknown), // Can be NoMatch, WokenUp and Spurious
+        -1 => match errno() {
+            libc::EINTR => Ok(WakeupReason::Interrupt),
+            libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
+            e => Err(FutexError::from_errno(e)),
+        },
+        r => {
+            debug_assert!(false, "Unexpected return value of umtx_op syscall: {}", r);
+            Ok(WakeupReason::Unknown)
+        }
+    }
+}
+
+#[inline]
+fn umtx_wake(
+    ptr: *mut libc::c_void,
+    op: libc::c_int,
+    wake_count: libc::c_int,
+) -> Result<usize, FutexError> {
+    let wake_count = wake_count as libc::c_long;
+    let r = unsafe { umtx_op(ptr, op, wake_count, ptr::null_mut(), ptr::null_mut()) };
+    if r < 0 {
+        return Err(FutexError::from_errno(errno()));
+    }
+    Ok(r as usize)
+}
 
 const _UMTX_OP: i32 = 454;
+#[cfg(target_pointer_width = "64")]
+const UMTX_OP_WAIT: libc::c_int = 2;
+const UMTX_OP_WAKE: libc::c_int = 3;
+const UMTX_OP_WAIT_UINT: libc::c_int = 11;
 const UMTX_OP_WAIT_UINT_PRIVATE: libc::c_int = 15;
 const UMTX_OP_WAKE_PRIVATE: libc::c_int = 16;
 const UMTX_ABSTIME: i32 = 0x01;
@@ -114,7 +255,7 @@ struct umtx_time {
 fn convert_timeout(timeout: Option<Duration>) -> Option<umtx_time> {
     match timeout {
         Some(duration) => {
-            if duration.as_secs() > libc::time_t::max_value() as u64 {
+            if duration.as_secs() > libc::time_t::MAX as u64 {
                 return None;
             }
             Some(umtx_time {
@@ -1,9 +1,12 @@
 #![allow(non_camel_case_types)]
 
+use core::mem;
 use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
+use crate::futex::{Futex, FutexError, WakeupReason};
 use crate::utils::AtomicAsMutPtr;
 
 macro_rules! imp_futex {
@@ -16,43 +19,82 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut zx_futex_t;
+                debug_check_alignment(ptr);
                 let deadline = convert_timeout(timeout);
                 let r = unsafe { zx_futex_wait(ptr, expected as zx_futex_t, deadline) };
                 match r {
                     ZX_OK => Ok(WakeupReason::Unknown),
                     ZX_ERR_BAD_STATE => Ok(WakeupReason::NoMatch),
                     ZX_ERR_TIMED_OUT if deadline != ZX_TIME_INFINITE => Ok(WakeupReason::TimedOut),
-                    r => {
-                        debug_assert!(false, "Unexpected return value of zx_futex_wait: {}", r);
-                        Ok(WakeupReason::Unknown)
-                    }
+                    r => Err(from_zx_status(r)),
                 }
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake(&self) -> Result<usize, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut i32;
-                let wake_count = u32::max_value();
+                debug_check_alignment(ptr);
+                let wake_count = u32::MAX;
                 let r = unsafe { zx_futex_wake(ptr, wake_count) };
-                debug_assert!(
-                    r == ZX_OK,
-                    "Unexpected return value of zx_futex_wake: {}",
-                    r
-                );
+                if r != ZX_OK {
+                    return Err(from_zx_status(r));
+                }
                 Ok(0) // `zx_futex_wake` does not return the number of woken threads
             }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut i32;
+                debug_check_alignment(ptr);
+                let r = unsafe { zx_futex_wake(ptr, 1) };
+                if r != ZX_OK {
+                    return Err(from_zx_status(r));
+                }
+                Ok(0)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
+                let ptr = self.as_mut_ptr() as *mut i32;
+                debug_check_alignment(ptr);
+                let wake_count = count.min(u32::MAX as usize) as u32;
+                let r = unsafe { zx_futex_wake(ptr, wake_count) };
+                if r != ZX_OK {
+                    return Err(from_zx_status(r));
+                }
+                Ok(0)
+            }
         }
     };
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+// Zircon rejects a futex that is not 4-byte aligned with `ZX_ERR_INVALID_ARGS`, which would leave
+// the waiters hanging if the error gets ignored. Atomics in Rust are always aligned, so this can
+// only be caused by unsafe code, for example a reference to a field of a packed struct.
+#[inline]
+fn debug_check_alignment(ptr: *const zx_futex_t) {
+    debug_assert!(
+        ptr as usize % mem::align_of::<zx_futex_t>() == 0,
+        "futex at {:p} is not aligned to 4 bytes",
+        ptr
+    );
+}
 
 fn convert_timeout(timeout: Option<Duration>) -> zx_time_t {
     match timeout {
         Some(duration) => {
-            if duration.as_nanos() > zx_duration_t::max_value() as u128 {
+            if duration.as_nanos() > zx_duration_t::MAX as u128 {
                 ZX_TIME_INFINITE
             } else {
                 unsafe { zx_deadline_after(duration.as_nanos() as zx_duration_t) }
@@ -62,17 +104,29 @@ fn convert_timeout(timeout: Option<Duration>) -> zx_time_t {
     }
 }
 
+fn from_zx_status(status: zx_status_t) -> FutexError {
+    match status {
+        ZX_ERR_NOT_SUPPORTED => FutexError::Unsupported,
+        ZX_ERR_INVALID_ARGS => FutexError::Fault,
+        s => FutexError::Os(s),
+    }
+}
+
 // It would be better if we could depend on the `fuchsia-zircon-sys` crate.
 // But it contains a bug in its signature of `zx_futex_wait`, and the repository seems gone.
 type zx_futex_t = i32;
 type zx_status_t = i32;
-type zx_duration_t = u64;
-type zx_time_t = u64;
+// Durations and deadlines are signed in Zircon. Converting a duration larger than `i64::MAX`
+// nanoseconds would produce a negative value, and a deadline in the past.
+type zx_duration_t = i64;
+type zx_time_t = i64;
 
 const ZX_OK: zx_status_t = 0;
+const ZX_ERR_NOT_SUPPORTED: zx_status_t = -2;
+const ZX_ERR_INVALID_ARGS: zx_status_t = -10;
 const ZX_ERR_BAD_STATE: zx_status_t = -20;
 const ZX_ERR_TIMED_OUT: zx_status_t = -21;
-const ZX_TIME_INFINITE: zx_time_t = u64::max_value();
+const ZX_TIME_INFINITE: zx_time_t = i64::MAX;
 
 #[link(name = "zircon")]
 extern "C" {
@@ -0,0 +1,326 @@
+//! A deterministic model of the futex-based `Parker` state machine, driven by the `cargo fuzz`
+//! harness in `fuzz/`.
+//!
+//! `park` and `unpark` from `parking.rs` run unmodified on real threads, but on an atomic that
+//! hands control to a scheduler before every operation. Only one thread runs at a time, and the
+//! fuzz input decides which one runs next, and when a waiting thread wakes up spuriously or times
+//! out. Every input replays the same interleaving, so a crash can be minimized and reproduced.
+//!
+//! The model checks the interleavings of the state transitions, not the memory orderings: the
+//! threads hand over through a mutex, so every operation is sequentially consistent. The loom tests
+//! in `futex/mod.rs` cover the orderings.
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::futex::sync::ParkerAtomic;
+use crate::futex::{Futex, FutexError, WakeupReason};
+
+/// An error found by the model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelError {
+    /// The parker sleeps, and no other thread can wake it: an `unpark` got lost.
+    Deadlock,
+    /// The parker keeps timing out without consuming an `unpark`, so an `unpark` got lost.
+    Livelock,
+    /// `park` reported `WokenUp` more often than `unpark` was called.
+    WokenWithoutUnpark,
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Deadlock => f.write_str("lost unpark: the parker sleeps forever"),
+            ModelError::Livelock => f.write_str("lost unpark: the parker keeps timing out"),
+            ModelError::WokenWithoutUnpark => f.write_str("park returned without an unpark"),
+        }
+    }
+}
+
+const PARKER: usize = 0;
+const UNPARKER: usize = 1;
+const DONE: usize = usize::MAX;
+
+// Bits of an input byte that turn a scheduling decision into the wakeup of a waiting thread.
+const SPURIOUS: u8 = 0x80;
+const INTERRUPT: u8 = 0x40;
+
+// Steps per round once the input is used up. A round takes about ten.
+const STEPS_PER_ROUND: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ThreadState {
+    Runnable,
+    // Waiting in `Futex::wait`.
+    Waiting { timeout: bool },
+    // Woken from `Futex::wait`, which returns this reason.
+    Woken(WakeupReason),
+    // The unparker waits until `park` returned `WokenUp` this many times.
+    Handshake(usize),
+    Finished,
+}
+
+struct State {
+    threads: [ThreadState; 2],
+    current: usize,
+    input: Vec<u8>,
+    pos: usize,
+    steps: usize,
+    max_steps: usize,
+    unparks: usize,
+    woken: usize,
+    failure: Option<ModelError>,
+}
+
+impl State {
+    fn runnable(&self, thread: usize) -> bool {
+        match self.threads[thread] {
+            ThreadState::Runnable | ThreadState::Woken(_) => true,
+            ThreadState::Handshake(woken) => woken == self.woken,
+            ThreadState::Waiting { .. } | ThreadState::Finished => false,
+        }
+    }
+
+    // Pick the thread that runs next, and wake the parker if the input or the passing of time
+    // says so.
+    fn schedule(&mut self) {
+        self.steps += 1;
+        if self.steps > self.max_steps {
+            self.failure = Some(ModelError::Livelock);
+            return;
+        }
+        let byte = self.input.get(self.pos).copied();
+        self.pos += 1;
+        let waiting = match self.threads[PARKER] {
+            ThreadState::Waiting { timeout } => Some(timeout),
+            _ => None,
+        };
+        if let (Some(byte), Some(timeout)) = (byte, waiting) {
+            if byte & SPURIOUS != 0 {
+                let reason = if timeout {
+                    WakeupReason::TimedOut
+                } else if byte & INTERRUPT != 0 {
+                    WakeupReason::Interrupt
+                } else {
+                    WakeupReason::Unknown
+                };
+                self.threads[PARKER] = ThreadState::Woken(reason);
+                self.current = PARKER;
+                return;
+            }
+        }
+        let runnable: Vec<usize> = (0..self.threads.len())
+            .filter(|&i| self.runnable(i))
+            .collect();
+        if !runnable.is_empty() {
+            self.current = runnable[byte.unwrap_or(0) as usize % runnable.len()];
+        } else if waiting == Some(true) {
+            // Time passes while every thread waits, until the timeout expires.
+            self.threads[PARKER] = ThreadState::Woken(WakeupReason::TimedOut);
+            self.current = PARKER;
+        } else if self.threads.iter().all(|&s| s == ThreadState::Finished) {
+            self.current = DONE;
+        } else {
+            self.failure = Some(ModelError::Deadlock);
+        }
+    }
+}
+
+struct Scheduler {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+thread_local! {
+    static CURRENT_THREAD: Cell<usize> = const { Cell::new(DONE) };
+}
+
+impl Scheduler {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    // Block until this thread is scheduled. After a failure the model threads stay blocked, the
+    // caller of `park_unpark` reports it.
+    fn wait_turn<'a>(&'a self, mut state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        let me = CURRENT_THREAD.with(|c| c.get());
+        while state.current != me || state.failure.is_some() {
+            state = self.cond.wait(state).unwrap();
+        }
+        state
+    }
+
+    // Hand control to the thread chosen by the input, and return the state this thread resumes
+    // in.
+    fn yield_as(&self, new_state: ThreadState) -> ThreadState {
+        let me = CURRENT_THREAD.with(|c| c.get());
+        let mut state = self.lock();
+        state.threads[me] = new_state;
+        state.schedule();
+        self.cond.notify_all();
+        let mut state = self.wait_turn(state);
+        let resumed = state.threads[me];
+        state.threads[me] = ThreadState::Runnable;
+        resumed
+    }
+
+    fn yield_now(&self) {
+        self.yield_as(ThreadState::Runnable);
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(self: &Arc<Self>, thread: usize, f: F) {
+        let scheduler = self.clone();
+        thread::spawn(move || {
+            CURRENT_THREAD.with(|c| c.set(thread));
+            drop(scheduler.wait_turn(scheduler.lock()));
+            f();
+            let mut state = scheduler.lock();
+            state.threads[thread] = ThreadState::Finished;
+            state.schedule();
+            scheduler.cond.notify_all();
+        });
+    }
+}
+
+// The parker state, with a yield to the scheduler before every operation.
+struct ModelAtomic {
+    value: AtomicI32,
+    scheduler: Arc<Scheduler>,
+}
+
+impl ParkerAtomic for ModelAtomic {
+    fn load(&self, order: Ordering) -> i32 {
+        self.scheduler.yield_now();
+        self.value.load(order)
+    }
+
+    fn swap(&self, val: i32, order: Ordering) -> i32 {
+        self.scheduler.yield_now();
+        self.value.swap(val, order)
+    }
+
+    fn compare_exchange(
+        &self,
+        current: i32,
+        new: i32,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<i32, i32> {
+        self.scheduler.yield_now();
+        self.value.compare_exchange(current, new, success, failure)
+    }
+}
+
+impl Futex for ModelAtomic {
+    type Integer = i32;
+
+    // Comparing the value and going to sleep is atomic, because only one thread runs at a time.
+    fn wait(&self, expected: i32, timeout: Option<Duration>) -> Result<WakeupReason, FutexError> {
+        self.scheduler.yield_now();
+        if self.value.load(Ordering::Relaxed) != expected {
+            return Ok(WakeupReason::NoMatch);
+        }
+        match self.scheduler.yield_as(ThreadState::Waiting {
+            timeout: timeout.is_some(),
+        }) {
+            ThreadState::Woken(reason) => Ok(reason),
+            s => unreachable!("resumed from wait in state {:?}", s),
+        }
+    }
+
+    fn wake(&self) -> Result<usize, FutexError> {
+        self.scheduler.yield_now();
+        let mut state = self.scheduler.lock();
+        let mut woken = 0;
+        for thread in state.threads.iter_mut() {
+            if let ThreadState::Waiting { .. } = *thread {
+                *thread = ThreadState::Woken(WakeupReason::WokenUp);
+                woken += 1;
+            }
+        }
+        Ok(woken)
+    }
+}
+
+/// Run `park` and `unpark` with the interleaving chosen by `data`.
+///
+/// The first byte picks the number of rounds, the second whether `park` has a timeout. Every
+/// following byte is one scheduling decision, with the high bit set it wakes a waiting parker
+/// spuriously or times it out. Once the input is used up the threads run in a fixed order.
+///
+/// In every round the parker calls `park` until it returns `WokenUp`, and the unparker calls
+/// `unpark` once. The unparker waits for the previous round to finish before it unparks again, so
+/// every `unpark` must wake the parker.
+///
+/// On an error the two model threads are left blocked, the caller is expected to panic.
+pub fn park_unpark(data: &[u8]) -> Result<(), ModelError> {
+    let rounds = 1 + data.first().copied().unwrap_or(0) as usize % 8;
+    let timeout = data.get(1).map_or(false, |b| b & 1 != 0);
+    let input = data.get(2..).unwrap_or(&[]).to_vec();
+    let scheduler = Arc::new(Scheduler {
+        state: Mutex::new(State {
+            threads: [ThreadState::Runnable; 2],
+            current: DONE,
+            max_steps: input.len() + rounds * STEPS_PER_ROUND,
+            input,
+            pos: 0,
+            steps: 0,
+            unparks: 0,
+            woken: 0,
+            failure: None,
+        }),
+        cond: Condvar::new(),
+    });
+    let atomic = Arc::new(ModelAtomic {
+        value: AtomicI32::new(0),
+        scheduler: scheduler.clone(),
+    });
+
+    {
+        let atomic = atomic.clone();
+        let scheduler = scheduler.clone();
+        scheduler.clone().spawn(PARKER, move || {
+            let timeout = if timeout {
+                Some(Duration::from_millis(1))
+            } else {
+                None
+            };
+            for _ in 0..rounds {
+                while super::park(&*atomic, timeout) != WakeupReason::WokenUp {}
+                let mut state = scheduler.lock();
+                state.woken += 1;
+                if state.woken > state.unparks {
+                    state.failure = Some(ModelError::WokenWithoutUnpark);
+                    scheduler.cond.notify_all();
+                }
+            }
+        });
+    }
+    {
+        let atomic = atomic.clone();
+        let scheduler = scheduler.clone();
+        scheduler.clone().spawn(UNPARKER, move || {
+            for round in 0..rounds {
+                scheduler.yield_as(ThreadState::Handshake(round));
+                scheduler.lock().unparks += 1;
+                super::unpark(&*atomic);
+            }
+        });
+    }
+
+    let mut state = scheduler.lock();
+    state.schedule();
+    scheduler.cond.notify_all();
+    loop {
+        if let Some(failure) = state.failure {
+            return Err(failure);
+        }
+        if state.current == DONE {
+            return Ok(());
+        }
+        state = scheduler.cond.wait(state).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use core::ptr;
+use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
+use core::time::Duration;
+
+use hermit_abi::{
+    futex_wait, futex_wake, timespec, EAGAIN, EFAULT, EINTR, EINVAL, ENOSYS, ETIMEDOUT,
+    FUTEX_RELATIVE_TIMEOUT,
+};
+
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, AtomicAsMutPtr};
+
+macro_rules! imp_futex {
+    ($atomic_type:ident, $int_type:ident) => {
+        impl Futex for $atomic_type {
+            type Integer = $int_type;
+
+            #[inline]
+            fn wait(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut u32;
+                let ts = convert_timeout(timeout);
+                let ts_ptr = ts
+                    .as_ref()
+                    .map(|ts_ref| ts_ref as *const timespec)
+                    .unwrap_or(ptr::null());
+                // Hermit returns a negated error number.
+                let r = unsafe { futex_wait(ptr, expected as u32, ts_ptr, FUTEX_RELATIVE_TIMEOUT) };
+                match -r {
+                    0 => Ok(WakeupReason::Unknown),
+                    EAGAIN => Ok(WakeupReason::NoMatch),
+                    EINTR => Ok(WakeupReason::Interrupt),
+                    ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
+                    e => Err(from_errno(e)),
+                }
+            }
+
+            #[inline]
+            fn wake(&self) -> Result<usize, FutexError> {
+                futex_wake_count(self.as_mut_ptr() as *mut u32, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                futex_wake_count(self.as_mut_ptr() as *mut u32, 1)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
+                futex_wake_count(self.as_mut_ptr() as *mut u32, clamp_count(count))
+            }
+        }
+    };
+}
+imp_futex!(AtomicU32, u32);
+imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+#[inline]
+fn futex_wake_count(ptr: *mut u32, wake_count: i32) -> Result<usize, FutexError> {
+    let r = unsafe { futex_wake(ptr, wake_count) };
+    if r < 0 {
+        return Err(from_errno(-r));
+    }
+    Ok(r as usize)
+}
+
+// Hermit has its own `errno` values.
+fn from_errno(errno: i32) -> FutexError {
+    match errno {
+        ENOSYS => FutexError::Unsupported,
+        EFAULT | EINVAL => FutexError::Fault,
+        e => FutexError::Os(e),
+    }
+}
+
+fn convert_timeout(timeout: Option<Duration>) -> Option<timespec> {
+    match timeout {
+        Some(duration) => {
+            if duration.as_secs() > i64::MAX as u64 {
+                return None;
+            }
+            Some(timespec {
+                tv_sec: duration.as_secs() as i64,
+                tv_nsec: duration.subsec_nanos() as i32,
+            })
+        }
+        None => None,
+    }
+}
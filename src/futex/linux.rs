@@ -1,10 +1,11 @@
-use core::cmp;
 use core::ptr;
-use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::AtomicIsize;
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
-use crate::utils::{errno, AtomicAsMutPtr};
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, errno, AtomicAsMutPtr};
 
 macro_rules! imp_futex {
     ($atomic_type:ident, $int_type:ident) => {
@@ -16,63 +17,153 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut i32;
-                let ts = convert_timeout(timeout);
-                let ts_ptr = ts
-                    .as_ref()
-                    .map(|ts_ref| ts_ref as *const _)
-                    .unwrap_or(ptr::null());
-                let r = unsafe {
-                    futex(
-                        ptr,
-                        libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
-                        expected as i32,
-                        ts_ptr,
-                        ptr::null_mut(),
-                        0,
-                    )
-                };
-                match r {
-                    0 => Ok(WakeupReason::Unknown),
-                    -1 => match errno() {
-                        libc::EAGAIN => Ok(WakeupReason::NoMatch),
-                        libc::EINTR => Ok(WakeupReason::Interrupt),
-                        libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
-                        e => {
-                            debug_assert!(false, "Unexpected errno of futex syscall: {}", e);
-                            Ok(WakeupReason::Unknown)
-                        }
-                    },
-                    r => {
-                        debug_assert!(false, "Unexpected return value of futex syscall: {}", r);
-                        Ok(WakeupReason::Unknown)
-                    }
-                }
+                futex_wait(ptr, libc::FUTEX_PRIVATE_FLAG, expected as i32, timeout)
+            }
+
+            #[inline]
+            fn wake(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut i32;
+                futex_wake(ptr, libc::FUTEX_PRIVATE_FLAG, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut i32;
+                futex_wake(ptr, libc::FUTEX_PRIVATE_FLAG, 1)
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
                 let ptr = self.as_mut_ptr() as *mut i32;
-                let wake_count = i32::max_value();
-                let r = unsafe {
-                    futex(
-                        ptr,
-                        libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
-                        wake_count,
-                        ptr::null(),
-                        ptr::null_mut(),
-                        0,
-                    )
-                };
-                debug_assert!(r >= 0, "Unexpected return value of futex syscall: {}", r);
-                Ok(cmp::max(r as usize, 0))
+                futex_wake(ptr, libc::FUTEX_PRIVATE_FLAG, clamp_count(count))
+            }
+
+            #[inline]
+            fn wait_shared(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                futex_wait(self.as_mut_ptr() as *mut i32, 0, expected as i32, timeout)
+            }
+
+            #[inline]
+            fn wake_shared(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut i32, 0, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one_shared(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut i32, 0, 1)
             }
         }
     };
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+// Process-shared variants for `SharedParker`. Without `FUTEX_PRIVATE_FLAG` the kernel identifies
+// the waiters by the backing memory object instead of by the virtual address, so it works across
+// processes that map the same memory.
+pub(crate) fn wait_shared(
+    atomic: &AtomicI32,
+    expected: i32,
+    timeout: Option<Duration>,
+) -> Result<WakeupReason, FutexError> {
+    Futex::wait_shared(atomic, expected, timeout)
+}
+
+pub(crate) fn wake_shared(atomic: &AtomicI32) -> Result<usize, FutexError> {
+    Futex::wake_shared(atomic)
+}
+
+pub(super) fn wake_op(
+    atomic: &AtomicU32,
+    wake_count: usize,
+    atomic2: &AtomicU32,
+    wake_count2: usize,
+    encoded_op: u32,
+) -> Result<usize, FutexError> {
+    // The second count is passed in place of the timeout.
+    let r = unsafe {
+        futex(
+            atomic.as_mut_ptr() as *mut i32,
+            libc::FUTEX_WAKE_OP | libc::FUTEX_PRIVATE_FLAG,
+            clamp_count(wake_count),
+            clamp_count(wake_count2) as usize as *const libc::timespec,
+            atomic2.as_mut_ptr() as *mut libc::c_void,
+            encoded_op as i32,
+        )
+    };
+    if r < 0 {
+        return Err(FutexError::from_errno(errno()));
+    }
+    Ok(r as usize)
+}
+
+#[inline]
+fn futex_wait(
+    ptr: *mut i32,
+    flags: libc::c_int,
+    expected: i32,
+    timeout: Option<Duration>,
+) -> Result<WakeupReason, FutexError> {
+    let ts = convert_timeout(timeout);
+    let ts_ptr = ts
+        .as_ref()
+        .map(|ts_ref| ts_ref as *const _)
+        .unwrap_or(ptr::null());
+    let r = unsafe {
+        futex(
+            ptr,
+            libc::FUTEX_WAIT | flags,
+            expected,
+            ts_ptr,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    match r {
+        0 => Ok(WakeupReason::Unknown),
+        -1 => match errno() {
+            libc::EAGAIN => Ok(WakeupReason::NoMatch),
+            libc::EINTR => Ok(WakeupReason::Interrupt),
+            libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
+            e => Err(FutexError::from_errno(e)),
+        },
+        r => {
+            debug_assert!(false, "Unexpected return value of futex syscall: {}", r);
+            Ok(WakeupReason::Unknown)
+        }
+    }
+}
+
+#[inline]
+fn futex_wake(ptr: *mut i32, flags: libc::c_int, wake_count: i32) -> Result<usize, FutexError> {
+    let r = unsafe {
+        futex(
+            ptr,
+            libc::FUTEX_WAKE | flags,
+            wake_count,
+            ptr::null(),
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if r < 0 {
+        return Err(FutexError::from_errno(errno()));
+    }
+    Ok(r as usize)
+}
 
 unsafe fn futex(
     uaddr: *mut libc::c_int,
@@ -97,7 +188,7 @@ type tv_nsec_t = libc::c_long;
 fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
     match timeout {
         Some(duration) => {
-            if duration.as_secs() > libc::time_t::max_value() as u64 {
+            if duration.as_secs() > libc::time_t::MAX as u64 {
                 return None;
             }
             Some(libc::timespec {
@@ -108,3 +199,62 @@ fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
         None => None,
     }
 }
+
+const TRUE: usize = 0;
+const FALSE: usize = 1;
+const UNINITIALIZED: usize = 2;
+
+// Whether the kernel supports a syscall, probed on first use. Threads that race on the first use
+// all probe, and come to the same answer.
+pub(super) struct KernelSupport(AtomicUsize);
+
+impl KernelSupport {
+    pub(super) const fn new() -> KernelSupport {
+        KernelSupport(AtomicUsize::new(UNINITIALIZED))
+    }
+
+    pub(super) fn get<F: FnOnce() -> bool>(&self, probe: F) -> bool {
+        match self.0.load(Ordering::Relaxed) {
+            TRUE => true,
+            FALSE => false,
+            _ => {
+                let supported = probe();
+                let state = if supported { TRUE } else { FALSE };
+                self.0.store(state, Ordering::Relaxed);
+                supported
+            }
+        }
+    }
+}
+
+// `futex_waitv` takes a `struct __kernel_timespec`, which has a 64-bit `tv_sec` and `tv_nsec`
+// everywhere, also where `libc::timespec` does not.
+#[repr(C)]
+pub(super) struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+// `futex_waitv` only takes an absolute timeout. Returns `None` if the deadline overflows,
+// which we treat as an infinite timeout.
+pub(super) fn convert_deadline(timeout: Duration) -> Option<KernelTimespec> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let r = unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    debug_assert_eq!(r, 0);
+    let mut secs = timeout.as_secs().checked_add(now.tv_sec as u64)?;
+    let mut nanos = timeout.subsec_nanos() + now.tv_nsec as u32;
+    if nanos >= 1_000_000_000 {
+        secs = secs.checked_add(1)?;
+        nanos -= 1_000_000_000;
+    }
+    if secs > i64::MAX as u64 {
+        return None;
+    }
+    Some(KernelTimespec {
+        tv_sec: secs as i64,
+        tv_nsec: nanos as i64,
+    })
+}
@@ -17,64 +17,175 @@
 // TL;DR: make a futex process-private if possible, and do a write on the atomic before reparking a
 // futex after a spurious wakeup.
 
-use core::sync::atomic::Ordering::{Relaxed, Release};
-use core::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use core::fmt;
+use core::hint;
+use core::sync::atomic::AtomicU32;
 use core::time::Duration;
 
-use crate::RESERVED_MASK;
-
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 mod darwin;
 #[cfg(target_os = "dragonfly")]
 mod dragonfly;
+#[cfg(target_os = "emscripten")]
+mod emscripten;
 #[cfg(target_os = "freebsd")]
 mod freebsd;
 #[cfg(target_os = "fuchsia")]
 mod fuchsia;
+// Not part of the public API, only for the fuzz harness in `fuzz/`.
+#[cfg(all(
+    feature = "fuzz",
+    any(valet_backend = "futex", valet_backend = "windows")
+))]
+#[doc(hidden)]
+pub mod fuzz;
+#[cfg(target_os = "hermit")]
+mod hermit;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod linux;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod multi;
+#[cfg(target_os = "netbsd")]
+mod netbsd;
 #[cfg(target_os = "openbsd")]
 mod openbsd;
 #[cfg(target_os = "redox")]
 mod redox;
-#[cfg(all(target_arch = "wasm32", target_feature = "atomics"))]
+pub mod safe;
+#[cfg(all(
+    target_arch = "wasm32",
+    target_feature = "atomics",
+    not(target_os = "emscripten")
+))]
 mod wasm_atomic;
 #[cfg(windows)]
 mod windows;
 
-/// Reason the operating system provided for waking up a thread. Because of the limited guarantees
-/// of some platforms, this turns out not to be all that useful except for documentation purposes.
-#[allow(dead_code)]
-pub enum WakeupReason {
-    /// Thread did not get parked, because the `expected` value did not match.
-    /// Not all operating systems report this case.
-    NoMatch,
-    /// Thread got woken up because its timeout expired.
-    /// Only DragonFly BSD does not report this reliably.
-    TimedOut,
-    /// Thread got woken up because of an interrupt.
-    Interrupt,
-    /// Thread got woken up by a `wake` call.
-    WokenUp,
-    /// Thread may be woken up by a `wake` call, but it may also have been for other reasons.
-    Unknown,
-}
+#[cfg(any(
+    valet_backend = "futex",
+    valet_backend = "futex_or_posix",
+    valet_backend = "windows"
+))]
+mod parking;
+#[cfg(any(
+    valet_backend = "futex",
+    valet_backend = "futex_or_posix",
+    valet_backend = "windows"
+))]
+mod sync;
+#[cfg(any(
+    valet_backend = "futex",
+    valet_backend = "futex_or_posix",
+    valet_backend = "windows"
+))]
+pub(crate) use self::parking::*;
+
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+pub(crate) use self::darwin::has_futex;
+#[cfg(target_os = "netbsd")]
+pub(crate) use self::netbsd::has_futex;
+
+#[cfg(target_os = "dragonfly")]
+pub(crate) use self::dragonfly::{wait_shared, wake_shared};
+#[cfg(target_os = "freebsd")]
+pub(crate) use self::freebsd::{wait_shared, wake_shared};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) use self::linux::{wait_shared, wake_shared};
 
+#[doc(no_inline)]
+pub use crate::WakeupReason;
+
+/// A futex-like API on an atomic integer: park the thread while the atomic has some value, and wake
+/// threads parked on it.
+///
+/// # Supported types
+/// Every platform implements this trait for `AtomicU32` and `AtomicI32`, and on 32-bit targets for
+/// `AtomicUsize` and `AtomicIsize`. FreeBSD also implements it for the 64-bit atomics. Windows
+/// implements it for all atomic integers and `AtomicBool`, as `WaitOnAddress` compares 1, 2, 4 or
+/// 8 bytes.
+///
+/// `AtomicPtr` implements it on every platform except 64-bit big-endian ones other than Windows.
+/// On 64-bit targets other than Windows only the 32 low-order bits of the pointer are compared,
+/// see the notes on that implementation.
+///
+/// There is deliberately no implementation for `AtomicBool` on the other platforms. Their futex
+/// syscalls compare a whole aligned 4-byte word, while a `bool` is a single byte. Casting its
+/// address to a pointer to a 4-byte integer would read the neighbouring bytes, which belong to
+/// other objects or are out of bounds, and the address is not even aligned in general. Keep a flag
+/// in an `AtomicU32` instead.
 pub trait Futex {
     type Integer;
 
+    /// Returns whether `self` equals `expected`, loading it with `Relaxed` ordering.
+    ///
+    /// Only used by the default implementation of [`wait`](#method.wait), to spin on the value.
+    /// The default implementation returns `None`, which makes the default `wait` return
+    /// `FutexError::Unsupported`. Implementations with a real `wait` don't need this.
+    fn compare_relaxed(&self, expected: &Self::Integer) -> Option<bool> {
+        let _ = expected;
+        None
+    }
+
     /// Park the current thread if `self` equals `expected`. Most implementations will only compare
     /// the 32 high-order bits.
     ///
     /// `timeout` is relative duration, not an absolute deadline.
     ///
     /// This function does not guard against spurious wakeups.
+    ///
+    /// The default implementation is for platforms without a real backend: it spins until the
+    /// value of `self` no longer equals `expected`, using [`compare_relaxed`]. Generic code keeps
+    /// working this way, but only performs well for short waits. With the `tracing` feature a
+    /// warning is emitted the first time it is used. It returns `FutexError::Unsupported` if
+    /// `compare_relaxed` is not implemented.
+    ///
+    /// There is no clock in `no_std`, so with a `timeout` the default implementation gives up after
+    /// a fixed number of spins (2<sup>16</sup>), no matter how long or short the timeout is. That
+    /// is reported as a spurious wakeup, not as `TimedOut`.
+    ///
+    /// [`compare_relaxed`]: #method.compare_relaxed
     fn wait(
         &self,
-        _expected: Self::Integer,
-        _timeout: Option<Duration>,
-    ) -> Result<WakeupReason, ()> {
-        Err(())
+        expected: Self::Integer,
+        timeout: Option<Duration>,
+    ) -> Result<WakeupReason, FutexError> {
+        match self.compare_relaxed(&expected) {
+            None => return Err(FutexError::Unsupported),
+            Some(false) => return Ok(WakeupReason::NoMatch),
+            Some(true) => {}
+        }
+        #[cfg(feature = "tracing")]
+        warn_spinning_wait();
+        // One load per iteration, the comparison above doubles as the first one.
+        let mut spins = 0;
+        loop {
+            hint::spin_loop();
+            if self.compare_relaxed(&expected) != Some(true) {
+                return Ok(WakeupReason::Unknown);
+            }
+            if timeout.is_some() {
+                spins += 1;
+                if spins == DEFAULT_WAIT_TIMEOUT_SPINS {
+                    return Ok(WakeupReason::Unknown);
+                }
+            }
+        }
+    }
+
+    /// The same as [`wait`], for a caller that just loaded `expected` from `self`.
+    ///
+    /// Retry loops have often just observed the value they are going to wait on. Implementations
+    /// can trust `expected` to be that value, and leave the comparison to the operating system
+    /// instead of doing an extra load first. The implementations in this crate never load the
+    /// atomic on the Rust side before waiting, so the default implementation calls [`wait`].
+    ///
+    /// [`wait`]: #method.wait
+    fn wait_checked(
+        &self,
+        expected: Self::Integer,
+        timeout: Option<Duration>,
+    ) -> Result<WakeupReason, FutexError> {
+        self.wait(expected, timeout)
     }
 
     /// Wake all threads waiting on `self`, and set `self` to `new`.
@@ -84,122 +195,383 @@ pub trait Futex {
     /// Events for one needs to know the number of threads parked. So we make it up to the
     /// implementation to set set `self` to `new`.
     ///
-    /// We don't support waking n out of m waiting threads. This gets into pretty advanced use cases,
-    /// and it is not clear this can be supported cross-platform and without too much overhead.
-    fn wake(&self) -> Result<usize, ()> {
-        Err(())
+    /// To wake only some of the waiting threads use [`wake_one`] or [`wake_n`].
+    ///
+    /// The default implementation does nothing: the threads spinning in the default [`wait`]
+    /// notice the changed value by themselves. It returns 0, as it doesn't know how many threads
+    /// there are.
+    ///
+    /// [`wait`]: #method.wait
+    /// [`wake_one`]: #method.wake_one
+    /// [`wake_n`]: #method.wake_n
+    fn wake(&self) -> Result<usize, FutexError> {
+        Ok(0)
+    }
+
+    /// Wake one thread waiting on `self`.
+    ///
+    /// Returns the number of threads woken, 0 or 1, on platforms where the OS reports it (Linux,
+    /// Android, FreeBSD, OpenBSD, DragonFly BSD, Redox and WASM atomics), and 0 otherwise.
+    ///
+    /// Lock implementations can use this to avoid waking all waiting threads only to have all but
+    /// one of them go back to sleep.
+    ///
+    /// The default implementation calls [`wake`], which may wake more than one thread. Callers
+    /// have to handle that anyway, as [`wait`] does not guard against spurious wakeups. The
+    /// threads spinning in the default `wait` all notice a changed value by themselves, so with
+    /// the default `wake` this does nothing and returns 0.
+    ///
+    /// [`wait`]: #method.wait
+    /// [`wake`]: #method.wake
+    fn wake_one(&self) -> Result<usize, FutexError> {
+        self.wake()
+    }
+
+    /// Wake up to `count` threads waiting on `self`, for example to release `count` permits of a
+    /// semaphore at once.
+    ///
+    /// `count` is a best-effort maximum: fewer threads are woken if fewer are waiting. Returns the
+    /// number of threads woken on platforms where the OS reports it (the same as for
+    /// [`wake_one`]), and 0 otherwise. A `count` of 0 wakes no threads.
+    ///
+    /// Most implementations pass `count` to the operating system. The default implementation calls
+    /// [`wake_one`] `count` times, which is what Darwin and Windows use. With the default
+    /// `wake_one` and [`wake`] it does nothing and returns 0, like `wake`.
+    ///
+    /// [`wake`]: #method.wake
+    /// [`wake_one`]: #method.wake_one
+    fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+        let mut woken = 0;
+        for _ in 0..count {
+            woken += self.wake_one()?;
+        }
+        Ok(woken)
+    }
+
+    /// The same as [`wait`], for an atomic in memory shared between processes.
+    ///
+    /// The other methods use process-private futexes, which the kernel finds by their virtual
+    /// address in the current process. A shared futex is found by the memory object backing it,
+    /// for example a `MAP_SHARED` mapping, so threads in different processes that map the same
+    /// memory can wait on and wake each other. This is slower, because the kernel has to resolve
+    /// the backing object on every call. Only use the shared methods on both sides: a shared wake
+    /// does not wake a private wait on the same atomic, and the other way around.
+    ///
+    /// Supported on Linux, Android, FreeBSD, OpenBSD and Redox. The default implementation returns
+    /// `FutexError::Unsupported`.
+    ///
+    /// [`wait`]: #method.wait
+    fn wait_shared(
+        &self,
+        expected: Self::Integer,
+        timeout: Option<Duration>,
+    ) -> Result<WakeupReason, FutexError> {
+        let _ = (expected, timeout);
+        Err(FutexError::Unsupported)
+    }
+
+    /// Wake all threads, in any process, waiting on `self` with [`wait_shared`].
+    ///
+    /// Returns the number of threads woken where the OS reports it, like [`wake_one`]. The default
+    /// implementation returns `FutexError::Unsupported`.
+    ///
+    /// [`wait_shared`]: #method.wait_shared
+    /// [`wake_one`]: #method.wake_one
+    fn wake_shared(&self) -> Result<usize, FutexError> {
+        Err(FutexError::Unsupported)
+    }
+
+    /// Wake one thread, in any process, waiting on `self` with [`wait_shared`].
+    ///
+    /// The default implementation returns `FutexError::Unsupported`.
+    ///
+    /// [`wait_shared`]: #method.wait_shared
+    fn wake_one_shared(&self) -> Result<usize, FutexError> {
+        Err(FutexError::Unsupported)
     }
 }
 
-//
-// Implementation of the Waiters trait
-//
-const HAS_WAITERS: usize = 0x1 << UNCOMPARED_LO_BITS;
-pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
-    let old = atomic.compare_and_swap(expected, expected | HAS_WAITERS, Ordering::Relaxed);
-    if old & !RESERVED_MASK != expected {
-        return;
+// Number of iterations the default `Futex::wait` spins for when a timeout is supplied.
+const DEFAULT_WAIT_TIMEOUT_SPINS: usize = 1 << 16;
+
+// The part of an `AtomicPtr` the futex compares. On 32-bit targets that is the whole pointer, and
+// `WaitOnAddress` compares all 8 bytes on 64-bit Windows.
+#[cfg(any(target_pointer_width = "32", windows))]
+type PtrWord = core::sync::atomic::AtomicUsize;
+#[cfg(all(target_pointer_width = "64", target_endian = "little", not(windows)))]
+type PtrWord = AtomicU32;
+
+/// Wait on a pointer, for example for a tag in its low-order bits to change.
+///
+/// The futex syscalls on 64-bit targets other than Windows compare 32 bits, so this only compares
+/// the 4 bytes at the address of the atomic, just like `Waiters` does on an `AtomicUsize`. On
+/// little-endian targets those are the 32 low-order bits of the address, which contain the tag
+/// bits that alignment leaves free. A store that only changes the 32 high-order bits is not seen
+/// by the kernel: a thread that starts waiting after such a store goes to sleep even though the
+/// pointer no longer equals `expected`, until the next wake. Always change a tag bit when changing
+/// the pointer, or only wait on pointers into the same 4 GiB region.
+///
+/// On 64-bit big-endian targets the 4 bytes at the address of the atomic are the high-order bits,
+/// which don't contain the tag. Comparing the other half would be a mixed-size access that doesn't
+/// start at the same address, see `get_i32_ref`, so there is no implementation there.
+///
+/// The `wait` and `wake` methods must be paired on the `AtomicPtr`, not mixed with a futex on
+/// another integer type that overlaps it.
+#[cfg(any(target_pointer_width = "32", target_endian = "little", windows))]
+impl<T> Futex for core::sync::atomic::AtomicPtr<T> {
+    type Integer = *mut T;
+
+    #[inline]
+    fn wait(
+        &self,
+        expected: Self::Integer,
+        timeout: Option<Duration>,
+    ) -> Result<WakeupReason, FutexError> {
+        ptr_word(self).wait(ptr_word_value(expected), timeout)
     }
-    loop {
-        unsafe {
-            let atomic_i32 = get_i32_ref(atomic);
-            let expected = ((expected | HAS_WAITERS) >> UNCOMPARED_LO_BITS) as u32 as i32;
-            let _ = atomic_i32.wait(expected, None);
-        }
-        let old = atomic.compare_and_swap(
-            expected | HAS_WAITERS,
-            expected | HAS_WAITERS,
-            Ordering::Relaxed,
-        );
-        if old != (expected | HAS_WAITERS) {
-            break;
+
+    #[inline]
+    fn wait_checked(
+        &self,
+        expected: Self::Integer,
+        timeout: Option<Duration>,
+    ) -> Result<WakeupReason, FutexError> {
+        ptr_word(self).wait_checked(ptr_word_value(expected), timeout)
+    }
+
+    #[inline]
+    fn wake(&self) -> Result<usize, FutexError> {
+        ptr_word(self).wake()
+    }
+
+    #[inline]
+    fn wake_one(&self) -> Result<usize, FutexError> {
+        ptr_word(self).wake_one()
+    }
+
+    #[inline]
+    fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+        ptr_word(self).wake_n(count)
+    }
+
+    #[inline]
+    fn wait_shared(
+        &self,
+        expected: Self::Integer,
+        timeout: Option<Duration>,
+    ) -> Result<WakeupReason, FutexError> {
+        ptr_word(self).wait_shared(ptr_word_value(expected), timeout)
+    }
+
+    #[inline]
+    fn wake_shared(&self) -> Result<usize, FutexError> {
+        ptr_word(self).wake_shared()
+    }
+
+    #[inline]
+    fn wake_one_shared(&self) -> Result<usize, FutexError> {
+        ptr_word(self).wake_one_shared()
+    }
+}
+
+#[cfg(any(target_pointer_width = "32", target_endian = "little", windows))]
+#[inline]
+fn ptr_word<T>(atomic: &core::sync::atomic::AtomicPtr<T>) -> &PtrWord {
+    use crate::utils::AtomicAsMutPtr;
+    // An `AtomicPtr` has the size and alignment of a pointer, which is at least that of `PtrWord`.
+    unsafe { &*(atomic.as_mut_ptr() as *const PtrWord) }
+}
+
+#[cfg(any(target_pointer_width = "32", windows))]
+#[inline]
+fn ptr_word_value<T>(ptr: *mut T) -> usize {
+    ptr as usize
+}
+#[cfg(all(target_pointer_width = "64", target_endian = "little", not(windows)))]
+#[inline]
+fn ptr_word_value<T>(ptr: *mut T) -> u32 {
+    ptr as usize as u32
+}
+
+#[cfg(feature = "tracing")]
+fn warn_spinning_wait() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        tracing::warn!("no futex backend for this type, `Futex::wait` falls back to spinning");
+    }
+}
+
+/// The reason a [`Futex`] operation failed.
+///
+/// [`Futex`]: trait.Futex.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FutexError {
+    /// The operation is not supported by the operating system, or not for this integer type.
+    ///
+    /// On Windows every operation needs `WaitOnAddress`, and is unsupported on versions before
+    /// Windows 8 or with the `fallback` feature.
+    Unsupported,
+    /// The operating system refused to block the current thread.
+    WouldBlock,
+    /// The operating system rejected the address of the atomic or the timeout as invalid.
+    Fault,
+    /// Any other error code returned by the operating system.
+    Os(i32),
+}
+
+impl fmt::Display for FutexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FutexError::Unsupported => f.write_str("futex operation not supported"),
+            FutexError::WouldBlock => f.write_str("futex operation would block"),
+            FutexError::Fault => f.write_str("invalid address or timeout for futex operation"),
+            FutexError::Os(code) => write!(f, "futex operation failed with error code {}", code),
         }
     }
 }
 
-pub(crate) fn store_and_wake(atomic: &AtomicUsize, new: usize) {
-    if atomic.swap(new, Ordering::Release) & HAS_WAITERS == HAS_WAITERS {
-        unsafe {
-            let atomic_i32 = get_i32_ref(atomic);
-            let _ = atomic_i32.wake();
+#[cfg(feature = "std")]
+impl std::error::Error for FutexError {}
+
+// Map the `errno` values the futex-like syscalls have in common.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+impl FutexError {
+    fn from_errno(errno: i32) -> FutexError {
+        match errno {
+            libc::ENOSYS => FutexError::Unsupported,
+            libc::EFAULT | libc::EINVAL => FutexError::Fault,
+            e => FutexError::Os(e),
         }
     }
 }
 
-/// The `Waiters` trait has to be implemented on an `AtomicUsize` because we need a pointer-sized
-/// value for some implementations. But the `Futex` trait is implemented on an `AtomicI32` because
-/// that is wait the OS interface relies on. On 64-bit platforms we are going to crate a reference
-/// to only a 32-bit portion of the `AtomicUsize`.
-///
-/// There are the obvious concerns about size, alignment, and endianness. But this is above all a
-/// questionable operation because it is not explicitly supported by the C++ memory model. There is
-/// little information on what happens when you do atomic operations on only a part of the atomic.
-/// One paper is [Mixed-size Concurrency: ARM, POWER, C/C++11, and SC][Mixed-size Concurrency].
-///
-/// We should not assume that the kernel does anything stonger with the atomic than a relaxed load.
-/// But it may also do a CAS loop that writes to the atomic, as long as the value is not modified
-/// (DragonFly BSD is a documented case).
-///
-/// The one thing to worry about for us is preserving *modification order consistency* of the atomic
-/// integer. This normally relies on the integer having the same address. The processor may not
-/// track 'overlapping footprints' of the smaller integer (as the paper calls it). So when the
-/// smaller integer part of an atomic starts at a different address, we would have to use orderings
-/// such as Release or SeqCst to prevent reordering of operations on the smaller integer with
-/// operations on the full atomic.
-///
-/// As we don't control the memory orderings the kernel uses, our only option is to use the part of
-/// the atomic that starts at the same address. On little-endian this are the 32 low-order bits, on
-/// big-endian the 32 high-order bits. Notably this part may not contain the (high-order) bits that
-/// match the `expected` value of `compare_and_wait`.
-///
-/// Mixed-size Concurrency: https://hal.inria.fr/hal-01413221/document
-pub(crate) unsafe fn get_i32_ref(ptr_sized: &AtomicUsize) -> &AtomicI32 {
-    &*(ptr_sized as *const AtomicUsize as *const AtomicI32)
+/// The modification [`wake_op`] does on its second atomic, with the argument of the operation.
+///
+/// The argument is a 12-bit signed integer, from -2048 to 2047.
+///
+/// [`wake_op`]: fn.wake_op.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeOpModify {
+    /// Store the argument.
+    Set(i32),
+    /// Add the argument.
+    Add(i32),
+    /// Bitwise or with the argument.
+    Or(i32),
+    /// Bitwise and with the complement of the argument.
+    AndNot(i32),
+    /// Bitwise xor with the argument.
+    Xor(i32),
 }
-#[cfg(target_pointer_width = "32")]
-const UNCOMPARED_LO_BITS: usize = 0;
-#[cfg(all(target_pointer_width = "64", target_endian = "little"))]
-const UNCOMPARED_LO_BITS: usize = 0;
-#[cfg(all(target_pointer_width = "64", target_endian = "big"))]
-const UNCOMPARED_LO_BITS: usize = 32;
 
-//
-// Implementation of the Parker trait
-//
-pub(crate) type Parker = AtomicI32;
-
-// States for Parker
-const NOT_PARKED: i32 = 0x0;
-const PARKED: i32 = 0x1;
-const NOTIFIED: i32 = 0x2;
-
-#[allow(clippy::match_wild_err_arm)]
-pub(crate) fn park(atomic: &AtomicI32, timeout: Option<Duration>) {
-    loop {
-        match atomic.compare_exchange(NOT_PARKED, PARKED, Release, Relaxed) {
-            Ok(_) => {}
-            Err(NOTIFIED) => {
-                atomic.store(NOT_PARKED, Relaxed);
-                return;
-            }
-            Err(_) => panic!(
-                "Tried to call park on an atomic while \
-                 another thread is already parked on it"
-            ),
+/// The comparison [`wake_op`] does on the old value of its second atomic, with the argument to
+/// compare against.
+///
+/// The old value is compared as a signed integer. The argument is a 12-bit signed integer, from
+/// -2048 to 2047.
+///
+/// [`wake_op`]: fn.wake_op.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeOpCompare {
+    /// The old value equals the argument.
+    Eq(i32),
+    /// The old value does not equal the argument.
+    Ne(i32),
+    /// The old value is less than the argument.
+    Lt(i32),
+    /// The old value is less than or equal to the argument.
+    Le(i32),
+    /// The old value is greater than the argument.
+    Gt(i32),
+    /// The old value is greater than or equal to the argument.
+    Ge(i32),
+}
+
+/// The operation of [`wake_op`]: modify the second atomic, and compare its old value to decide
+/// whether to wake threads waiting on it.
+///
+/// [`wake_op`]: fn.wake_op.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WakeOp {
+    /// The modification of the second atomic.
+    pub modify: WakeOpModify,
+    /// The comparison on the old value of the second atomic.
+    pub compare: WakeOpCompare,
+}
+
+impl WakeOp {
+    // The `val3` argument of `FUTEX_WAKE_OP`. `None` if an argument doesn't fit in 12 bits.
+    #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+    fn encode(self) -> Option<u32> {
+        let (op, oparg) = match self.modify {
+            WakeOpModify::Set(arg) => (0, arg),
+            WakeOpModify::Add(arg) => (1, arg),
+            WakeOpModify::Or(arg) => (2, arg),
+            WakeOpModify::AndNot(arg) => (3, arg),
+            WakeOpModify::Xor(arg) => (4, arg),
+        };
+        let (cmp, cmparg) = match self.compare {
+            WakeOpCompare::Eq(arg) => (0, arg),
+            WakeOpCompare::Ne(arg) => (1, arg),
+            WakeOpCompare::Lt(arg) => (2, arg),
+            WakeOpCompare::Le(arg) => (3, arg),
+            WakeOpCompare::Gt(arg) => (4, arg),
+            WakeOpCompare::Ge(arg) => (5, arg),
         };
-        let _ = atomic.wait(PARKED, timeout);
-        let wakeup_state = atomic.swap(NOT_PARKED, Relaxed);
-        if wakeup_state == NOTIFIED || timeout.is_some() {
-            // We were either woken up by another thread (NOTIFIED), or there was a timeout
-            // supplied, in which case we don't guarantee there are no spurious wakeups.
-            break;
+        const ARG_RANGE: core::ops::RangeInclusive<i32> = -0x800..=0x7ff;
+        if !ARG_RANGE.contains(&oparg) || !ARG_RANGE.contains(&cmparg) {
+            return None;
         }
+        Some(op << 28 | cmp << 24 | (oparg as u32 & 0xfff) << 12 | (cmparg as u32 & 0xfff))
     }
 }
 
-pub(crate) fn unpark(atomic: &AtomicI32) {
-    if atomic.swap(NOTIFIED, Release) == PARKED {
-        let _ = atomic.wake();
+/// Atomically modify `atomic2` and wake threads waiting on two atomics, with `FUTEX_WAKE_OP`.
+///
+/// The kernel does the following as one operation:
+/// - Apply `op.modify` to `atomic2`, and remember its old value.
+/// - Wake up to `wake_count` threads waiting on `atomic`.
+/// - If the old value of `atomic2` matches `op.compare`, also wake up to `wake_count2` threads
+///   waiting on `atomic2`.
+///
+/// Returns the total number of threads woken. This makes it possible to, for example, unlock a
+/// mutex and only wake a waiter if the mutex was contended, in one system call.
+///
+/// Linux wakes one waiting thread even if a count is zero. So `atomic` can be the same atomic as
+/// `atomic2` only if it is fine to always wake one of its waiters.
+///
+/// Only implemented on Linux and Android, every other platform returns `FutexError::Unsupported`.
+/// An argument in `op` that doesn't fit in 12 bits returns `FutexError::Fault`.
+///
+/// # Atomic ordering
+/// The modification of `atomic2` by the kernel acts as a sequentially consistent read-modify-write.
+pub fn wake_op(
+    atomic: &AtomicU32,
+    wake_count: usize,
+    atomic2: &AtomicU32,
+    wake_count2: usize,
+    op: WakeOp,
+) -> Result<usize, FutexError> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let encoded = op.encode().ok_or(FutexError::Fault)?;
+        linux::wake_op(atomic, wake_count, atomic2, wake_count2, encoded)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (atomic, wake_count, atomic2, wake_count2, op);
+        Err(FutexError::Unsupported)
     }
 }
 
@@ -209,6 +581,8 @@ mod test {
     use crate::Futex;
     use std::sync::atomic::{AtomicU32, Ordering};
     use std::thread::spawn;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "netbsd"))]
+    use std::thread::{sleep, JoinHandle};
     use std::time::Duration;
 
     #[test]
@@ -218,6 +592,17 @@ mod test {
         let _ = futex.wait(1, None);
     }
 
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "netbsd"))]
+    // A stale `expected` value is still caught by the operating system.
+    fn futex_wait_checked_stale_value() {
+        let futex = AtomicU32::new(0);
+        assert_eq!(
+            futex.wait_checked(1, None),
+            Ok(super::WakeupReason::NoMatch)
+        );
+    }
+
     #[test]
     // Panics if it is able to observe changes made by another thread while it should be waiting.
     // May fail if there is a spurious wakeup.
@@ -243,10 +628,707 @@ mod test {
         assert_eq!(OTHER.load(Ordering::Relaxed), 1000);
     }
 
+    // Spawn `count` threads that wait on `futex` until it is no longer 0.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "netbsd"))]
+    fn spawn_waiters(futex: &'static AtomicU32, count: usize) -> Vec<JoinHandle<()>> {
+        (0..count)
+            .map(|_| {
+                spawn(move || {
+                    while futex.load(Ordering::Relaxed) == 0 {
+                        let _ = futex.wait(0, None);
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // Set `futex` to 1, and wake until all `waiters` returned.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "netbsd"))]
+    fn finish_waiters(futex: &'static AtomicU32, waiters: Vec<JoinHandle<()>>) {
+        futex.store(1, Ordering::Relaxed);
+        while !waiters.iter().all(|waiter| waiter.is_finished()) {
+            let _ = futex.wake();
+            sleep(Duration::from_millis(1));
+        }
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+
+    // Two threads wait, `wake_one` wakes exactly one of them.
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "netbsd"))]
+    fn futex_wake_one() {
+        static FUTEX: AtomicU32 = AtomicU32::new(0);
+        let waiters = spawn_waiters(&FUTEX, 2);
+        // Wake until one thread is woken, the waiters may not have started waiting yet. The woken
+        // thread goes back to sleep, the value has not changed.
+        while FUTEX.wake_one() != Ok(1) {
+            sleep(Duration::from_millis(1));
+        }
+        finish_waiters(&FUTEX, waiters);
+    }
+
+    // Three threads wait. `wake_n` wakes no more than `count` of them, and all of them with a
+    // larger `count`.
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "netbsd"))]
+    fn futex_wake_n() {
+        static FUTEX: AtomicU32 = AtomicU32::new(0);
+        let waiters = spawn_waiters(&FUTEX, 3);
+        // Wake until two threads are woken at once, the waiters may not have started waiting yet.
+        // The woken threads go back to sleep, the value has not changed.
+        while FUTEX.wake_n(2) != Ok(2) {
+            sleep(Duration::from_millis(1));
+        }
+        while FUTEX.wake_n(5) != Ok(3) {
+            sleep(Duration::from_millis(1));
+        }
+        // A `count` of 0 wakes nobody, while all three may be waiting.
+        assert_eq!(FUTEX.wake_n(0), Ok(0));
+        // Not clamped to a negative `int`.
+        assert!(FUTEX.wake_n(usize::MAX).is_ok());
+        finish_waiters(&FUTEX, waiters);
+    }
+
+    #[test]
+    // Waking without waiters should succeed, also when done twice in a row.
+    fn futex_wake_without_waiters() {
+        let futex = AtomicU32::new(0);
+        assert!(futex.wake().is_ok());
+        assert!(futex.wake().is_ok());
+    }
+
+    #[test]
+    // A type without a real backend gets the spinning default implementations.
+    fn futex_default_spins() {
+        use crate::WakeupReason;
+        use std::sync::Arc;
+
+        struct Spinning(AtomicU32);
+        impl Futex for Spinning {
+            type Integer = u32;
+            fn compare_relaxed(&self, expected: &u32) -> Option<bool> {
+                Some(self.0.load(Ordering::Relaxed) == *expected)
+            }
+        }
+
+        let futex = Arc::new(Spinning(AtomicU32::new(0)));
+        assert_eq!(futex.wait(1, None), Ok(WakeupReason::NoMatch));
+        assert_eq!(
+            futex.wait(0, Some(Duration::from_millis(1))),
+            Ok(WakeupReason::Unknown)
+        );
+
+        let futex2 = futex.clone();
+        let waiter = spawn(move || futex2.wait(0, None));
+        futex.0.store(1, Ordering::Relaxed);
+        assert_eq!(futex.wake(), Ok(0));
+        assert!(waiter.join().unwrap().is_ok());
+        // Waking some of the threads is no different from waking all of them.
+        assert_eq!(futex.wake_one(), Ok(0));
+        assert_eq!(futex.wake_n(0), Ok(0));
+        assert_eq!(futex.wake_n(3), Ok(0));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn futex_error_from_errno() {
+        use crate::FutexError;
+        use std::string::ToString;
+
+        assert_eq!(
+            FutexError::from_errno(libc::ENOSYS),
+            FutexError::Unsupported
+        );
+        assert_eq!(FutexError::from_errno(libc::EFAULT), FutexError::Fault);
+        assert_eq!(
+            FutexError::from_errno(libc::EPERM),
+            FutexError::Os(libc::EPERM)
+        );
+        assert_eq!(
+            FutexError::Fault.to_string(),
+            "invalid address or timeout for futex operation"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Unsupported by Miri
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    // A wake on the second futex is reported with its index. Skipped on kernels without
+    // `futex_waitv`.
+    fn futex_wait_any() {
+        use super::multi::wait_any;
+        use crate::FutexError;
+        use std::thread::sleep;
+
+        static A: AtomicU32 = AtomicU32::new(0);
+        static B: AtomicU32 = AtomicU32::new(0);
+        match wait_any(&[(&A, 0), (&B, 1)], None) {
+            Err(FutexError::Unsupported) => return,
+            r => assert_eq!(r, Ok(None)),
+        }
+        assert_eq!(wait_any(&[], None), Ok(None));
+        assert_eq!(
+            wait_any(&[(&A, 0)], Some(Duration::from_millis(10))),
+            Ok(None)
+        );
+
+        let waiter = spawn(|| loop {
+            if let Ok(Some(index)) = wait_any(&[(&A, 0), (&B, 0)], None) {
+                break index;
+            }
+        });
+        // Wake until the thread is woken, it may not have started waiting yet.
+        while B.wake() != Ok(1) {
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(waiter.join().unwrap(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Unsupported by Miri
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    // A tiny mutex that unlocks with a single `wake_op`, which only wakes a thread if the lock was
+    // contended. Its first atomic is an event nobody waits on.
+    fn futex_wake_op_mutex() {
+        use super::{wake_op, WakeOp, WakeOpCompare, WakeOpModify};
+        use std::sync::atomic::AtomicUsize;
+
+        const UNLOCKED: u32 = 0;
+        const LOCKED: u32 = 1;
+        const CONTENDED: u32 = 2;
+        static LOCK: AtomicU32 = AtomicU32::new(UNLOCKED);
+        static EVENT: AtomicU32 = AtomicU32::new(0);
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        fn lock() {
+            if LOCK
+                .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            while LOCK.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = LOCK.wait(CONTENDED, None);
+            }
+        }
+
+        fn unlock() -> usize {
+            let op = WakeOp {
+                modify: WakeOpModify::Set(UNLOCKED as i32),
+                compare: WakeOpCompare::Eq(CONTENDED as i32),
+            };
+            wake_op(&EVENT, 0, &LOCK, 1, op).unwrap()
+        }
+
+        // Without contention there is nothing to wake.
+        lock();
+        assert_eq!(unlock(), 0);
+        assert_eq!(LOCK.load(Ordering::Relaxed), UNLOCKED);
+
+        // Hold the lock until the other threads are blocked on it, so the unlock has to wake one.
+        lock();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                spawn(|| {
+                    for _ in 0..1000 {
+                        lock();
+                        // Not an atomic increment, the lock protects it.
+                        let count = COUNTER.load(Ordering::Relaxed);
+                        COUNTER.store(count + 1, Ordering::Relaxed);
+                        unlock();
+                    }
+                })
+            })
+            .collect();
+        while LOCK.load(Ordering::Relaxed) != CONTENDED {
+            std::thread::yield_now();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(unlock(), 1);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(COUNTER.load(Ordering::Relaxed), 4000);
+
+        let out_of_range = WakeOp {
+            modify: WakeOpModify::Add(0x800),
+            compare: WakeOpCompare::Eq(0),
+        };
+        assert_eq!(
+            wake_op(&EVENT, 0, &LOCK, 0, out_of_range),
+            Err(crate::FutexError::Fault)
+        );
+    }
+
+    #[test]
+    // A wake without a change of the value is treated as a spurious wakeup.
+    fn futex_u32_wait_while() {
+        use super::safe::FutexU32;
+        use std::thread::sleep;
+
+        static FUTEX: FutexU32 = FutexU32::new(0);
+        assert_eq!(FUTEX.wait_while(1, None), Ok(true));
+        assert_eq!(
+            FUTEX.wait_while(0, Some(Duration::from_millis(10))),
+            Ok(false)
+        );
+
+        let waiter = spawn(|| FUTEX.wait_while(0, None));
+        sleep(Duration::from_millis(10));
+        FUTEX.wake_all().unwrap();
+        sleep(Duration::from_millis(10));
+        assert!(!waiter.is_finished());
+        FUTEX.as_atomic().store(1, Ordering::Release);
+        FUTEX.wake_one().unwrap();
+        assert_eq!(waiter.join().unwrap(), Ok(true));
+    }
+
+    // A waiter on a tagged pointer wakes up once the tag changes.
+    #[test]
+    #[cfg(any(target_pointer_width = "32", target_endian = "little", windows))]
+    #[cfg_attr(miri, ignore)] // Mixed-size atomic access
+    fn futex_atomic_ptr_tag() {
+        use std::sync::atomic::AtomicPtr;
+        use std::thread::sleep;
+
+        static TARGET: AtomicU32 = AtomicU32::new(0);
+        static PTR: AtomicPtr<AtomicU32> = AtomicPtr::new(std::ptr::null_mut());
+        let untagged = &TARGET as *const AtomicU32 as *mut AtomicU32;
+        let tagged = (untagged as usize | 1) as *mut AtomicU32;
+        PTR.store(untagged, Ordering::Relaxed);
+
+        // Returns immediately because the tag does not match.
+        let _ = PTR.wait(tagged, None);
+
+        let waiter = spawn(|| {
+            let untagged = &TARGET as *const AtomicU32 as *mut AtomicU32;
+            while PTR.load(Ordering::Acquire) == untagged {
+                let _ = PTR.wait(untagged, None);
+            }
+        });
+        sleep(Duration::from_millis(10));
+        PTR.store(tagged, Ordering::Release);
+        PTR.wake().unwrap();
+        waiter.join().unwrap();
+    }
+
     #[test]
     // This test will hang if it does not wake from a timeout.
     fn futex_wakes_from_timeout() {
         let futex = AtomicU32::new(0);
         let _ = futex.wait(0, Some(Duration::from_millis(10)));
     }
+
+    #[test]
+    // A timeout that overflows the platform representation should be treated as infinite, not be
+    // truncated into a negative or invalid value. With debug assertions an invalid timeout would
+    // trigger the assertion on unexpected return values.
+    fn futex_huge_timeout() {
+        let futex = AtomicU32::new(0);
+        for &timeout in &[
+            Duration::from_secs(u64::MAX),
+            Duration::new(u64::MAX, 999_999_999),
+            Duration::from_secs(i32::MAX as u64 + 1),
+            Duration::from_secs(u32::MAX as u64 + 1),
+        ] {
+            // Returns immediately because the value does not match.
+            let _ = futex.wait(1, Some(timeout));
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    // Signals interrupt `ulock_wait`. The remaining time should be tracked across interrupts, not
+    // restarted on every one of them.
+    fn futex_timeout_across_interrupts() {
+        use std::os::unix::thread::JoinHandleExt;
+        use std::sync::atomic::AtomicBool;
+        use std::time::Instant;
+        use std::{mem, ptr};
+
+        extern "C" fn handler(_: libc::c_int) {}
+        static DONE: AtomicBool = AtomicBool::new(false);
+        const TIMEOUT: Duration = Duration::from_millis(200);
+
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut());
+        }
+        let waiter = spawn(|| {
+            let futex = AtomicU32::new(0);
+            let start = Instant::now();
+            let reason = futex.wait(0, Some(TIMEOUT));
+            DONE.store(true, Ordering::Relaxed);
+            (reason, start.elapsed())
+        });
+        while !DONE.load(Ordering::Relaxed) {
+            unsafe { libc::pthread_kill(waiter.as_pthread_t(), libc::SIGUSR1) };
+            std::thread::sleep(Duration::from_millis(15));
+        }
+        let (reason, elapsed) = waiter.join().unwrap();
+        assert_eq!(reason, Ok(super::WakeupReason::TimedOut));
+        assert!(elapsed >= TIMEOUT);
+        assert!(elapsed < TIMEOUT * 2, "waited {:?}", elapsed);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    // Without a timeout a few signals should not make `wait` return, it waits again instead.
+    fn futex_reparks_after_interrupt() {
+        use std::os::unix::thread::JoinHandleExt;
+        use std::thread::sleep;
+        use std::{mem, ptr};
+
+        extern "C" fn handler(_: libc::c_int) {}
+        static FUTEX: AtomicU32 = AtomicU32::new(0);
+
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut());
+        }
+        let waiter = spawn(|| FUTEX.wait(0, None));
+        for _ in 0..3 {
+            sleep(Duration::from_millis(20));
+            unsafe { libc::pthread_kill(waiter.as_pthread_t(), libc::SIGUSR1) };
+        }
+        sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        FUTEX.store(1, Ordering::Relaxed);
+        while !waiter.is_finished() {
+            let _ = FUTEX.wake();
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(waiter.join().unwrap(), Ok(super::WakeupReason::Unknown));
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "wasm32", target_feature = "atomics"))]
+    // `compare_and_wait` ends the loop based on the reason returned by `memory.atomic.wait`.
+    fn wasm_compare_and_wait_terminates_on_wake() {
+        use crate::{Waiters, RESERVED_BITS};
+        use std::sync::atomic::AtomicUsize;
+
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        let waiter = spawn(|| ATOMIC.compare_and_wait(0));
+        std::thread::sleep(Duration::from_millis(10));
+        unsafe { ATOMIC.store_and_wake(1 << RESERVED_BITS) };
+        waiter.join().unwrap();
+        // Mismatch returns right away.
+        ATOMIC.compare_and_wait(0);
+    }
+
+    #[test]
+    #[cfg(feature = "loom")]
+    // Model check the unpark-before-park race of `unpark_ordering` in `lib.rs`: one parker, two
+    // unparks from another thread with a flag set in between, and waits that all return spuriously.
+    // The second unpark must never get lost, or the parker would spin forever.
+    fn loom_unpark_ordering() {
+        use loom::sync::atomic::{AtomicBool, AtomicI32};
+        use loom::sync::Arc;
+
+        loom::model(|| {
+            let parker = Arc::new(AtomicI32::new(0));
+            let flag = Arc::new(AtomicBool::new(false));
+            let unparker = {
+                let parker = parker.clone();
+                let flag = flag.clone();
+                loom::thread::spawn(move || {
+                    super::unpark(&*parker);
+                    flag.store(true, Ordering::Relaxed);
+                    super::unpark(&*parker);
+                })
+            };
+            while !flag.load(Ordering::Relaxed) {
+                super::park(&*parker, None);
+            }
+            unparker.join().unwrap();
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "loom")]
+    // Two threads unpark concurrently, `park` returns after either of them.
+    fn loom_two_unparkers() {
+        use loom::sync::atomic::AtomicI32;
+        use loom::sync::Arc;
+
+        loom::model(|| {
+            let parker = Arc::new(AtomicI32::new(0));
+            let unparkers: Vec<_> = (0..2)
+                .map(|_| {
+                    let parker = parker.clone();
+                    loom::thread::spawn(move || super::unpark(&*parker))
+                })
+                .collect();
+            super::park(&*parker, None);
+            for unparker in unparkers {
+                unparker.join().unwrap();
+            }
+            // The unparks may have been merged into one, or left one pending.
+            let state = parker.load(Ordering::Relaxed);
+            assert!(state == 0 || state == 2);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "fuzz")]
+    // A few fixed schedules for the model of the fuzz harness, with and without timeouts and
+    // spurious wakeups.
+    fn fuzz_model_park_unpark() {
+        use super::fuzz::park_unpark;
+
+        assert_eq!(park_unpark(&[]), Ok(()));
+        let mut seed = 0x2545_f491u32;
+        for input in 0..64 {
+            let mut data = vec![7, input as u8];
+            for _ in 0..input * 4 {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                data.push(seed as u8);
+            }
+            assert_eq!(park_unpark(&data), Ok(()), "input {:?}", data);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "reserved bits")]
+    fn detects_modified_reserved_bits() {
+        use std::sync::atomic::AtomicUsize;
+
+        let atomic = AtomicUsize::new(0x4);
+        super::compare_and_wait(&atomic, 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Unsupported by Miri
+    #[cfg(all(target_os = "freebsd", target_pointer_width = "64"))]
+    fn futex_64bit() {
+        use std::sync::atomic::{AtomicI64, AtomicU64};
+
+        macro_rules! round_trip {
+            ($atomic_type:ident) => {{
+                static FUTEX: $atomic_type = $atomic_type::new(0);
+                let waiter = spawn(|| {
+                    while FUTEX.load(Ordering::Relaxed) == 0 {
+                        FUTEX.wait(0, None).unwrap();
+                    }
+                });
+                // Only differs from the waiter's `expected` value in the high 32 bits.
+                FUTEX.store(1 << 32, Ordering::Relaxed);
+                FUTEX.wake().unwrap();
+                waiter.join().unwrap();
+                // Returns immediately, the full 64-bit value is compared.
+                FUTEX.wait(0, None).unwrap();
+            }};
+        }
+        round_trip!(AtomicU64);
+        round_trip!(AtomicI64);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Unsupported by Miri
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "openbsd"
+    ))]
+    // A child process waits on a futex in shared memory, and is woken by its parent.
+    fn futex_shared_across_fork() {
+        use crate::WakeupReason;
+        use std::ptr;
+
+        let size = std::mem::size_of::<AtomicU32>();
+        let mem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mem, libc::MAP_FAILED);
+        let futex = unsafe { &*(mem as *const AtomicU32) };
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Only async-signal-safe calls in the child: exit with 0 if we got woken, and with 1
+            // if the timeout expired first.
+            let code = loop {
+                let r = futex.wait_shared(0, Some(Duration::from_secs(10)));
+                if r == Ok(WakeupReason::TimedOut) || r.is_err() {
+                    break 1;
+                }
+                if futex.load(Ordering::Relaxed) != 0 {
+                    break 0;
+                }
+            };
+            unsafe { libc::_exit(code) };
+        }
+        // Give the child some time to start waiting. If it is late it sees the new value, and the
+        // test still passes.
+        std::thread::sleep(Duration::from_millis(50));
+        futex.store(1, Ordering::Relaxed);
+        futex.wake_shared().unwrap();
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        unsafe { libc::munmap(mem, size) };
+    }
+
+    #[test]
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "openbsd",
+        target_os = "redox"
+    ))]
+    // Within one process a shared wait is woken by a shared wake, like a private one.
+    fn futex_shared_round_trip() {
+        static FUTEX: AtomicU32 = AtomicU32::new(0);
+        let waiter = spawn(|| {
+            while FUTEX.load(Ordering::Relaxed) == 0 {
+                FUTEX.wait_shared(0, None).unwrap();
+            }
+        });
+        FUTEX.store(1, Ordering::Relaxed);
+        FUTEX.wake_shared().unwrap();
+        waiter.join().unwrap();
+        assert_eq!(FUTEX.wake_one_shared(), Ok(0));
+    }
+
+    #[test]
+    #[cfg(all(target_pointer_width = "32", not(windows)))]
+    // Pointer-sized atomics are plain 32-bit futexes on 32-bit targets. There is no impl on 64-bit
+    // targets, where the kernel would only compare half of the value.
+    fn futex_usize_32bit() {
+        use std::sync::atomic::{AtomicIsize, AtomicUsize};
+
+        macro_rules! round_trip {
+            ($atomic_type:ident) => {{
+                static FUTEX: $atomic_type = $atomic_type::new(0);
+                let waiter = spawn(|| {
+                    while FUTEX.load(Ordering::Relaxed) == 0 {
+                        FUTEX.wait(0, None).unwrap();
+                    }
+                });
+                FUTEX.store(1, Ordering::Relaxed);
+                FUTEX.wake().unwrap();
+                waiter.join().unwrap();
+                // Returns immediately, the whole value is compared.
+                FUTEX.wait(0, None).unwrap();
+            }};
+        }
+        round_trip!(AtomicUsize);
+        round_trip!(AtomicIsize);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    // `WaitOnAddress` supports all atomic integer widths, check they all wait and wake.
+    fn futex_all_widths() {
+        use std::sync::atomic::*;
+
+        macro_rules! round_trip {
+            ($atomic_type:ident) => {{
+                static FUTEX: $atomic_type = $atomic_type::new(0);
+                let waiter = spawn(|| {
+                    while FUTEX.load(Ordering::Relaxed) == 0 {
+                        FUTEX.wait(0, None).unwrap();
+                    }
+                });
+                FUTEX.store(1, Ordering::Relaxed);
+                FUTEX.wake().unwrap();
+                waiter.join().unwrap();
+            }};
+        }
+        round_trip!(AtomicU8);
+        round_trip!(AtomicI8);
+        round_trip!(AtomicU16);
+        round_trip!(AtomicI16);
+        round_trip!(AtomicU32);
+        round_trip!(AtomicI32);
+        round_trip!(AtomicU64);
+        round_trip!(AtomicI64);
+        round_trip!(AtomicUsize);
+        round_trip!(AtomicIsize);
+    }
+
+    #[test]
+    // `HAS_WAITERS` must be one of the reserved bits, and fall within the 32-bit part of the
+    // atomic that the kernel compares. On 32-bit targets that is the whole atomic, including the
+    // free bits.
+    fn has_waiters_is_compared() {
+        use super::{HAS_WAITERS, UNCOMPARED_LO_BITS};
+        use crate::RESERVED_MASK;
+
+        assert_ne!(HAS_WAITERS & RESERVED_MASK, 0);
+        assert_eq!((HAS_WAITERS >> UNCOMPARED_LO_BITS) as u32, 1);
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(UNCOMPARED_LO_BITS, 0);
+    }
+
+    #[test]
+    // The `i32` that `get_i32_ref` points to must hold the bits of `expected` that
+    // `compare_and_wait` keeps after shifting by `UNCOMPARED_LO_BITS`. Checked on the bytes of the
+    // value, so it needs no mixed-size atomics and also runs under Miri on a big-endian target.
+    fn i32_ref_matches_uncompared_lo_bits() {
+        use super::{HAS_WAITERS, UNCOMPARED_LO_BITS};
+        use crate::{FreeBits, FREE_BITS};
+        use std::convert::TryInto;
+
+        let free = FreeBits::new((1 << FREE_BITS) - 1).bits();
+        for &value in &[HAS_WAITERS, free | HAS_WAITERS, 0x1234_5678 | HAS_WAITERS] {
+            let bytes = value.to_ne_bytes();
+            let first_i32 = i32::from_ne_bytes(bytes[..4].try_into().unwrap());
+            assert_eq!(first_i32, (value >> UNCOMPARED_LO_BITS) as u32 as i32);
+        }
+        // On big-endian 64-bit targets the kernel compares the high half, which holds the free
+        // bits.
+        #[cfg(all(target_pointer_width = "64", target_endian = "big"))]
+        assert_eq!((free >> UNCOMPARED_LO_BITS) as u32 >> 27, 0x1f);
+    }
+
+    #[test]
+    // A `store_and_wake` that only changes the free bits must wake the waiter. On 32-bit and
+    // big-endian 64-bit targets the kernel compares the free bits as well, on little-endian 64-bit
+    // targets only the cleared `HAS_WAITERS` bit.
+    fn store_and_wake_free_bits_only() {
+        use crate::{Waiters, RESERVED_BITS};
+        use std::sync::atomic::AtomicUsize;
+        use std::thread::yield_now;
+
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0b10101 << RESERVED_BITS);
+        let waiter = spawn(|| ATOMIC.compare_and_wait(0b10101 << RESERVED_BITS));
+        while ATOMIC.load(Ordering::Relaxed) & super::HAS_WAITERS == 0 {
+            yield_now();
+        }
+        unsafe { ATOMIC.store_and_wake(0b10100 << RESERVED_BITS) };
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "stats", any(target_os = "linux", target_os = "android")))]
+    fn stats_count_wasted_wakes() {
+        use std::sync::atomic::AtomicUsize;
+
+        // Pretend there is a waiter that left before we could wake it.
+        let atomic = AtomicUsize::new(super::HAS_WAITERS);
+        let before = crate::stats::snapshot();
+        super::store_and_wake(&atomic, 0);
+        let after = crate::stats::snapshot();
+        assert!(after.wake_calls > before.wake_calls);
+        assert!(after.wasted_wakes > before.wasted_wakes);
+    }
 }
@@ -0,0 +1,117 @@
+//! Waiting on several futexes at once, with the `futex_waitv` syscall of Linux 5.16 and later.
+//!
+//! Only available on Linux and Android.
+use core::ptr;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
+use crate::futex::linux::{convert_deadline, KernelSupport, KernelTimespec};
+use crate::futex::FutexError;
+use crate::utils::{errno, AtomicAsMutPtr};
+
+/// The maximum number of futexes [`wait_any`] can wait on.
+///
+/// [`wait_any`]: fn.wait_any.html
+pub const MAX_FUTEXES: usize = 128;
+
+/// Park the current thread until any of `futexes` is woken, if every atomic equals its expected
+/// value.
+///
+/// Returns `Some(index)` of the futex whose wake woke us up, and `None` if we returned for another
+/// reason: one of the values didn't match, the timeout expired, or a signal interrupted the wait.
+/// Like [`Futex::wait`] this does not guard against spurious wakeups, so recheck all values after
+/// it returns. A wake on any one of the futexes wakes us. `timeout` is a relative duration.
+///
+/// Use [`Futex::wake`] or one of its variants on an atomic to wake a thread waiting on it here.
+///
+/// With an empty `futexes` there is nothing to wait for, and this returns `Ok(None)` right away.
+///
+/// Returns `FutexError::Unsupported` on kernels without `futex_waitv`, and `FutexError::Fault` if
+/// `futexes` has more than [`MAX_FUTEXES`] entries.
+///
+/// [`Futex::wait`]: ../trait.Futex.html#method.wait
+/// [`Futex::wake`]: ../trait.Futex.html#method.wake
+/// [`MAX_FUTEXES`]: constant.MAX_FUTEXES.html
+pub fn wait_any(
+    futexes: &[(&AtomicU32, u32)],
+    timeout: Option<Duration>,
+) -> Result<Option<usize>, FutexError> {
+    if futexes.is_empty() {
+        return Ok(None);
+    }
+    if !has_futex_waitv() {
+        return Err(FutexError::Unsupported);
+    }
+    if futexes.len() > MAX_FUTEXES {
+        return Err(FutexError::Fault);
+    }
+    let mut waiters = [FutexWaitv {
+        val: 0,
+        uaddr: 0,
+        flags: 0,
+        reserved: 0,
+    }; MAX_FUTEXES];
+    for (waiter, &(atomic, expected)) in waiters.iter_mut().zip(futexes) {
+        *waiter = FutexWaitv {
+            val: expected as u64,
+            uaddr: atomic.as_mut_ptr() as usize as u64,
+            flags: FUTEX2_SIZE_U32 | FUTEX2_PRIVATE,
+            reserved: 0,
+        };
+    }
+    let ts = timeout.and_then(convert_deadline);
+    let ts_ptr = ts
+        .as_ref()
+        .map(|ts_ref| ts_ref as *const KernelTimespec)
+        .unwrap_or(ptr::null());
+    let r = unsafe {
+        futex_waitv(
+            waiters.as_ptr(),
+            futexes.len() as libc::c_uint,
+            ts_ptr,
+            libc::CLOCK_MONOTONIC,
+        )
+    };
+    if r >= 0 {
+        return Ok(Some(r as usize));
+    }
+    match errno() {
+        libc::EAGAIN | libc::EINTR => Ok(None),
+        libc::ETIMEDOUT if ts.is_some() => Ok(None),
+        e => Err(FutexError::from_errno(e)),
+    }
+}
+
+unsafe fn futex_waitv(
+    waiters: *const FutexWaitv,
+    nr_futexes: libc::c_uint,
+    timeout: *const KernelTimespec,
+    clockid: libc::clockid_t,
+) -> libc::c_long {
+    libc::syscall(SYS_FUTEX_WAITV, waiters, nr_futexes, 0, timeout, clockid)
+}
+
+// `struct futex_waitv` of the kernel, the same layout on every architecture.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FutexWaitv {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+// Not exported by the `libc` crate. The syscall number is the same on all architectures.
+const SYS_FUTEX_WAITV: libc::c_long = 449;
+const FUTEX2_SIZE_U32: u32 = 0x02;
+const FUTEX2_PRIVATE: u32 = 128;
+
+static HAS_FUTEX_WAITV: KernelSupport = KernelSupport::new();
+
+// Probe with an empty vector. Fails with `ENOSYS` without the syscall, and with `EINVAL` otherwise.
+fn has_futex_waitv() -> bool {
+    HAS_FUTEX_WAITV.get(|| {
+        let r = unsafe { futex_waitv(ptr::null(), 0, ptr::null(), libc::CLOCK_MONOTONIC) };
+        r == 0 || errno() != libc::ENOSYS
+    })
+}
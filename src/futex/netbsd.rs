@@ -0,0 +1,174 @@
+use core::mem;
+use core::ptr;
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::AtomicIsize;
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, errno, AtomicAsMutPtr};
+
+// NetBSD's `_lwp_park` and `_lwp_unpark` work on a thread id, not on an address: the waker has to
+// know which threads to unpark. That doesn't fit an interface where the atomic is all that is
+// shared, so we use the native `__futex` syscall instead. It was added in NetBSD 10 with the same
+// operations and semantics as the Linux futex, including a relative timeout for `FUTEX_WAIT`.
+// Older versions don't have it, `has_futex` checks the kernel version once so the `Waiters` and
+// `Parker` implementations can use the Posix implementation instead.
+
+macro_rules! imp_futex {
+    ($atomic_type:ident, $int_type:ident) => {
+        impl Futex for $atomic_type {
+            type Integer = $int_type;
+
+            #[inline]
+            fn wait(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                if !has_futex() {
+                    return Err(FutexError::Unsupported);
+                }
+                let ptr = self.as_mut_ptr() as *mut u32;
+                let ts = convert_timeout(timeout);
+                let ts_ptr = ts
+                    .as_ref()
+                    .map(|ts_ref| ts_ref as *const libc::timespec)
+                    .unwrap_or(ptr::null());
+                let r = unsafe {
+                    libc::syscall(
+                        SYS___FUTEX,
+                        ptr,
+                        FUTEX_WAIT | FUTEX_PRIVATE_FLAG,
+                        expected as libc::c_int,
+                        ts_ptr,
+                        ptr::null_mut::<u32>(),
+                        0,
+                        0,
+                    )
+                };
+                if r == 0 {
+                    return Ok(WakeupReason::Unknown);
+                }
+                match errno() {
+                    libc::EAGAIN => Ok(WakeupReason::NoMatch),
+                    libc::EINTR => Ok(WakeupReason::Interrupt),
+                    libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
+                    e => Err(FutexError::from_errno(e)),
+                }
+            }
+
+            #[inline]
+            fn wake(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut u32, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut u32, 1)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
+                futex_wake(self.as_mut_ptr() as *mut u32, clamp_count(count))
+            }
+        }
+    };
+}
+imp_futex!(AtomicU32, u32);
+imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+#[inline]
+fn futex_wake(ptr: *mut u32, wake_count: libc::c_int) -> Result<usize, FutexError> {
+    if !has_futex() {
+        return Err(FutexError::Unsupported);
+    }
+    let r = unsafe {
+        libc::syscall(
+            SYS___FUTEX,
+            ptr,
+            FUTEX_WAKE | FUTEX_PRIVATE_FLAG,
+            wake_count,
+            ptr::null::<libc::timespec>(),
+            ptr::null_mut::<u32>(),
+            0,
+            0,
+        )
+    };
+    if r < 0 {
+        return Err(FutexError::from_errno(errno()));
+    }
+    Ok(r as usize)
+}
+
+const TRUE: usize = 0;
+const FALSE: usize = 1;
+const UNINITIALIZED: usize = 2;
+
+static HAS_FUTEX: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+
+// Decided from the kernel version instead of by trying the syscall: an unknown syscall raises
+// `SIGSYS` on NetBSD, which kills the process unless it is handled.
+pub(crate) fn has_futex() -> bool {
+    match HAS_FUTEX.load(Ordering::Relaxed) {
+        TRUE => true,
+        FALSE => false,
+        _ => {
+            let supported = get_os_revision() >= NETBSD_10;
+            let state = if supported { TRUE } else { FALSE };
+            HAS_FUTEX.store(state, Ordering::Relaxed);
+            supported
+        }
+    }
+}
+
+// `kern.osrevision`, the value of `__NetBSD_Version__` the kernel was built with: `MMmmrrpp00`.
+fn get_os_revision() -> libc::c_int {
+    let mib = [libc::CTL_KERN, libc::KERN_OSREV];
+    let mut revision: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>();
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            &mut revision as *mut libc::c_int as *mut _,
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    };
+    if ret == -1 {
+        panic!("kern.osrevision sysctl failed");
+    }
+    revision
+}
+
+const NETBSD_10: libc::c_int = 1_000_000_000;
+
+// Not exported by the `libc` crate.
+const SYS___FUTEX: libc::c_int = 166;
+const FUTEX_WAIT: libc::c_int = 0;
+const FUTEX_WAKE: libc::c_int = 1;
+const FUTEX_PRIVATE_FLAG: libc::c_int = 128;
+
+fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
+    match timeout {
+        Some(duration) => {
+            if duration.as_secs() > libc::time_t::MAX as u64 {
+                return None;
+            }
+            Some(libc::timespec {
+                tv_sec: duration.as_secs() as libc::time_t,
+                tv_nsec: duration.subsec_nanos() as libc::c_long,
+            })
+        }
+        None => None,
+    }
+}
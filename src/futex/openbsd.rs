@@ -1,10 +1,11 @@
-use core::cmp;
 use core::ptr;
 use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
-use crate::utils::AtomicAsMutPtr;
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, errno, AtomicAsMutPtr};
 
 macro_rules! imp_futex {
     ($atomic_type:ident, $int_type:ident) => {
@@ -16,47 +17,109 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut u32;
-                let ts = convert_timeout(timeout);
-                let ts_ptr = ts
-                    .as_ref()
-                    .map(|ts_ref| ts_ref as *const libc::timespec)
-                    .unwrap_or(ptr::null());
-                let r = unsafe {
-                    futex(
-                        ptr,
-                        FUTEX_WAIT | FUTEX_PRIVATE_FLAG,
-                        expected as libc::c_int,
-                        ts_ptr,
-                        ptr::null_mut(),
-                    )
-                };
-                match r {
-                    0 => Ok(WakeupReason::Unknown),
-                    libc::EAGAIN => Ok(WakeupReason::NoMatch),
-                    libc::EINTR | libc::ECANCELED => Ok(WakeupReason::Interrupt),
-                    libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
-                    r => {
-                        debug_assert!(false, "Unexpected return value of futex call: {}", r);
-                        Ok(WakeupReason::Unknown)
-                    }
-                }
+                futex_wait(ptr, FUTEX_PRIVATE_FLAG, expected as libc::c_int, timeout)
+            }
+
+            #[inline]
+            fn wake(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut u32, FUTEX_PRIVATE_FLAG, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut u32, FUTEX_PRIVATE_FLAG, 1)
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
                 let ptr = self.as_mut_ptr() as *mut u32;
-                let wake_count = i32::max_value();
-                let r = unsafe { futex(ptr, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, wake_count, ptr::null(), ptr::null_mut()) };
-                debug_assert!(r >= 0, "Unexpected return value of futex call: {}", r);
-                Ok(cmp::max(r as usize, 0))
+                futex_wake(ptr, FUTEX_PRIVATE_FLAG, clamp_count(count))
+            }
+
+            #[inline]
+            fn wait_shared(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                futex_wait(
+                    self.as_mut_ptr() as *mut u32,
+                    0,
+                    expected as libc::c_int,
+                    timeout,
+                )
+            }
+
+            #[inline]
+            fn wake_shared(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut u32, 0, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one_shared(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut u32, 0, 1)
             }
         }
     };
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+// `flags` is `FUTEX_PRIVATE_FLAG`, or 0 for a process-shared futex.
+#[inline]
+fn futex_wait(
+    ptr: *mut u32,
+    flags: libc::c_int,
+    expected: libc::c_int,
+    timeout: Option<Duration>,
+) -> Result<WakeupReason, FutexError> {
+    let ts = convert_timeout(timeout);
+    let ts_ptr = ts
+        .as_ref()
+        .map(|ts_ref| ts_ref as *const libc::timespec)
+        .unwrap_or(ptr::null());
+    let r = unsafe { futex(ptr, FUTEX_WAIT | flags, expected, ts_ptr, ptr::null_mut()) };
+    // Recent OpenBSD versions return -1 and set `errno`, older versions returned the error number
+    // directly.
+    let r = if r == -1 { errno() } else { r };
+    match r {
+        0 => Ok(WakeupReason::Unknown),
+        libc::EAGAIN => Ok(WakeupReason::NoMatch),
+        libc::EINTR | libc::ECANCELED => Ok(WakeupReason::Interrupt),
+        libc::ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
+        e => Err(FutexError::from_errno(e)),
+    }
+}
+
+#[inline]
+fn futex_wake(
+    ptr: *mut u32,
+    flags: libc::c_int,
+    wake_count: libc::c_int,
+) -> Result<usize, FutexError> {
+    let r = unsafe {
+        futex(
+            ptr,
+            FUTEX_WAKE | flags,
+            wake_count,
+            ptr::null(),
+            ptr::null_mut(),
+        )
+    };
+    if r < 0 {
+        return Err(FutexError::from_errno(errno()));
+    }
+    Ok(r as usize)
+}
 
 const FUTEX_WAIT: libc::c_int = 0;
 const FUTEX_WAKE: libc::c_int = 1;
@@ -75,7 +138,7 @@ extern "C" {
 fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
     match timeout {
         Some(duration) => {
-            if duration.as_secs() > libc::time_t::max_value() as u64 {
+            if duration.as_secs() > libc::time_t::MAX as u64 {
                 return None;
             }
             Some(libc::timespec {
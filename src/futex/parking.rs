@@ -0,0 +1,294 @@
+//! Implementation of the `Waiters` and `Parker` traits on top of `Futex`.
+//!
+//! Not compiled when the `fallback` feature replaces the futex-based implementation, except on
+//! Windows where the futex-like `WaitOnAddress` is one of the two backends.
+
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::clock::Deadline;
+use crate::futex::sync::ParkerAtomic;
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::{RESERVED_BITS_MODIFIED, RESERVED_MASK};
+
+//
+// Implementation of the Waiters trait
+//
+pub(super) const HAS_WAITERS: usize = 0x1 << UNCOMPARED_LO_BITS;
+pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
+    let old = match atomic.compare_exchange(expected, expected | HAS_WAITERS, Relaxed, Relaxed) {
+        Ok(x) | Err(x) => x,
+    };
+    debug_check_reserved_bits(old);
+    if old & !RESERVED_MASK != expected {
+        return;
+    }
+    loop {
+        let _r = unsafe {
+            let atomic_i32 = get_i32_ref(atomic);
+            let expected = ((expected | HAS_WAITERS) >> UNCOMPARED_LO_BITS) as u32 as i32;
+            atomic_i32.wait_checked(expected, None)
+        };
+        // `memory.atomic.wait` reports precisely why it returned, also through the Emscripten
+        // functions. A wake is only issued after the value is changed and `HAS_WAITERS` is
+        // cleared, so we can skip the comparison below.
+        #[cfg(any(
+            target_os = "emscripten",
+            all(target_arch = "wasm32", target_feature = "atomics")
+        ))]
+        {
+            if let Ok(WakeupReason::WokenUp) | Ok(WakeupReason::NoMatch) = _r {
+                break;
+            }
+        }
+        let old = match atomic.compare_exchange(
+            expected | HAS_WAITERS,
+            expected | HAS_WAITERS,
+            Relaxed,
+            Relaxed,
+        ) {
+            Ok(x) | Err(x) => x,
+        };
+        if old != (expected | HAS_WAITERS) {
+            break;
+        }
+    }
+}
+
+// Waits at most once. Returns `false` if the value still matches after the wait, because of the
+// timeout or a spurious wakeup. `HAS_WAITERS` stays set; the next wake only does a needless wake.
+pub(crate) fn compare_and_wait_timeout(
+    atomic: &AtomicUsize,
+    expected: usize,
+    deadline: &mut Deadline,
+) -> bool {
+    let old = match atomic.compare_exchange(expected, expected | HAS_WAITERS, Relaxed, Relaxed) {
+        Ok(x) | Err(x) => x,
+    };
+    debug_check_reserved_bits(old);
+    if old & !RESERVED_MASK != expected {
+        return true;
+    }
+    let _r = unsafe {
+        let atomic_i32 = get_i32_ref(atomic);
+        let expected = ((expected | HAS_WAITERS) >> UNCOMPARED_LO_BITS) as u32 as i32;
+        atomic_i32.wait_checked(expected, Some(deadline.remaining()))
+    };
+    #[cfg(any(
+        target_os = "emscripten",
+        all(target_arch = "wasm32", target_feature = "atomics")
+    ))]
+    {
+        if let Ok(WakeupReason::WokenUp) | Ok(WakeupReason::NoMatch) = _r {
+            return true;
+        }
+    }
+    atomic.load(Relaxed) != (expected | HAS_WAITERS)
+}
+
+pub(crate) fn store_and_wake(atomic: &AtomicUsize, new: usize) {
+    let old = atomic.swap(new, Ordering::Release);
+    debug_check_reserved_bits(old);
+    if old & HAS_WAITERS == HAS_WAITERS {
+        wake_waiters(atomic);
+    }
+}
+
+// Wake all waiters, not just one: `new` clears `HAS_WAITERS`, so after a single wake the other
+// waiters would be stranded without anyone knowing to wake them. Every waiter compares the value
+// after waking, so the ones for which `new` matches `expected` will go back to sleep.
+pub(crate) fn handoff_to_one(atomic: &AtomicUsize, new: usize) -> bool {
+    let old = atomic.swap(new, Ordering::Release);
+    debug_check_reserved_bits(old);
+    if old & HAS_WAITERS == HAS_WAITERS {
+        wake_waiters(atomic);
+        true
+    } else {
+        false
+    }
+}
+
+pub(crate) fn compare_store_and_wake(atomic: &AtomicUsize, expected: usize, new: usize) -> bool {
+    let mut current = atomic.load(Relaxed);
+    loop {
+        debug_check_reserved_bits(current);
+        if current & !RESERVED_MASK != expected {
+            return false;
+        }
+        // Compare the whole value including `HAS_WAITERS`, so we know whether we have to wake.
+        match atomic.compare_exchange_weak(current, new, Release, Relaxed) {
+            Ok(_) => break,
+            Err(x) => current = x,
+        }
+    }
+    if current & HAS_WAITERS == HAS_WAITERS {
+        wake_waiters(atomic);
+    }
+    true
+}
+
+// Clearing `HAS_WAITERS` changes the value the kernel compares, so the woken threads see the
+// wakeup as real.
+pub(crate) fn wake_only(atomic: &AtomicUsize) {
+    let old = atomic.fetch_and(!HAS_WAITERS, Release);
+    debug_check_reserved_bits(old);
+    if old & HAS_WAITERS == HAS_WAITERS {
+        wake_waiters(atomic);
+    }
+}
+
+// The kernel owns the queue, we only know if there may be waiters.
+pub(crate) fn waiter_count(atomic: &AtomicUsize) -> Option<usize> {
+    if atomic.load(Relaxed) & HAS_WAITERS == 0 {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+fn wake_waiters(atomic: &AtomicUsize) {
+    unsafe {
+        let atomic_i32 = get_i32_ref(atomic);
+        let r = atomic_i32.wake();
+        // Waiting threads would hang if the wake failed because the OS rejected the address.
+        assert!(
+            r != Err(FutexError::Fault),
+            "futex wake failed: invalid address"
+        );
+        #[cfg(feature = "stats")]
+        crate::stats::record_wake(r);
+    }
+}
+
+// The only reserved bit we ever set is `HAS_WAITERS`, anything else means the user changed the
+// reserved bits while threads may be waiting.
+#[inline]
+fn debug_check_reserved_bits(value: usize) {
+    debug_assert!(
+        value & RESERVED_MASK & !HAS_WAITERS == 0,
+        "{}",
+        RESERVED_BITS_MODIFIED
+    );
+}
+
+/// The `Waiters` trait has to be implemented on an `AtomicUsize` because we need a pointer-sized
+/// value for some implementations. But the `Futex` trait is implemented on an `AtomicI32` because
+/// that is wait the OS interface relies on. On 64-bit platforms we are going to crate a reference
+/// to only a 32-bit portion of the `AtomicUsize`.
+///
+/// There are the obvious concerns about size, alignment, and endianness. But this is above all a
+/// questionable operation because it is not explicitly supported by the C++ memory model. There is
+/// little information on what happens when you do atomic operations on only a part of the atomic.
+/// One paper is [Mixed-size Concurrency: ARM, POWER, C/C++11, and SC][Mixed-size Concurrency].
+///
+/// We should not assume that the kernel does anything stonger with the atomic than a relaxed load.
+/// But it may also do a CAS loop that writes to the atomic, as long as the value is not modified
+/// (DragonFly BSD is a documented case).
+///
+/// The one thing to worry about for us is preserving *modification order consistency* of the atomic
+/// integer. This normally relies on the integer having the same address. The processor may not
+/// track 'overlapping footprints' of the smaller integer (as the paper calls it). So when the
+/// smaller integer part of an atomic starts at a different address, we would have to use orderings
+/// such as Release or SeqCst to prevent reordering of operations on the smaller integer with
+/// operations on the full atomic.
+///
+/// As we don't control the memory orderings the kernel uses, our only option is to use the part of
+/// the atomic that starts at the same address. On little-endian this are the 32 low-order bits, on
+/// big-endian the 32 high-order bits. `UNCOMPARED_LO_BITS` is the shift that moves this part of a
+/// `usize` value into an `i32`.
+///
+/// On little-endian 64-bit targets this part does not contain the (high-order) free bits that
+/// match the `expected` value of `compare_and_wait`, on big-endian ones it does. Either way it
+/// contains `HAS_WAITERS`, which every wake clears, so a waiter never misses a wakeup.
+///
+/// Mixed-size atomic accesses are outside of the Rust memory model, and Miri reports them as data
+/// races when they are not ordered by other synchronization. The tests that wait on an
+/// `AtomicUsize` through this reference are ignored under Miri for that reason.
+///
+/// Mixed-size Concurrency: https://hal.inria.fr/hal-01413221/document
+pub(crate) unsafe fn get_i32_ref(ptr_sized: &AtomicUsize) -> &AtomicI32 {
+    &*(ptr_sized as *const AtomicUsize as *const AtomicI32)
+}
+#[cfg(target_pointer_width = "32")]
+pub(super) const UNCOMPARED_LO_BITS: usize = 0;
+#[cfg(all(target_pointer_width = "64", target_endian = "little"))]
+pub(super) const UNCOMPARED_LO_BITS: usize = 0;
+#[cfg(all(target_pointer_width = "64", target_endian = "big"))]
+pub(super) const UNCOMPARED_LO_BITS: usize = 32;
+
+//
+// Implementation of the Parker trait
+//
+// The `futex_or_posix` backend stores it in an `AtomicUsize` instead, and Windows has its own.
+#[cfg(valet_backend = "futex")]
+pub(crate) type Parker = AtomicI32;
+
+// States for Parker. These are part of the stable layout of `crate::Parker`, and must not change.
+const NOT_PARKED: i32 = 0x0;
+const PARKED: i32 = 0x1;
+const NOTIFIED: i32 = 0x2;
+
+#[allow(clippy::match_wild_err_arm)]
+// The check for `NOTIFIED` before going to sleep has to be an atomic operation that is ordered with
+// the `swap` in `unpark`, which runs on another CPU. A restartable sequence (`rseq`) can't replace
+// it: it only protects against preemption and migration of the current thread, not against
+// concurrent stores from other CPUs. See `benches/park.rs` for the cost of this path.
+pub(crate) fn park<A: ParkerAtomic>(atomic: &A, timeout: Option<Duration>) -> WakeupReason {
+    loop {
+        // Observing `NOTIFIED` must synchronize with the `unpark` that stored it, or the caller may
+        // not see the writes that came before the `unpark`, and park again with nobody to wake it.
+        match atomic.compare_exchange(NOT_PARKED, PARKED, Release, Acquire) {
+            Ok(_) => {}
+            Err(NOTIFIED) => {
+                // A `swap` instead of a `store`: if another `unpark` happened since the
+                // `compare_exchange`, we have to synchronize with it too.
+                atomic.swap(NOT_PARKED, Acquire);
+                return WakeupReason::WokenUp;
+            }
+            Err(_) => panic!(
+                "Tried to call park on an atomic while \
+                 another thread is already parked on it"
+            ),
+        };
+        let r = atomic.wait(PARKED, timeout);
+        let wakeup_state = atomic.swap(NOT_PARKED, Acquire);
+        if wakeup_state == NOTIFIED {
+            return WakeupReason::WokenUp;
+        } else if timeout.is_some() {
+            // There was a timeout supplied, in which case we don't guarantee there are no
+            // spurious wakeups.
+            return match r {
+                Ok(WakeupReason::TimedOut) => WakeupReason::TimedOut,
+                Ok(WakeupReason::Interrupt) => WakeupReason::Interrupt,
+                _ => WakeupReason::Unknown,
+            };
+        }
+    }
+}
+
+// The wakeup is keyed on the address of the parker, so a waiter can't be moved to another one.
+pub(crate) fn transfer_waiter(_from: &AtomicI32, _to: &AtomicI32) -> bool {
+    false
+}
+
+pub(crate) fn is_notified<A: ParkerAtomic>(atomic: &A) -> bool {
+    atomic.load(Relaxed) == NOTIFIED
+}
+
+pub(crate) fn clear_notification<A: ParkerAtomic>(atomic: &A) -> bool {
+    atomic
+        .compare_exchange(NOTIFIED, NOT_PARKED, Acquire, Relaxed)
+        .is_ok()
+}
+
+// Windows wakes a single thread with `WakeByAddressSingle` instead.
+#[cfg_attr(windows, allow(dead_code))]
+pub(crate) fn unpark<A: ParkerAtomic>(atomic: &A) -> bool {
+    if atomic.swap(NOTIFIED, Release) == PARKED {
+        let _ = atomic.wake();
+        true
+    } else {
+        false
+    }
+}
@@ -1,14 +1,16 @@
 use core::ptr;
 use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
 use core::time::Duration;
 
 use syscall::call;
 use syscall::data::TimeSpec;
-use syscall::error::{Error, EAGAIN, EINTR, ETIMEDOUT};
+use syscall::error::{Error, EAGAIN, EFAULT, EINTR, EINVAL, ENOSYS, ETIMEDOUT};
 use syscall::flag::{FUTEX_WAIT, FUTEX_WAKE};
 
-use crate::futex::{Futex, WakeupReason};
-use crate::utils::AtomicAsMutPtr;
+use crate::futex::{Futex, FutexError, WakeupReason};
+use crate::utils::{clamp_count, AtomicAsMutPtr};
 
 macro_rules! imp_futex {
     ($atomic_type:ident, $int_type:ident) => {
@@ -20,7 +22,7 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut i32;
                 let ts = convert_timeout(timeout);
                 let ts_ptr = ts
@@ -45,37 +47,81 @@ macro_rules! imp_futex {
                         EAGAIN => Ok(WakeupReason::NoMatch),
                         EINTR => Ok(WakeupReason::Interrupt),
                         ETIMEDOUT if ts.is_some() => Ok(WakeupReason::TimedOut),
-                        e => {
-                            debug_assert!(false, "Unexpected error of futex syscall: {}", e);
-                            Ok(WakeupReason::Unknown)
-                        }
+                        e => Err(from_errno(e)),
                     },
                 }
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
-                let ptr = self.as_mut_ptr() as *mut i32;
-                let wake_count = i32::max_value();
-                let r = unsafe { call::futex(ptr, FUTEX_WAKE, wake_count, 0, ptr::null_mut()) };
-                match r {
-                    Ok(num_woken) => Ok(num_woken),
-                    Err(Error { errno }) => {
-                        debug_assert!(false, "Unexpected error of futex syscall: {}", errno);
-                        Ok(0)
-                    }
+            fn wake(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut i32, i32::MAX)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                futex_wake(self.as_mut_ptr() as *mut i32, 1)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
                 }
+                futex_wake(self.as_mut_ptr() as *mut i32, clamp_count(count))
+            }
+
+            // Redox has no process-private futexes, every futex is already found by the physical
+            // address of the memory it is in.
+            #[inline]
+            fn wait_shared(
+                &self,
+                expected: Self::Integer,
+                timeout: Option<Duration>,
+            ) -> Result<WakeupReason, FutexError> {
+                self.wait(expected, timeout)
+            }
+
+            #[inline]
+            fn wake_shared(&self) -> Result<usize, FutexError> {
+                self.wake()
+            }
+
+            #[inline]
+            fn wake_one_shared(&self) -> Result<usize, FutexError> {
+                self.wake_one()
             }
         }
     };
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
+
+#[inline]
+fn futex_wake(ptr: *mut i32, wake_count: i32) -> Result<usize, FutexError> {
+    let r = unsafe { call::futex(ptr, FUTEX_WAKE, wake_count, 0, ptr::null_mut()) };
+    match r {
+        Ok(num_woken) => Ok(num_woken),
+        Err(Error { errno }) => Err(from_errno(errno)),
+    }
+}
+
+// Redox has its own `errno` values.
+fn from_errno(errno: i32) -> FutexError {
+    match errno {
+        ENOSYS => FutexError::Unsupported,
+        EFAULT | EINVAL => FutexError::Fault,
+        e => FutexError::Os(e),
+    }
+}
 
 fn convert_timeout(timeout: Option<Duration>) -> Option<TimeSpec> {
     match timeout {
         Some(duration) => {
-            if duration.as_secs() > i64::max_value() as u64 {
+            if duration.as_secs() > i64::MAX as u64 {
                 return None;
             }
             Some(TimeSpec {
@@ -0,0 +1,126 @@
+//! A futex that takes care of spurious wakeups.
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::clock::Deadline;
+use crate::futex::{Futex, FutexError, WakeupReason};
+
+/// An `AtomicU32` used as futex, that rechecks the value after every wakeup.
+///
+/// [`Futex::wait`] returns on spurious wakeups, and the caller has to compare the value again.
+/// [`wait_while`] does that loop, and [`wake_one`] and [`wake_all`] wake the threads waiting in
+/// it. The wrapper is `#[repr(transparent)]` and its methods are inlined, so it costs nothing over
+/// using the `Futex` trait directly.
+///
+/// ```
+/// use std::sync::atomic::Ordering;
+/// use std::sync::Arc;
+/// use std::thread;
+/// use valet_parking::futex::safe::FutexU32;
+///
+/// let futex = Arc::new(FutexU32::new(0));
+/// let waiter = {
+///     let futex = futex.clone();
+///     thread::spawn(move || futex.wait_while(0, None).unwrap())
+/// };
+/// futex.as_atomic().store(1, Ordering::Release);
+/// futex.wake_all().unwrap();
+/// assert!(waiter.join().unwrap());
+/// ```
+///
+/// [`Futex::wait`]: ../trait.Futex.html#method.wait
+/// [`wait_while`]: #method.wait_while
+/// [`wake_one`]: #method.wake_one
+/// [`wake_all`]: #method.wake_all
+#[repr(transparent)]
+#[derive(Default)]
+pub struct FutexU32(AtomicU32);
+
+impl FutexU32 {
+    /// Create a new futex with the initial value `value`.
+    pub const fn new(value: u32) -> FutexU32 {
+        FutexU32(AtomicU32::new(value))
+    }
+
+    /// The underlying atomic, to load and store its value.
+    #[inline]
+    pub fn as_atomic(&self) -> &AtomicU32 {
+        &self.0
+    }
+
+    /// Consume the futex, returning the value it contains.
+    #[inline]
+    pub fn into_inner(self) -> u32 {
+        self.0.into_inner()
+    }
+
+    /// Park the current thread while the value equals `expected`, or until `timeout` elapsed.
+    ///
+    /// Returns `Ok(true)` once the value is different from `expected`, and `Ok(false)` if the
+    /// timeout expired. After a spurious wakeup the value is compared again, and the thread parks
+    /// again if it still matches.
+    ///
+    /// With the `std` feature the thread parks again for the part of `timeout` that is left.
+    /// Without it there is no clock to measure that, so with a timeout this waits at most once,
+    /// and a spurious wakeup also returns `Ok(false)`.
+    ///
+    /// # Atomic ordering
+    /// The value is compared with [`Acquire`] ordering, so writes done before the store that
+    /// changed it are visible once this returns `Ok(true)`.
+    ///
+    /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
+    #[inline]
+    pub fn wait_while(&self, expected: u32, timeout: Option<Duration>) -> Result<bool, FutexError> {
+        let mut deadline = timeout.map(Deadline::after);
+        loop {
+            if self.0.load(Ordering::Acquire) != expected {
+                return Ok(true);
+            }
+            let reason = self
+                .0
+                .wait(expected, deadline.as_mut().map(Deadline::remaining))?;
+            if let Some(ref deadline) = deadline {
+                if reason == WakeupReason::TimedOut || deadline.expired() {
+                    return Ok(self.0.load(Ordering::Acquire) != expected);
+                }
+            }
+        }
+    }
+
+    /// Wake one thread waiting in [`wait_while`]. Returns the number of threads woken, if the
+    /// platform reports it.
+    ///
+    /// Store the new value before waking.
+    ///
+    /// [`wait_while`]: #method.wait_while
+    #[inline]
+    pub fn wake_one(&self) -> Result<usize, FutexError> {
+        self.0.wake_one()
+    }
+
+    /// Wake all threads waiting in [`wait_while`]. Returns the number of threads woken, if the
+    /// platform reports it.
+    ///
+    /// Store the new value before waking.
+    ///
+    /// [`wait_while`]: #method.wait_while
+    #[inline]
+    pub fn wake_all(&self) -> Result<usize, FutexError> {
+        self.0.wake()
+    }
+}
+
+impl From<u32> for FutexU32 {
+    fn from(value: u32) -> FutexU32 {
+        FutexU32::new(value)
+    }
+}
+
+impl fmt::Debug for FutexU32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FutexU32")
+            .field(&self.0.load(Ordering::Relaxed))
+            .finish()
+    }
+}
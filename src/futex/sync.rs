@@ -0,0 +1,73 @@
+//! The atomic operations of the futex-based `Parker` state machine, as a trait.
+//!
+//! `park` and `unpark` in `parking.rs` are generic over `ParkerAtomic`, so they can run on loom's
+//! `AtomicI32` with the `loom` feature, and be model checked. Production code only instantiates
+//! them with `core::sync::atomic::AtomicI32`, so this costs nothing.
+use core::sync::atomic::{AtomicI32, Ordering};
+
+use crate::futex::Futex;
+
+pub(crate) trait ParkerAtomic: Futex<Integer = i32> {
+    fn load(&self, order: Ordering) -> i32;
+    fn swap(&self, val: i32, order: Ordering) -> i32;
+    fn compare_exchange(
+        &self,
+        current: i32,
+        new: i32,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<i32, i32>;
+}
+
+macro_rules! imp_parker_atomic {
+    ($atomic_type:ty) => {
+        impl ParkerAtomic for $atomic_type {
+            #[inline]
+            fn load(&self, order: Ordering) -> i32 {
+                <$atomic_type>::load(self, order)
+            }
+
+            #[inline]
+            fn swap(&self, val: i32, order: Ordering) -> i32 {
+                <$atomic_type>::swap(self, val, order)
+            }
+
+            #[inline]
+            fn compare_exchange(
+                &self,
+                current: i32,
+                new: i32,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<i32, i32> {
+                <$atomic_type>::compare_exchange(self, current, new, success, failure)
+            }
+        }
+    };
+}
+imp_parker_atomic!(AtomicI32);
+#[cfg(feature = "loom")]
+imp_parker_atomic!(loom::sync::atomic::AtomicI32);
+
+// Loom can't block a thread on an address. Waiting yields to the other threads instead, which makes
+// every wait return spuriously, and lets loom explore the interleavings around it.
+#[cfg(feature = "loom")]
+impl Futex for loom::sync::atomic::AtomicI32 {
+    type Integer = i32;
+
+    fn wait(
+        &self,
+        expected: i32,
+        _timeout: Option<core::time::Duration>,
+    ) -> Result<crate::WakeupReason, crate::FutexError> {
+        if self.load(Ordering::Relaxed) != expected {
+            return Ok(crate::WakeupReason::NoMatch);
+        }
+        loom::thread::yield_now();
+        Ok(crate::WakeupReason::Unknown)
+    }
+
+    fn wake(&self) -> Result<usize, crate::FutexError> {
+        Ok(0)
+    }
+}
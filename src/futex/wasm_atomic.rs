@@ -6,9 +6,11 @@
 
 use core::arch::wasm32;
 use core::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_pointer_width = "32")]
+use core::sync::atomic::{AtomicIsize, AtomicUsize};
 use core::time::Duration;
 
-use crate::futex::{Futex, WakeupReason};
+use crate::futex::{Futex, FutexError, WakeupReason};
 use crate::utils::AtomicAsMutPtr;
 
 macro_rules! imp_futex {
@@ -21,7 +23,7 @@ macro_rules! imp_futex {
                 &self,
                 expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut i32;
                 let timeout_ns = convert_timeout(timeout);
                 let r = unsafe { wasm32::i32_atomic_wait(ptr, expected as i32, timeout_ns) };
@@ -37,9 +39,27 @@ macro_rules! imp_futex {
             }
 
             #[inline]
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake(&self) -> Result<usize, FutexError> {
                 let ptr = self.as_mut_ptr() as *mut i32;
-                let r = unsafe { wasm32::atomic_notify(ptr, u32::max_value()) };
+                let r = unsafe { wasm32::atomic_notify(ptr, u32::MAX) };
+                Ok(r as usize)
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                let ptr = self.as_mut_ptr() as *mut i32;
+                let r = unsafe { wasm32::atomic_notify(ptr, 1) };
+                Ok(r as usize)
+            }
+
+            #[inline]
+            fn wake_n(&self, count: usize) -> Result<usize, FutexError> {
+                if count == 0 {
+                    return Ok(0);
+                }
+                let ptr = self.as_mut_ptr() as *mut i32;
+                let wake_count = count.min(u32::MAX as usize) as u32;
+                let r = unsafe { wasm32::atomic_notify(ptr, wake_count) };
                 Ok(r as usize)
             }
         }
@@ -47,11 +67,15 @@ macro_rules! imp_futex {
 }
 imp_futex!(AtomicU32, u32);
 imp_futex!(AtomicI32, i32);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicUsize, usize);
+#[cfg(target_pointer_width = "32")]
+imp_futex!(AtomicIsize, isize);
 
 fn convert_timeout(timeout: Option<Duration>) -> i64 {
     match timeout {
         Some(duration) => {
-            if duration.as_secs() > i64::max_value() as u64 {
+            if duration.as_secs() > i64::MAX as u64 {
                 return -1;
             }
             (duration.as_secs() as i64)
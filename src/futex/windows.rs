@@ -3,15 +3,19 @@ use core::sync::atomic::*;
 use core::time::Duration;
 
 use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
-use winapi::shared::winerror::ERROR_TIMEOUT;
+use winapi::shared::winerror::{ERROR_INVALID_PARAMETER, ERROR_TIMEOUT};
 use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::winbase::INFINITE;
 use winapi::um::winnt::PVOID;
 
-use crate::futex::{Futex, WakeupReason};
+use crate::futex::{Futex, FutexError, WakeupReason};
 use crate::utils::AtomicAsMutPtr;
 use crate::windows::{Backend, BACKEND};
 
+// The `Futex` impls need the `WaitOnAddress` backend of Windows 8 and later. With the NT Keyed
+// Events fallback of older versions, or with the `fallback` feature, every operation returns
+// `FutexError::Unsupported`: keyed events don't compare a value before sleeping, and need a count
+// of the waiting threads that only the `Waiters` and `Parker` implementations keep.
 macro_rules! imp_futex {
     ($atomic_type:ident, $int_type:ident) => {
         impl Futex for $atomic_type {
@@ -21,7 +25,7 @@ macro_rules! imp_futex {
                 &self,
                 mut expected: Self::Integer,
                 timeout: Option<Duration>,
-            ) -> Result<WakeupReason, ()> {
+            ) -> Result<WakeupReason, FutexError> {
                 if let Backend::Wait(f) = BACKEND.get() {
                     let address = self.as_mut_ptr() as PVOID;
                     let compare_address = &mut expected as *mut $int_type as PVOID;
@@ -36,33 +40,42 @@ macro_rules! imp_futex {
                         TRUE => Ok(WakeupReason::Unknown), // Can be any reason except TimedOut
                         FALSE | _ => match unsafe { GetLastError() } {
                             ERROR_TIMEOUT if ms != INFINITE => Ok(WakeupReason::TimedOut),
-                            e => {
-                                debug_assert!(
-                                    false,
-                                    "Unexpected error of WaitOnAddress call: {}",
-                                    e
-                                );
-                                Ok(WakeupReason::Unknown)
-                            }
+                            // `WaitOnAddress` supports all our sizes, so this means the address is
+                            // invalid.
+                            ERROR_INVALID_PARAMETER => Err(FutexError::Fault),
+                            e => Err(FutexError::Os(e as i32)),
                         },
                     }
                 } else {
-                    unreachable!();
+                    Err(FutexError::Unsupported)
                 }
             }
 
-            fn wake(&self) -> Result<usize, ()> {
+            fn wake(&self) -> Result<usize, FutexError> {
                 if let Backend::Wait(f) = BACKEND.get() {
                     let address = self.as_mut_ptr() as PVOID;
                     (f.WakeByAddressAll)(address);
                     Ok(0) // `WakeByAddressAll` does not return the number of woken threads
                 } else {
-                    unreachable!();
+                    Err(FutexError::Unsupported)
+                }
+            }
+
+            #[inline]
+            fn wake_one(&self) -> Result<usize, FutexError> {
+                if let Backend::Wait(f) = BACKEND.get() {
+                    let address = self.as_mut_ptr() as PVOID;
+                    (f.WakeByAddressSingle)(address);
+                    Ok(0)
+                } else {
+                    Err(FutexError::Unsupported)
                 }
             }
         }
     };
 }
+// `WaitOnAddress` accepts an `AddressSize` of 1, 2, 4 or 8 bytes, which covers all atomic integer
+// types on both 32- and 64-bit Windows.
 imp_futex!(AtomicUsize, usize);
 imp_futex!(AtomicIsize, isize);
 imp_futex!(AtomicU64, u64);
@@ -73,6 +86,8 @@ imp_futex!(AtomicU16, u16);
 imp_futex!(AtomicI16, i16);
 imp_futex!(AtomicU8, u8);
 imp_futex!(AtomicI8, i8);
+// A `bool` is one byte that is always 0 or 1, so `WaitOnAddress` can compare it like a `u8`.
+imp_futex!(AtomicBool, bool);
 
 // Timeout in milliseconds, round nanosecond values up to milliseconds.
 fn convert_timeout_ms(timeout: Option<Duration>) -> DWORD {
@@ -83,7 +98,7 @@ fn convert_timeout_ms(timeout: Option<Duration>) -> DWORD {
             .checked_mul(1000)
             .and_then(|x| x.checked_add((duration.subsec_nanos() as u64 + 999999) / 1000000))
             .map(|ms| {
-                if ms > <DWORD>::max_value() as u64 {
+                if ms > DWORD::MAX as u64 {
                     INFINITE
                 } else {
                     ms as DWORD
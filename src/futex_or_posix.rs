@@ -0,0 +1,120 @@
+//! Picks the futex or the Posix implementation at runtime, on platforms where the futex syscalls
+//! are missing in older OS versions: macOS before 10.12 and NetBSD before 10.
+use core::sync::atomic::AtomicUsize;
+use core::time::Duration;
+
+use crate::clock::Deadline;
+use crate::futex::has_futex;
+use crate::{futex, posix, WakeupReason};
+
+//
+// Implementation of the Waiters trait
+//
+pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
+    if has_futex() {
+        futex::compare_and_wait(atomic, expected)
+    } else {
+        posix::compare_and_wait(atomic, expected)
+    }
+}
+
+pub(crate) fn compare_and_wait_timeout(
+    atomic: &AtomicUsize,
+    expected: usize,
+    deadline: &mut Deadline,
+) -> bool {
+    if has_futex() {
+        futex::compare_and_wait_timeout(atomic, expected, deadline)
+    } else {
+        posix::compare_and_wait_timeout(atomic, expected, deadline)
+    }
+}
+
+pub(crate) unsafe fn store_and_wake(atomic: &AtomicUsize, new: usize) {
+    if has_futex() {
+        futex::store_and_wake(atomic, new)
+    } else {
+        posix::store_and_wake(atomic, new)
+    }
+}
+
+pub(crate) unsafe fn compare_store_and_wake(
+    atomic: &AtomicUsize,
+    expected: usize,
+    new: usize,
+) -> bool {
+    if has_futex() {
+        futex::compare_store_and_wake(atomic, expected, new)
+    } else {
+        posix::compare_store_and_wake(atomic, expected, new)
+    }
+}
+
+pub(crate) unsafe fn handoff_to_one(atomic: &AtomicUsize, new: usize) -> bool {
+    if has_futex() {
+        futex::handoff_to_one(atomic, new)
+    } else {
+        posix::handoff_to_one(atomic, new)
+    }
+}
+
+pub(crate) unsafe fn wake_only(atomic: &AtomicUsize) {
+    if has_futex() {
+        futex::wake_only(atomic)
+    } else {
+        posix::wake_only(atomic)
+    }
+}
+
+pub(crate) fn waiter_count(atomic: &AtomicUsize) -> Option<usize> {
+    if has_futex() {
+        futex::waiter_count(atomic)
+    } else {
+        posix::waiter_count(atomic)
+    }
+}
+
+//
+// Implementation of the Parker trait
+//
+pub(crate) type Parker = AtomicUsize;
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    if has_futex() {
+        futex::park(unsafe { futex::get_i32_ref(atomic) }, timeout)
+    } else {
+        posix::park(atomic, timeout)
+    }
+}
+
+pub(crate) unsafe fn transfer_waiter(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    if has_futex() {
+        futex::transfer_waiter(futex::get_i32_ref(from), futex::get_i32_ref(to))
+    } else {
+        posix::transfer_waiter(from, to)
+    }
+}
+
+pub(crate) fn is_notified(atomic: &AtomicUsize) -> bool {
+    if has_futex() {
+        futex::is_notified(unsafe { futex::get_i32_ref(atomic) })
+    } else {
+        posix::is_notified(atomic)
+    }
+}
+
+pub(crate) fn clear_notification(atomic: &AtomicUsize) -> bool {
+    if has_futex() {
+        futex::clear_notification(unsafe { futex::get_i32_ref(atomic) })
+    } else {
+        posix::clear_notification(atomic)
+    }
+}
+
+pub(crate) unsafe fn unpark(atomic: &AtomicUsize) -> bool {
+    if has_futex() {
+        futex::unpark(futex::get_i32_ref(atomic))
+    } else {
+        posix::unpark(atomic)
+    }
+}
@@ -0,0 +1,87 @@
+use core::sync::atomic::AtomicUsize;
+use core::time::Duration;
+
+use crate::sem_parker::{self, Semaphore};
+use crate::waiter_queue;
+use crate::WakeupReason;
+
+pub(crate) use sem_parker::{clear_notification, is_notified};
+pub(crate) use waiter_queue::{
+    compare_and_wait, compare_and_wait_timeout, compare_store_and_wake, handoff_to_one,
+    store_and_wake, waiter_count, wake_only,
+};
+
+// Haiku has no futex, but every thread can cheaply create a kernel semaphore. The parked thread
+// creates one for every park, see `sem_parker` for how it is shared with the thread that unparks
+// us.
+struct KernelSem(libc::sem_id);
+
+impl Semaphore for KernelSem {
+    fn create() -> KernelSem {
+        let sem = unsafe { libc::create_sem(0, b"valet parker\0".as_ptr() as *const libc::c_char) };
+        assert!(sem >= 0, "failed to create a semaphore to park on");
+        KernelSem(sem)
+    }
+
+    fn take(&self, timeout: Option<Duration>) -> Result<(), WakeupReason> {
+        let r = unsafe {
+            match convert_timeout_us(timeout) {
+                Some(timeout) => {
+                    libc::acquire_sem_etc(self.0, 1, libc::B_RELATIVE_TIMEOUT, timeout)
+                }
+                // Without `B_CAN_INTERRUPT` signals don't interrupt the wait.
+                None => libc::acquire_sem(self.0),
+            }
+        };
+        match r {
+            libc::B_OK => Ok(()),
+            // A timeout of zero reports `B_WOULD_BLOCK`.
+            libc::B_TIMED_OUT | libc::B_WOULD_BLOCK => Err(WakeupReason::TimedOut),
+            _ => {
+                debug_assert!(timeout.is_some(), "acquire_sem failed with error {:#x}", r);
+                Err(WakeupReason::Unknown)
+            }
+        }
+    }
+
+    fn give(&self) {
+        let _r = unsafe { libc::release_sem(self.0) };
+        debug_assert_eq!(_r, libc::B_OK);
+    }
+
+    fn delete(&mut self) {
+        let _r = unsafe { libc::delete_sem(self.0) };
+        debug_assert_eq!(_r, libc::B_OK);
+    }
+
+    fn yield_now() {
+        unsafe { libc::sched_yield() };
+    }
+}
+
+pub(crate) type Parker = AtomicUsize;
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    sem_parker::park::<KernelSem>(atomic, timeout)
+}
+
+pub(crate) unsafe fn unpark(atomic: &AtomicUsize) -> bool {
+    sem_parker::unpark::<KernelSem>(atomic)
+}
+
+pub(crate) unsafe fn transfer_waiter(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    sem_parker::transfer_waiter::<KernelSem>(from, to)
+}
+
+// Timeout in microseconds, rounded up so we never wake up early. Durations too long to count in a
+// `bigtime_t` become an infinite timeout.
+#[allow(clippy::manual_div_ceil)]
+fn convert_timeout_us(timeout: Option<Duration>) -> Option<libc::bigtime_t> {
+    let duration = timeout?;
+    let us = (duration.as_nanos() + 999) / 1000;
+    if us > libc::bigtime_t::MAX as u128 {
+        None
+    } else {
+        Some(us as libc::bigtime_t)
+    }
+}
@@ -0,0 +1,131 @@
+//! A parker that can be woken through a `kqueue`, for integration with `kqueue`-based event loops.
+//! Only available on platforms that support `EVFILT_USER`, with the `std` feature.
+use core::mem;
+use core::ptr;
+use core::time::Duration;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// Identifier of our user event in the kqueue.
+const IDENT: libc::uintptr_t = 0;
+
+/// A parker for one thread, that waits on a user event in a `kqueue`.
+///
+/// Event loops built on `kqueue` want to be woken from the same call that watches their sockets,
+/// instead of from a separate futex. The file descriptor returned by [`as_raw_fd`] is a kqueue,
+/// which can itself be registered for readability in the `kqueue` of the event loop. It becomes
+/// readable when the parker gets unparked. After the event loop got woken this way, it should call
+/// [`reset`] to consume the notification.
+///
+/// Like with [`Parker`], an `unpark` before the thread is parked is not lost: the next `park`
+/// returns immediately. Multiple unparks before a park are coalesced into one.
+///
+/// [`as_raw_fd`]: #method.as_raw_fd
+/// [`reset`]: #method.reset
+/// [`Parker`]: ../struct.Parker.html
+pub struct KqueueParker {
+    kq: RawFd,
+}
+
+impl KqueueParker {
+    /// Create a new parker, with a new `kqueue`.
+    pub fn new() -> io::Result<KqueueParker> {
+        let kq = unsafe { libc::kqueue() };
+        if kq == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let parker = KqueueParker { kq };
+        // `EV_CLEAR` resets the event after it is retrieved.
+        parker.kevent(libc::EV_ADD | libc::EV_CLEAR, 0, None)?;
+        Ok(parker)
+    }
+
+    /// Parks the current thread until another thread calls [`unpark`], or until the timeout
+    /// expires.
+    ///
+    /// If `timeout` is `None` this function will only return after an [`unpark`]. With a timeout
+    /// it may also return spuriously.
+    ///
+    /// [`unpark`]: #method.unpark
+    pub fn park(&self, timeout: Option<Duration>) {
+        let ts = convert_timeout(timeout);
+        loop {
+            match self.wait(ts.as_ref()) {
+                Ok(true) => break,
+                _ if timeout.is_some() => break,
+                _ => {}
+            }
+        }
+    }
+
+    /// Unparks the parked thread, or makes the next `park` return immediately if no thread is
+    /// parked yet. Makes the file descriptor readable.
+    pub fn unpark(&self) {
+        let r = self.kevent(0, libc::NOTE_TRIGGER, None);
+        debug_assert!(r.is_ok(), "Unexpected error of kevent: {:?}", r);
+    }
+
+    /// Consume a pending notification, without blocking. Returns whether there was one.
+    ///
+    /// Should be called by an event loop that got woken because the file descriptor became
+    /// readable.
+    pub fn reset(&self) -> bool {
+        let zero = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        self.wait(Some(&zero)).unwrap_or(false)
+    }
+
+    // Returns `true` if the user event was triggered.
+    fn wait(&self, ts: Option<&libc::timespec>) -> io::Result<bool> {
+        let mut event: libc::kevent = unsafe { mem::zeroed() };
+        let ts_ptr = ts.map(|ts| ts as *const _).unwrap_or(ptr::null());
+        let r = unsafe { libc::kevent(self.kq, ptr::null(), 0, &mut event, 1, ts_ptr) };
+        if r == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(r == 1 && event.filter == libc::EVFILT_USER && event.ident == IDENT)
+    }
+
+    fn kevent(&self, flags: u16, fflags: u32, ts: Option<&libc::timespec>) -> io::Result<()> {
+        let mut event: libc::kevent = unsafe { mem::zeroed() };
+        event.ident = IDENT;
+        event.filter = libc::EVFILT_USER;
+        event.flags = flags;
+        event.fflags = fflags;
+        let ts_ptr = ts.map(|ts| ts as *const _).unwrap_or(ptr::null());
+        let r = unsafe { libc::kevent(self.kq, &event, 1, ptr::null_mut(), 0, ts_ptr) };
+        if r == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for KqueueParker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.kq
+    }
+}
+
+impl Drop for KqueueParker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.kq) };
+    }
+}
+
+fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
+    match timeout {
+        Some(duration) => {
+            if duration.as_secs() > libc::time_t::MAX as u64 {
+                return None;
+            }
+            Some(libc::timespec {
+                tv_sec: duration.as_secs() as libc::time_t,
+                tv_nsec: duration.subsec_nanos() as libc::c_long,
+            })
+        }
+        None => None,
+    }
+}
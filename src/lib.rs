@@ -1,108 +1,229 @@
 //! `valet_boy` provides a cross-platform abstraction over thread parking. The goal is to provide an
 //! abstraction with little overhead, which is `no_std`-compatible and requires little overhead.
-#![cfg_attr(not(any(test, target_vendor = "fortanix")), no_std)]
+#![cfg_attr(
+    not(any(test, feature = "std", feature = "tracing", target_vendor = "fortanix")),
+    no_std
+)]
 #![cfg_attr(
     all(target_arch = "wasm32", target_feature = "atomics"),
     feature(stdsimd)
 )]
 #![cfg_attr(target_vendor = "fortanix", feature(sgx_platform))]
 
+use core::cmp;
+use core::fmt;
+use core::hint;
 use core::mem;
-use core::sync::atomic::AtomicUsize;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use core::time::Duration;
 
-// All platforms that have some futex-like interface
+// All platforms that have some futex-like interface, see `build.rs`.
+#[cfg(valet_futex_api)]
+pub mod futex;
+
+#[cfg(valet_futex_api)]
+#[doc(inline)]
+pub use futex::{Futex, FutexError};
+
+// The backend is selected by `build.rs`, see there for the rules and the `fallback` feature.
+#[cfg(valet_backend = "futex")]
+use futex as imp;
+
+// Picks `WaitOnAddress` or NT Keyed Events at runtime.
+#[cfg(windows)]
+mod windows;
+#[cfg(valet_backend = "windows")]
+use windows as imp;
+
+// Picks the futex or the Posix implementation at runtime, on macOS and NetBSD.
+#[cfg(valet_backend = "futex_or_posix")]
+mod futex_or_posix;
+#[cfg(valet_backend = "futex_or_posix")]
+use futex_or_posix as imp;
+
+// Also used on the macOS and NetBSD versions without futex syscalls.
+#[cfg(any(valet_backend = "posix", valet_backend = "futex_or_posix"))]
+mod posix;
+#[cfg(valet_backend = "posix")]
+use posix as imp;
+
+#[cfg(valet_backend = "solaris")]
+mod solaris;
+#[cfg(valet_backend = "solaris")]
+use solaris as imp;
+
+#[cfg(valet_backend = "haiku")]
+mod haiku;
+#[cfg(valet_backend = "haiku")]
+use haiku as imp;
+
+#[cfg(valet_backend = "vxworks")]
+mod vxworks;
+#[cfg(valet_backend = "vxworks")]
+use vxworks as imp;
+
+// The `Parker` of the implementations that park on a semaphore created for every park.
+#[cfg(any(
+    valet_backend = "haiku",
+    valet_backend = "solaris",
+    valet_backend = "vxworks"
+))]
+mod sem_parker;
+
+// The waiter queue is shared by the implementations that park threads one at a time.
+#[cfg(any(
+    valet_backend = "fortanix",
+    valet_backend = "futex_or_posix",
+    valet_backend = "haiku",
+    valet_backend = "posix",
+    valet_backend = "solaris",
+    valet_backend = "vxworks"
+))]
+mod waiter_queue;
+
+#[cfg(valet_backend = "fortanix")]
+mod fortanix;
+#[cfg(valet_backend = "fortanix")]
+use fortanix as imp;
+
+// Bare-metal targets without an operating system, with the `critical-section` feature.
+#[cfg(valet_backend = "bare_metal")]
+mod bare_metal;
+#[cfg(valet_backend = "bare_metal")]
+use bare_metal as imp;
+
+mod clock;
+mod free_bits;
+mod sanitizer;
+mod sync;
+// Helpers for the futex-like backends.
+#[cfg(valet_futex_api)]
+mod utils;
+
+pub use clock::Clock;
+#[cfg(feature = "std")]
+pub use clock::StdClock;
+pub use free_bits::FreeBits;
+pub use sync::Event;
+
+#[cfg(feature = "stats")]
+pub mod stats;
+
+#[cfg(valet_backend = "futex")]
+mod waiters32;
+#[cfg(valet_backend = "futex")]
+pub use waiters32::{Waiters32, RESERVED_BITS_32, RESERVED_MASK_32};
+
+#[cfg(feature = "std")]
+mod scope;
+#[cfg(feature = "std")]
+pub use scope::{Scope, ScopeGuard};
+
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+mod eventfd;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub use eventfd::EventfdParker;
+
 #[cfg(any(
     target_os = "android",
     target_os = "dragonfly",
     target_os = "freebsd",
-    target_os = "fuchsia",
-    target_os = "linux",
-    target_os = "ios",
-    target_os = "macos",
-    target_os = "openbsd",
-    target_os = "redox",
-    all(target_arch = "wasm32", target_feature = "atomics"),
-    windows
+    target_os = "linux"
 ))]
-pub mod futex;
-
+mod shared;
 #[cfg(any(
     target_os = "android",
     target_os = "dragonfly",
     target_os = "freebsd",
-    target_os = "fuchsia",
-    target_os = "linux",
-    target_os = "ios",
-    target_os = "macos",
-    target_os = "openbsd",
-    target_os = "redox",
-    all(target_arch = "wasm32", target_feature = "atomics"),
-    windows
+    target_os = "linux"
 ))]
-#[doc(inline)]
-pub use futex::{Futex, WakeupReason};
+pub use shared::SharedParker;
 
-// All platforms for which the futex interface is always available.
 #[cfg(all(
+    feature = "std",
     any(
-        target_os = "android",
         target_os = "dragonfly",
         target_os = "freebsd",
-        target_os = "fuchsia",
-        target_os = "linux",
-        target_os = "openbsd",
-        target_os = "redox",
-        all(target_arch = "wasm32", target_feature = "atomics")
-    ),
-    not(feature = "fallback")
+        target_os = "ios",
+        target_os = "macos"
+    )
 ))]
-use futex as imp;
-
-// Windows needs a fallback.
-#[cfg(windows)]
-mod windows;
-#[cfg(windows)]
-use windows as imp;
-
-#[cfg(all(any(target_os = "macos", target_os = "ios"), not(feature = "fallback")))]
-mod darwin;
-
-#[cfg(all(any(target_os = "macos", target_os = "ios"), not(feature = "fallback")))]
-use darwin as imp;
-
-#[cfg(unix)]
-#[allow(unused)]
-mod posix;
-
+mod kqueue;
 #[cfg(all(
-    unix,
+    feature = "std",
     any(
-        not(any(
-            target_os = "android",
-            target_os = "dragonfly",
-            target_os = "freebsd",
-            target_os = "fuchsia",
-            target_os = "linux",
-            target_os = "ios",
-            target_os = "macos",
-            target_os = "openbsd",
-            target_os = "redox"
-        )),
-        feature = "fallback"
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "macos"
     )
 ))]
-use posix as imp;
+pub use kqueue::KqueueParker;
 
-#[allow(unused)]
-mod waiter_queue;
+#[cfg(feature = "tracing")]
+mod trace;
 
-#[cfg(target_vendor = "fortanix")]
-mod fortanix;
-#[cfg(target_vendor = "fortanix")]
-use fortanix as imp;
+#[cfg(feature = "debug-registry")]
+pub mod registry;
 
-mod utils;
+/// Reason the operating system provided for waking up a thread. Because of the limited guarantees
+/// of some platforms, this turns out not to be all that useful except for documentation purposes.
+///
+/// New platforms may add reasons, so the enum is `#[non_exhaustive]`. Most callers only need
+/// [`woken_up`] or [`timed_out`].
+///
+/// [`woken_up`]: #method.woken_up
+/// [`timed_out`]: #method.timed_out
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WakeupReason {
+    /// Thread did not get parked, because the `expected` value did not match.
+    /// Not all operating systems report this case.
+    NoMatch,
+    /// Thread got woken up because its timeout expired.
+    /// Only DragonFly BSD does not report this reliably.
+    TimedOut,
+    /// Thread got woken up because of an interrupt.
+    Interrupt,
+    /// Thread got woken up by a `wake` call.
+    WokenUp,
+    /// Thread may be woken up by a `wake` call, but it may also have been for other reasons.
+    Unknown,
+}
+
+impl WakeupReason {
+    /// Returns `true` if the thread may have been woken up by a `wake` or `unpark` call.
+    ///
+    /// This is `true` for `WokenUp`, and also for `Unknown`: many platforms can't tell a real
+    /// wakeup from a spurious one, and report every wakeup that is not a timeout as `Unknown`. So
+    /// `true` means the caller should check its condition, not that it is guaranteed to hold.
+    pub fn woken_up(&self) -> bool {
+        matches!(self, WakeupReason::WokenUp | WakeupReason::Unknown)
+    }
+
+    /// Returns `true` if the thread woke up because its timeout expired.
+    ///
+    /// DragonFly BSD and the bare-metal implementation report an expired timeout as `Unknown`, so
+    /// there this is never `true`.
+    pub fn timed_out(&self) -> bool {
+        *self == WakeupReason::TimedOut
+    }
+}
+
+/// Why [`Parker::park_until_flag`] returned.
+///
+/// [`Parker::park_until_flag`]: struct.Parker.html#method.park_until_flag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParkResult {
+    /// The stop flag was set.
+    Stopped,
+    /// Another thread called `unpark`.
+    Unparked,
+    /// The timeout expired, or the thread woke up spuriously.
+    TimedOut,
+}
 
 /// Multiple threads can wait on a single [`AtomicUsize`] until one thread wakes them all up at
 /// once.
@@ -117,11 +238,18 @@ mod utils;
 /// - All reserved bits must be zero before the first use of [`compare_and_wait`].
 /// - None of the reserved bits are changed before [`store_and_wake`].
 ///
-/// The constants [`FREE_BITS`], [`RESERVED_BITS`] and [`RESERVED_MASK`] can be helpful.
+/// When debug assertions are enabled, the implementations check the reserved bits where possible
+/// and panic if they detect a violation of this contract.
+///
+/// The [`FreeBits`] helper, the functions [`pack_free_bits`] and [`unpack_free_bits`], and the
+/// constants [`FREE_BITS`], [`RESERVED_BITS`] and [`RESERVED_MASK`] can be helpful.
 ///
 /// [`AtomicUsize`]: https://doc.rust-lang.org/core/sync/atomic/struct.AtomicUsize.html
 /// [`compare_and_wait`]: #tymethod.compare_and_wait
 /// [`store_and_wake`]: #tymethod.store_and_wake
+/// [`FreeBits`]: struct.FreeBits.html
+/// [`pack_free_bits`]: fn.pack_free_bits.html
+/// [`unpack_free_bits`]: fn.unpack_free_bits.html
 /// [`FREE_BITS`]: constant.FREE_BITS.html
 /// [`RESERVED_BITS`]: constant.RESERVED_BITS.html
 /// [`RESERVED_MASK`]: constant.RESERVED_MASK.html
@@ -133,7 +261,7 @@ pub trait Waiters {
     /// condition where one thread may try to park itself, while another thread unparks it
     /// concurrently. It is also used to detect whether a wakeup was spurious, in wich case this
     /// `compare_and_wait` will repark the thread. Only the five non-reserved high order bits will
-    /// be compared.
+    /// be compared. A [`wake_only`] is a real wakeup, even though it leaves the value unchanged.
     ///
     /// # Atomic ordering
     /// `compare_and_wait` is a primitive intended for thread parking, not for data synchronization.
@@ -144,12 +272,99 @@ pub trait Waiters {
     /// [`Acquire`] after the `compare_and_wait`. This can be either a [`load`] on the atomic with
     /// [`Acquire`] ordering, or a [`fence`] with [`Acquire`] ordering.
     ///
+    /// [`wake_only`]: #tymethod.wake_only
     /// [`load`]: https://doc.rust-lang.org/core/sync/atomic/struct.AtomicUsize.html#method.load
     /// [`fence`]: https://doc.rust-lang.org/core/sync/atomic/fn.fence.html
     /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
     /// [`Relaxed`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Relaxed
     fn compare_and_wait(&self, expected: usize);
 
+    /// Make the current thread wait until it receives a wake signal, or until `timeout` elapsed.
+    /// Returns `false` on a timeout.
+    ///
+    /// Behaves the same as [`compare_and_wait`] otherwise. If the value no longer matches
+    /// `expected`, this returns `true` right away.
+    ///
+    /// With the `std` feature the thread is reparked after a spurious wakeup, for the part of
+    /// `timeout` that is left. The timeout for every wait is computed right before going to sleep,
+    /// so time spent retrying the compare-and-swap on a contended atomic counts against it too.
+    /// Without `std` there is no clock to measure that, so a spurious wakeup is reported as a
+    /// timeout and returns `false`. The bare-metal implementation can't measure time at all, and
+    /// ignores `timeout`: it spins for a fixed number of iterations, however long the timeout is.
+    ///
+    /// On platforms that keep the waiting threads in a queue, a thread that times out has to make
+    /// the other threads in the queue enqueue themselves again.
+    ///
+    /// The same atomic ordering guarantees as for [`compare_and_wait`] apply.
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    fn compare_and_wait_timeout(&self, expected: usize, timeout: Duration) -> bool;
+
+    /// Make the current thread wait while the value of `self` is one of `wait_values`.
+    ///
+    /// This is a generalization of [`compare_and_wait`] for state machines with multiple 'busy'
+    /// states. After every wakeup the value is checked again, and the thread is reparked if it is
+    /// still in the set. Only the five non-reserved high order bits will be compared.
+    ///
+    /// The same atomic ordering guarantees as for [`compare_and_wait`] apply.
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    fn compare_and_wait_in_set(&self, wait_values: &[usize]);
+
+    /// Make the current thread wait while the bits of `self` selected by `mask` equal those of
+    /// `expected`.
+    ///
+    /// This is a variant of [`compare_and_wait`] for when the free bits hold more than one field,
+    /// for example a small generation counter next to a flag, and only some of them should be
+    /// compared. Changes to bits outside `mask` are ignored: after every wakeup the masked bits are
+    /// checked again, and the thread is reparked if they still match.
+    ///
+    /// `mask` must only select free bits. Selecting reserved bits is a bug, which is caught by a
+    /// debug assertion.
+    ///
+    /// The same atomic ordering guarantees as for [`compare_and_wait`] apply.
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    fn compare_and_wait_masked(&self, expected: usize, mask: usize);
+
+    /// Wait until the value of `self` has reached or passed `threshold`, for monotonic sequence
+    /// numbers stored in the five non-reserved high order bits.
+    ///
+    /// The comparison is modular within the free bits, so a sequence can wrap around: a value is
+    /// considered to have reached `threshold` if it is at most 15 steps past it. The sequence may
+    /// advance multiple steps between wakeups, but must not run more than 15 steps ahead of a
+    /// waiter's `threshold`, or the waiter will see it as not reached yet.
+    ///
+    /// Like all other methods, `threshold` is positioned in the free bits, for example with
+    /// [`FreeBits::bits`].
+    ///
+    /// # Atomic ordering
+    /// The same as for [`compare_and_wait`].
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    /// [`FreeBits::bits`]: struct.FreeBits.html#method.bits
+    fn wait_until_at_least(&self, threshold: usize);
+
+    /// Wait until `is_available` returns `true` for the value of `self`, and atomically replace it
+    /// with the value returned by `claim`. Returns the value before the claim.
+    ///
+    /// This is the 'wake, then claim, retry on loss' pattern needed by primitives like counting
+    /// semaphores. If another thread changes the value between the wakeup and the claim, the
+    /// value is checked again and the thread is reparked if it is no longer available.
+    ///
+    /// Both closures only see the five non-reserved high order bits, and `claim` must return a
+    /// value with all reserved bits zero.
+    ///
+    /// # Atomic ordering
+    /// The claim is done with [`Acquire`] ordering.
+    ///
+    /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
+    fn wait_then_claim(
+        &self,
+        is_available: impl Fn(usize) -> bool,
+        claim: impl Fn(usize) -> usize,
+    ) -> usize;
+
     /// Wake up all waiting threads.
     ///
     /// `new` must be provided to set `self` to some value that is not matched by the `expected`
@@ -157,7 +372,8 @@ pub trait Waiters {
     ///
     /// # Atomic ordering
     /// The atomic store will be done with [`Release`] ordering. Other threads may do an [`Acquire`]
-    /// after waking to see all writes made by this thread.
+    /// after waking to see all writes made by this thread. This also holds when `self` already
+    /// has the value `new`.
     ///
     /// # Safety
     /// If any of the reserved bits where changed while there where threads waiting, this function
@@ -167,31 +383,388 @@ pub trait Waiters {
     /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
     /// [`Release`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Release
     unsafe fn store_and_wake(&self, new: usize);
+
+    /// Wake up all waiting threads and set `self` to `new`, but only if the value of `self` still
+    /// matches `expected`. Returns whether the value was stored.
+    ///
+    /// This supports state machines where the wakeup should be conditional on the state not
+    /// having been moved forward by another thread. Only the five non-reserved high order bits of
+    /// `self` will be compared to `expected`.
+    ///
+    /// # Atomic ordering
+    /// If the value is stored, the atomic store will be done with [`Release`] ordering, the same
+    /// as for [`store_and_wake`]. If the comparison fails, no ordering is guaranteed.
+    ///
+    /// # Safety
+    /// The same as for [`store_and_wake`].
+    ///
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    /// [`Release`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Release
+    unsafe fn compare_store_and_wake(&self, expected: usize, new: usize) -> bool;
+
+    /// Wake up one waiting thread, handing off `new` to it.
+    ///
+    /// Returns whether there was a waiting thread to receive the new value. This is a best-effort
+    /// answer: a waiter may have registered itself but not be waiting anymore.
+    ///
+    /// Other waiting threads remain parked, even though the value no longer matches their
+    /// `expected` value. They are only woken by a later `handoff_to_one` or [`store_and_wake`].
+    ///
+    /// On platforms that can only wake all waiting threads at once this falls back to waking all
+    /// of them. They all check the value after waking, so only threads for which `new` does not
+    /// match their `expected` value will return from [`compare_and_wait`].
+    ///
+    /// # Atomic ordering
+    /// The atomic store will be done with [`Release`] ordering, the same as for
+    /// [`store_and_wake`].
+    ///
+    /// # Safety
+    /// The same as for [`store_and_wake`]. Additionally `handoff_to_one` should not be called
+    /// concurrently with other calls to `handoff_to_one` or `store_and_wake` on the same atomic,
+    /// or while threads may wait on it with [`compare_and_wait_timeout`].
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    /// [`compare_and_wait_timeout`]: #tymethod.compare_and_wait_timeout
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    /// [`Release`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Release
+    unsafe fn handoff_to_one(&self, new: usize) -> bool;
+
+    /// Wake up all waiting threads, without changing the five non-reserved high order bits.
+    ///
+    /// For protocols that keep the wait condition in separate memory, and where the woken threads
+    /// re-check that condition themselves. Only the reserved bits used to track waiting threads are
+    /// cleared. A thread waiting in [`compare_and_wait`] returns after a `wake_only`, even though
+    /// the value still matches its `expected` value.
+    ///
+    /// This works as a pulse: it wakes the threads that are waiting at this moment, and nothing
+    /// else. The wakeup is not recorded in the atomic. The free bits don't change, and the reserved
+    /// bits belong to the implementation, so there is no bit to flip that waiters could compare. A
+    /// thread that calls [`compare_and_wait`] after the `wake_only` sees the same value, and waits
+    /// for the next wakeup. So update the separate condition before the `wake_only`, and have
+    /// waiters check it before they wait. If the waiters must notice every wakeup, change the free
+    /// bits with [`wake_all`] instead.
+    ///
+    /// On the bare-metal implementation a waiting thread that does not look at the atomic between
+    /// two `wake_only`s in a row misses both.
+    ///
+    /// # Atomic ordering
+    /// The reserved bits are cleared with [`Release`] ordering, the same as the store in
+    /// [`store_and_wake`]. Other threads may do an [`Acquire`] after waking to see all writes made
+    /// by this thread, including those to the separate memory.
+    ///
+    /// # Safety
+    /// The same as for [`store_and_wake`].
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    /// [`wake_all`]: #tymethod.wake_all
+    /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
+    /// [`Release`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Release
+    #[doc(alias = "pulse")]
+    unsafe fn wake_only(&self);
+
+    /// Wake up all waiting threads, by advancing a generation counter in the free bits that are
+    /// not in `mask_preserve`.
+    ///
+    /// With [`store_and_wake`] the caller has to pick a `new` value that doesn't match the
+    /// `expected` value of the waiting threads. `wake_all` computes it: the free bits outside
+    /// `mask_preserve` are treated as one counter and incremented (wrapping around), the free bits
+    /// in `mask_preserve` are left unchanged. So `mask_preserve` holds the bits of your own state,
+    /// and the remaining free bits the generation. A generation with `n` bits wraps around after
+    /// `2^n` calls; a thread that sleeps through that many calls before it even started waiting
+    /// would not notice the change.
+    ///
+    /// No reserved bit is used as the generation. The reserved bits belong to the implementation
+    /// (for example a pointer to a queue of waiters) and are never compared against `expected`, so
+    /// flipping one would not wake threads that check the value after a spurious wakeup.
+    ///
+    /// The value is updated with a compare-and-swap loop, so concurrent changes to the preserved
+    /// bits are not lost.
+    ///
+    /// # Panics
+    /// Panics if `mask_preserve` covers all five free bits, leaving no room for a generation.
+    ///
+    /// # Atomic ordering
+    /// The same as for [`store_and_wake`].
+    ///
+    /// # Safety
+    /// The same as for [`store_and_wake`].
+    ///
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    unsafe fn wake_all(&self, mask_preserve: usize);
+
+    /// Prepare the atomic for waiting, without changing its value.
+    ///
+    /// The first wait on a freshly allocated atomic may take a page fault inside the kernel, adding
+    /// latency to the first [`compare_and_wait`]. `warm` does a read-modify-write operation on the
+    /// atomic to fault in its page. On DragonFly BSD this also resynchronizes the physical address
+    /// the kernel uses to identify the atomic.
+    ///
+    /// [`compare_and_wait`]: #tymethod.compare_and_wait
+    fn warm(&self);
+
+    /// The number of threads waiting on `self`, if the implementation keeps track of it.
+    ///
+    /// Returns `None` if the number is not known. The operating system owns the queue of waiting
+    /// threads with futex-like backends, so they only report `Some(0)` when no thread is waiting.
+    /// The same goes for the fallback implementation. Windows versions before Windows 8 and the
+    /// bare-metal implementation count their waiters, and always return a number.
+    ///
+    /// The result is only a hint for adaptive algorithms, for example to choose between
+    /// [`handoff_to_one`] and [`store_and_wake`]. Threads may start or stop waiting right after it
+    /// is read.
+    ///
+    /// [`handoff_to_one`]: #tymethod.handoff_to_one
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    fn waiter_count(&self) -> Option<usize>;
+
+    /// Transition an atomic that was used for other purposes into use with `Waiters`, by clearing
+    /// the reserved bits. The five non-reserved high order bits are left unchanged.
+    ///
+    /// Returns `true` if all reserved bits were already zero. Returns `false` if some reserved bits
+    /// were set, which either means the previous phase left data in them, or that a thread is
+    /// somehow already waiting on `self`. Such a thread would not be woken by later calls to
+    /// [`store_and_wake`], so a `false` result deserves a closer look.
+    ///
+    /// # Atomic ordering
+    /// The bits are cleared with an atomic read-modify-write operation with [`Acquire`] ordering,
+    /// so the values written in the previous phase are visible afterwards.
+    ///
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
+    fn enter_waiting_phase(&self) -> bool;
+
+    /// Create a new atomic with `free` stored in the five non-reserved high order bits, and all
+    /// reserved bits zero.
+    ///
+    /// Useful to initialize many atomics with a nonzero state, without the risk of accidentally
+    /// setting reserved bits.
+    ///
+    /// # Panics
+    /// Panics if `free` does not fit in [`FREE_BITS`] bits.
+    ///
+    /// [`FREE_BITS`]: constant.FREE_BITS.html
+    fn with_free_value(free: usize) -> Self
+    where
+        Self: Sized;
 }
 
 impl Waiters for AtomicUsize {
     fn compare_and_wait(&self, expected: usize) {
-        imp::compare_and_wait(self, expected & !RESERVED_MASK)
+        imp::compare_and_wait(self, expected & !RESERVED_MASK);
+        sanitizer::acquire(self);
+    }
+
+    fn compare_and_wait_timeout(&self, expected: usize, timeout: Duration) -> bool {
+        let expected = expected & !RESERVED_MASK;
+        let mut deadline = clock::Deadline::after(timeout);
+        let mut woken = imp::compare_and_wait_timeout(self, expected, &mut deadline);
+        while !woken && !deadline.expired() {
+            woken = imp::compare_and_wait_timeout(self, expected, &mut deadline);
+        }
+        if woken {
+            sanitizer::acquire(self);
+        }
+        woken
+    }
+
+    fn compare_and_wait_in_set(&self, wait_values: &[usize]) {
+        loop {
+            let current = self.load(Ordering::Relaxed) & !RESERVED_MASK;
+            if !wait_values.iter().any(|v| v & !RESERVED_MASK == current) {
+                break;
+            }
+            self.compare_and_wait(current);
+        }
+    }
+
+    fn compare_and_wait_masked(&self, expected: usize, mask: usize) {
+        debug_assert!(
+            mask & RESERVED_MASK == 0,
+            "the mask passed to `compare_and_wait_masked` must not select any of the reserved bits"
+        );
+        let mask = mask & !RESERVED_MASK;
+        loop {
+            let current = self.load(Ordering::Relaxed) & !RESERVED_MASK;
+            if current & mask != expected & mask {
+                break;
+            }
+            self.compare_and_wait(current);
+        }
+    }
+
+    fn wait_until_at_least(&self, threshold: usize) {
+        let threshold = threshold & !RESERVED_MASK;
+        loop {
+            let current = self.load(Ordering::Relaxed) & !RESERVED_MASK;
+            // The difference wraps around within the free bits, the highest bit acts as the sign.
+            if (current.wrapping_sub(threshold) as isize) >= 0 {
+                break;
+            }
+            self.compare_and_wait(current);
+        }
+    }
+
+    fn wait_then_claim(
+        &self,
+        is_available: impl Fn(usize) -> bool,
+        claim: impl Fn(usize) -> usize,
+    ) -> usize {
+        let mut current = self.load(Ordering::Relaxed);
+        loop {
+            let free = current & !RESERVED_MASK;
+            if !is_available(free) {
+                self.compare_and_wait(free);
+                current = self.load(Ordering::Relaxed);
+                continue;
+            }
+            let new = claim(free);
+            debug_assert!(new & RESERVED_MASK == 0, "{}", NEW_HAS_RESERVED_BITS);
+            // Preserve the reserved bits, other threads may be waiting.
+            let new = new | (current & RESERVED_MASK);
+            match self.compare_exchange_weak(current, new, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return free,
+                Err(x) => current = x,
+            }
+        }
     }
 
     unsafe fn store_and_wake(&self, new: usize) {
+        debug_assert!(new & RESERVED_MASK == 0, "{}", NEW_HAS_RESERVED_BITS);
+        sanitizer::release(self);
         imp::store_and_wake(self, new)
     }
+
+    unsafe fn compare_store_and_wake(&self, expected: usize, new: usize) -> bool {
+        debug_assert!(new & RESERVED_MASK == 0, "{}", NEW_HAS_RESERVED_BITS);
+        sanitizer::release(self);
+        imp::compare_store_and_wake(self, expected & !RESERVED_MASK, new)
+    }
+
+    unsafe fn handoff_to_one(&self, new: usize) -> bool {
+        debug_assert!(new & RESERVED_MASK == 0, "{}", NEW_HAS_RESERVED_BITS);
+        sanitizer::release(self);
+        imp::handoff_to_one(self, new)
+    }
+
+    unsafe fn wake_only(&self) {
+        sanitizer::release(self);
+        imp::wake_only(self)
+    }
+
+    unsafe fn wake_all(&self, mask_preserve: usize) {
+        let preserve = mask_preserve & !RESERVED_MASK;
+        let generation = !RESERVED_MASK & !preserve;
+        assert!(
+            generation != 0,
+            "`wake_all` needs at least one free bit outside `mask_preserve`"
+        );
+        let mut current = self.load(Ordering::Relaxed);
+        loop {
+            // Increment the generation bits, which may not be contiguous. Setting all other bits
+            // to one makes the carry ripple through them.
+            let next = (current | !generation).wrapping_add(1) & generation;
+            let new = next | (current & preserve);
+            if self.compare_store_and_wake(current, new) {
+                break;
+            }
+            current = self.load(Ordering::Relaxed);
+        }
+    }
+
+    fn warm(&self) {
+        self.fetch_or(0, Ordering::Relaxed);
+    }
+
+    fn waiter_count(&self) -> Option<usize> {
+        imp::waiter_count(self)
+    }
+
+    fn enter_waiting_phase(&self) -> bool {
+        self.fetch_and(!RESERVED_MASK, Ordering::Acquire) & RESERVED_MASK == 0
+    }
+
+    fn with_free_value(free: usize) -> AtomicUsize {
+        AtomicUsize::new(FreeBits::new(free).bits())
+    }
 }
 
 /// One thread parkes itself on an `AtomicUsize`, and multiple threads or a timeout are able to wake
 /// it up.
+///
+/// # Layout
+/// `Parker` is `#[repr(C)]`. With the futex-like backends (Linux, Android, the BSDs, Fuchsia,
+/// Redox, WebAssembly, Windows and macOS with `__ulock_wait`) its layout and state encoding are
+/// part of the stable API. Two versions of this crate compiled for the same target can then operate
+/// on the same `Parker` memory, for example one placed in a static shared across a plugin boundary.
+///
+/// The first field holds the parking state. With the futex-like backends it is an `AtomicI32`: 0
+/// for not parked, 1 for parked and 2 for notified. On targets where it is stored in an
+/// `AtomicUsize`, it occupies the low 32 bits.
+///
+/// The fallback implementations store a pointer to a private node of the parked thread in the
+/// first field, and `unpark` follows it. That node differs between versions of this crate, and
+/// between debug and release builds, so there a `Parker` can't be shared between two copies of the
+/// crate.
+///
+/// The second field is an `AtomicUsize` used by [`unpark_awaiting_ack`], with 0 for idle, 1 for
+/// pending and 2 for acknowledged in the free bits. Every `Parker` pays for it, even if
+/// `unpark_awaiting_ack` is never used: it is twice the size of the state alone, or with an
+/// `AtomicI32` state on a 64-bit target 16 bytes instead of 4.
+///
+/// [`unpark_awaiting_ack`]: #method.unpark_awaiting_ack
+#[repr(C)]
 pub struct Parker {
     inner: imp::Parker,
+    // Used with `Waiters` by `unpark_awaiting_ack` to wait for the acknowledgement.
+    ack: AtomicUsize,
 }
 
+// States for `Parker::ack`, in the free bits. Part of the stable layout of `Parker`.
+const ACK_IDLE: usize = 0;
+const ACK_PENDING: usize = FreeBits::new(1).bits();
+const ACK_DONE: usize = FreeBits::new(2).bits();
+
 impl Parker {
+    /// A `Parker` in its initial state, for initializing arrays in a `static`:
+    ///
+    /// ```
+    /// use valet_parking::Parker;
+    ///
+    /// static PARKERS: [Parker; 64] = [Parker::INIT; 64];
+    /// ```
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: Parker = Parker::new();
+
     pub const fn new() -> Parker {
         Parker {
             inner: imp::Parker::new(0),
+            ack: AtomicUsize::new(ACK_IDLE),
         }
     }
 
+    /// A `Parker` that is already notified, as if [`unpark`] was called on it. The first [`park`]
+    /// consumes the notification and returns `WakeupReason::WokenUp` right away.
+    ///
+    /// Useful when the thread that unparks may run before the thread that parks, for example a
+    /// waker that fires before a task first blocks, and the parking thread has to start by checking
+    /// its condition anyway.
+    ///
+    /// This can't be a `const fn`: on macOS the encoding of the notified state depends on whether
+    /// `__ulock_wait` is available, which is only known at runtime.
+    ///
+    /// [`park`]: #method.park
+    /// [`unpark`]: #method.unpark
+    pub fn new_notified() -> Parker {
+        let parker = Parker::new();
+        // No thread can be parked on a parker we just created, this only sets the notified state.
+        #[allow(unused_unsafe)]
+        unsafe {
+            imp::unpark(&parker.inner)
+        };
+        parker
+    }
+
     /// Parks the current thread.
     ///
     /// Only one thread can park on `self`. If `park` is called on an atomic that already has a
@@ -206,7 +779,9 @@ impl Parker {
     ///
     /// Platforms differ in the granularity of timeouts they support, the longest supported timeout,
     /// and what happens if the timeout is zero. This crate enforces some restrictions:
-    /// - `park` panics if the timeout is 0.
+    /// - A timeout of 0 doesn't block: `park` consumes a pending [`unpark`] and returns
+    ///   `WakeupReason::WokenUp`, and otherwise returns `WakeupReason::TimedOut` right away. The
+    ///   timeout is never passed to the operating system, so this is the same on all platforms.
     /// - Timeouts are rounded *up* to the nearest granularity supported by the platform.
     ///   Millisecond resolution is the coarsest of the current implementations.
     /// - The maximum timeout is on all platforms in the order of days or longer, so not really of
@@ -219,41 +794,554 @@ impl Parker {
     /// executed before it. Neither the processor nor the compiler are allowed to reorder them to
     /// happen later.
     ///
+    /// # Stack relocation
+    /// The fallback implementations on Fortanix SGX, Solaris, Haiku and VxWorks store a pointer to
+    /// the stack of the parked thread in `self`. The stack of the thread must not be moved while it
+    /// is parked, for example by a coroutine implementation that copies stacks around. With debug
+    /// assertions enabled this is detected on wakeup, and `park` or [`unpark`] panics.
+    ///
+    /// The pthreads fallback with the `std` feature stores a pointer to a thread-local instead, and
+    /// is not affected. Without `std`, or while the thread-locals of the thread are being
+    /// destroyed, it also uses the stack, and a moved stack can then only be detected by [`unpark`].
+    ///
+    /// # Return value
+    /// Returns why the thread woke up. `WakeupReason::WokenUp` means the thread consumed an
+    /// [`unpark`], also when it was pending before `park` was called. With a timeout the thread may
+    /// also return with `WakeupReason::TimedOut`, `WakeupReason::Interrupt`, or
+    /// `WakeupReason::Unknown` for a spurious wakeup.
+    ///
+    /// Not every platform reports the expiration of a timeout: DragonFly BSD and the bare-metal
+    /// implementation return `WakeupReason::Unknown` instead of `WakeupReason::TimedOut`. So only
+    /// `WokenUp` can be relied on to mean there was an `unpark`.
+    ///
     /// [`unpark`]: #method.unpark
-    pub fn park(&self, timeout: Option<Duration>) {
-        imp::park(&self.inner, timeout)
+    pub fn park(&self, timeout: Option<Duration>) -> WakeupReason {
+        self.park_reason(|| true, timeout)
     }
 
-    /// Unparks the waiting thread, if there is one.
-
+    /// Parks the current thread if `condition` returns `true`. Returns whether `condition` held.
     ///
-    /// # Atomic ordering
-    /// `unpark` will perform an atomic store with `Release` ordering. This guarantees that any
-    /// preparations done before unparking the thread will actually be executed before the `unpark`.
-    /// Neither the processor nor the compiler are not allowed to reorder them to happen later.
-    #[allow(unused_unsafe)]
-    pub fn unpark(&self) {
-        unsafe { imp::unpark(&self.inner) }
+    /// Behaves the same as [`park`], but checks `condition` as the last step before going to sleep.
+    /// With the `debug-registry` feature the thread is registered as parked before `condition` is
+    /// checked, which makes it possible for [`wake_all_parkers`] to reliably wake threads that wait
+    /// for a shutdown flag.
+    ///
+    /// [`park`]: #method.park
+    /// [`wake_all_parkers`]: registry/fn.wake_all_parkers.html
+    pub fn park_if<F>(&self, condition: F, timeout: Option<Duration>) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        self.park_reason(condition, timeout) != WakeupReason::NoMatch
     }
-}
-
-/// Number of high-order bits which are not reserved while using the
-/// [`Waiters`](trait.Waiters.html) trait.
-pub const FREE_BITS: usize = 5;
-/// Number of low-order bits which are reserved while using the [`Waiters`](trait.Waiters.html)
-/// trait.
-pub const RESERVED_BITS: usize = mem::size_of::<usize>() * 8 - FREE_BITS;
-/// Mask matching the bits which are reserved while using the [`Waiters`](trait.Waiters.html) trait.
-pub const RESERVED_MASK: usize = (1 << RESERVED_BITS) - 1;
-
-#[cfg(test)]
-mod test {
-    use crate::Parker;
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::thread::spawn;
-    use std::time::Duration;
 
-    const TEST_ROUNDS: usize = 20_000;
+    /// Parks the current thread, after spinning for a while in case the [`unpark`] follows
+    /// shortly.
+    ///
+    /// Checks up to `spins` times whether `self` got unparked, with an exponential backoff between
+    /// the checks (doubling up to 64 spin loop hints). If it is already unparked at the first
+    /// check this doesn't spin at all. Otherwise behaves the same as [`park`], which uses the
+    /// number of spins configured with [`set_default_park_spins`].
+    ///
+    /// With the `std` feature the time spent spinning is subtracted from `timeout`. Without it the
+    /// spinning is bounded, but not counted towards the timeout.
+    ///
+    /// [`park`]: #method.park
+    /// [`unpark`]: #method.unpark
+    /// [`set_default_park_spins`]: fn.set_default_park_spins.html
+    pub fn park_with_spin(&self, spins: u32, timeout: Option<Duration>) -> WakeupReason {
+        self.park_spinning(spins, || true, timeout)
+    }
+
+    /// Parks the current thread until it is unparked, or until `clock` reaches `deadline`.
+    ///
+    /// Returns `WakeupReason::WokenUp` if the thread consumed an [`unpark`], and
+    /// `WakeupReason::TimedOut` once the deadline passed. Unlike [`park`] with a timeout this never
+    /// returns spuriously: after a spurious wakeup, or after a timeout that expired early according
+    /// to `clock`, the thread parks again for the remaining time. This also makes up for platforms
+    /// that can't measure a timeout themselves, like the bare-metal implementation.
+    ///
+    /// ```
+    /// # #[cfg(feature = "std")] {
+    /// use std::time::{Duration, Instant};
+    /// use valet_parking::{Parker, StdClock, WakeupReason};
+    ///
+    /// let parker = Parker::new();
+    /// let deadline = Instant::now() + Duration::from_millis(10);
+    /// assert_eq!(parker.park_until_with(deadline, &StdClock), WakeupReason::TimedOut);
+    /// assert!(Instant::now() >= deadline);
+    /// # }
+    /// ```
+    ///
+    /// [`park`]: #method.park
+    /// [`unpark`]: #method.unpark
+    pub fn park_until_with<C: Clock>(&self, deadline: C::Instant, clock: &C) -> WakeupReason {
+        loop {
+            let now = clock.now();
+            // At the deadline this polls, so a pending `unpark` is still reported.
+            let remaining = if now >= deadline {
+                Duration::from_secs(0)
+            } else {
+                clock.duration_since(deadline, now)
+            };
+            match self.park(Some(remaining)) {
+                WakeupReason::WokenUp => return WakeupReason::WokenUp,
+                _ if remaining == Duration::from_secs(0) => return WakeupReason::TimedOut,
+                _ => {}
+            }
+        }
+    }
+
+    /// Parks the current thread until it is unparked, or until `timeout` elapsed. Returns the part
+    /// of `timeout` that is left, which is zero if it timed out. Requires the `std` feature.
+    ///
+    /// Like [`park_until_with`] this parks again after a spurious wakeup, for the rest of the
+    /// timeout. All time is measured from a `std::time::Instant` taken before the first park, so
+    /// the result does not drift when the thread parks several times. `Instant` is monotonic, so
+    /// changes to the system time have no effect.
+    ///
+    /// A thread that gets unparked right at the end of the timeout may also return zero; use
+    /// [`park_until_with`] if the difference matters.
+    ///
+    /// ```
+    /// # #[cfg(feature = "std")] {
+    /// use std::time::Duration;
+    /// use valet_parking::Parker;
+    ///
+    /// let parker = Parker::new();
+    /// parker.unpark();
+    /// let remaining = parker.park_timeout(Duration::from_secs(10));
+    /// assert!(remaining > Duration::from_secs(1));
+    /// assert_eq!(parker.park_timeout(Duration::from_millis(1)), Duration::from_secs(0));
+    /// # }
+    /// ```
+    ///
+    /// [`park_until_with`]: #method.park_until_with
+    #[cfg(feature = "std")]
+    pub fn park_timeout(&self, timeout: Duration) -> Duration {
+        let start = std::time::Instant::now();
+        let reason = match start.checked_add(timeout) {
+            Some(deadline) => self.park_until_with(deadline, &StdClock),
+            // A deadline too far in the future to represent never passes.
+            None => self.park(None),
+        };
+        match reason {
+            WakeupReason::WokenUp => timeout.saturating_sub(start.elapsed()),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    fn park_reason<F>(&self, condition: F, timeout: Option<Duration>) -> WakeupReason
+    where
+        F: FnOnce() -> bool,
+    {
+        let spins = DEFAULT_PARK_SPINS.load(Ordering::Relaxed);
+        self.park_spinning(spins, condition, timeout)
+    }
+
+    fn park_spinning<F>(&self, spins: u32, condition: F, timeout: Option<Duration>) -> WakeupReason
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut deadline = timeout.map(clock::Deadline::after);
+        // Spin with an exponential backoff between the checks. `imp::park` still does the actual
+        // handshake, it returns right away if we got notified while spinning.
+        let mut backoff = 1;
+        for _ in 0..spins {
+            if imp::is_notified(&self.inner) {
+                break;
+            }
+            if let Some(ref deadline) = deadline {
+                if deadline.expired() {
+                    break;
+                }
+            }
+            for _ in 0..backoff {
+                hint::spin_loop();
+            }
+            backoff = cmp::min(backoff * 2, MAX_SPIN_BACKOFF);
+        }
+        // Once the deadline passed this becomes a poll below, so `condition` is still checked and
+        // a pending `unpark` is still consumed.
+        let timeout = deadline.as_mut().map(clock::Deadline::remaining);
+        // `imp::park` never reports `NoMatch`, so `park_if` can use it to see if we parked.
+        let park = || {
+            if !condition() {
+                WakeupReason::NoMatch
+            } else if timeout == Some(Duration::from_secs(0)) {
+                // Poll. The platforms don't agree on what a zero timeout means, some even treat it
+                // as no timeout.
+                if imp::is_notified(&self.inner) {
+                    // Only the parked thread clears the notification, so this returns right away.
+                    imp::park(&self.inner, None);
+                    WakeupReason::WokenUp
+                } else {
+                    WakeupReason::TimedOut
+                }
+            } else {
+                imp::park(&self.inner, timeout)
+            }
+        };
+        #[cfg(feature = "debug-registry")]
+        let park = || registry::park(self, park);
+        #[cfg(feature = "tracing")]
+        let park = || trace::park(timeout, park);
+        #[cfg(all(feature = "stats", feature = "std", target_has_atomic = "64"))]
+        let park = || stats::park(park);
+        let reason = park();
+        sanitizer::acquire(&self.inner);
+        reason
+    }
+
+    /// Parks the current thread until it is unparked, the timeout expires, or `stop` is set.
+    ///
+    /// To cancel the park, set `stop` to `true` and then call [`unpark`]. `stop` is checked as the
+    /// last step before going to sleep, and again after waking up, so there is no window in which
+    /// the cancellation can get lost. If `stop` is set, `ParkResult::Stopped` is returned even if
+    /// the thread was also unparked or timed out.
+    ///
+    /// If the thread did not go to sleep because `stop` was already set, the `unpark` that comes
+    /// with it stays pending, and the next park on `self` returns immediately.
+    ///
+    /// # Atomic ordering
+    /// `stop` is loaded with [`Acquire`] ordering, so everything done before setting it is visible
+    /// when this returns `ParkResult::Stopped`.
+    ///
+    /// [`unpark`]: #method.unpark
+    /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
+    pub fn park_until_flag(&self, stop: &AtomicBool, timeout: Option<Duration>) -> ParkResult {
+        let reason = self.park_reason(|| !stop.load(Ordering::Acquire), timeout);
+        if stop.load(Ordering::Acquire) {
+            ParkResult::Stopped
+        } else if reason == WakeupReason::WokenUp {
+            ParkResult::Unparked
+        } else {
+            ParkResult::TimedOut
+        }
+    }
+
+    /// Parks the current thread, unless there is a pending [`unpark`]. Returns whether it went to
+    /// park.
+    ///
+    /// If `self` is already notified, the notification is consumed and `false` is returned without
+    /// entering the platform wait. Otherwise this behaves the same as [`park`] without a timeout,
+    /// and returns `true`. When an `unpark` arrives just as the thread goes to park, the park can
+    /// return right away even though `true` is returned.
+    ///
+    /// # Atomic ordering
+    /// A pending notification is consumed with a single compare-and-exchange from the notified to
+    /// the not-parked state, with [`Acquire`] ordering. When `try_park` returns `false`, everything
+    /// the unparking thread did before its [`unpark`] is visible. Otherwise the same guarantees as
+    /// for [`park`] apply.
+    ///
+    /// [`park`]: #method.park
+    /// [`unpark`]: #method.unpark
+    /// [`Acquire`]: https://doc.rust-lang.org/core/sync/atomic/enum.Ordering.html#variant.Acquire
+    pub fn try_park(&self) -> bool {
+        if imp::clear_notification(&self.inner) {
+            sanitizer::acquire(&self.inner);
+            return false;
+        }
+        self.park(None);
+        true
+    }
+
+    /// Parks the current thread, after first giving a userspace scheduler the chance to run other
+    /// tasks.
+    ///
+    /// For green-thread runtimes that multiplex tasks on OS threads. Before blocking in the
+    /// kernel, `yield_fn` is called, which the runtime can use to run other green tasks. The
+    /// thread is only parked if it is still not notified after `yield_fn` returns. If it already
+    /// was notified before, `yield_fn` is not called and this returns immediately.
+    ///
+    /// Otherwise behaves the same as [`park`] without a timeout.
+    ///
+    /// [`park`]: #method.park
+    pub fn park_cooperative<F>(&self, mut yield_fn: F)
+    where
+        F: FnMut(),
+    {
+        if !imp::is_notified(&self.inner) {
+            yield_fn();
+        }
+        // Returns right away, without a syscall, if we got notified in the meantime.
+        self.park(None);
+    }
+
+    /// Parks the current thread, and acknowledges the wakeup to a thread blocked in
+    /// [`unpark_awaiting_ack`].
+    ///
+    /// Behaves the same as [`park`]. If this thread consumes an unpark from
+    /// [`unpark_awaiting_ack`], that thread is released after this thread has returned from
+    /// parking. If `park_acknowledging` returns because of a timeout or spuriously, nothing is
+    /// acknowledged: the pending unpark is consumed and acknowledged by the next call instead.
+    ///
+    /// [`park`]: #method.park
+    /// [`unpark_awaiting_ack`]: #method.unpark_awaiting_ack
+    pub fn park_acknowledging(&self, timeout: Option<Duration>) {
+        if let WakeupReason::WokenUp = self.park_reason(|| true, timeout) {
+            if self.ack.load(Ordering::Relaxed) & !RESERVED_MASK == ACK_PENDING {
+                // `ack` is private, and only ever modified through `Waiters`.
+                unsafe { self.ack.store_and_wake(ACK_DONE) };
+            }
+        }
+    }
+
+    /// Unparks the waiting thread, if there is one.
+    ///
+    /// Returns `true` if a thread was parked on `self` and this call issued the wake signal for
+    /// it. Returns `false` if no thread was parked yet, in which case the next park returns right
+    /// away, or if the thread was already being unparked. The bare-metal implementation has no
+    /// wake signal, a parked thread spins until it sees the notification, so there it always
+    /// returns `false`. With the Windows XP keyed events it also returns `false` if the parked
+    /// thread stopped waiting because of its timeout.
+    ///
+    /// # Atomic ordering
+    /// `unpark` will perform an atomic store with `Release` ordering. This guarantees that any
+    /// preparations done before unparking the thread will actually be executed before the `unpark`.
+    /// Neither the processor nor the compiler are not allowed to reorder them to happen later.
+    #[allow(unused_unsafe)]
+    pub fn unpark(&self) -> bool {
+        sanitizer::release(&self.inner);
+        unsafe { imp::unpark(&self.inner) }
+    }
+
+    /// Unparks the waiting thread, and blocks until it has acknowledged the wakeup with
+    /// [`park_acknowledging`].
+    ///
+    /// This turns the one-way `unpark` into a rendezvous: when `unpark_awaiting_ack` returns, the
+    /// parked thread is no longer parked and has observed the notification. This is useful when,
+    /// for example, the unparking thread wants to free state that is shared with the parked thread.
+    ///
+    /// Only one thread at a time may call `unpark_awaiting_ack` on a parker, and it should not be
+    /// mixed with plain [`unpark`] calls: two unparks can be consumed by a single park, after which
+    /// the acknowledgement for one of them may never be sent.
+    ///
+    /// # Deadlocks
+    /// `unpark_awaiting_ack` blocks until a thread calls [`park_acknowledging`] on `self` and gets
+    /// woken up by this unpark. If no thread will ever park on `self` again, or it only uses
+    /// [`park`], this function never returns.
+    ///
+    /// # Atomic ordering
+    /// The same as for [`unpark`]. In addition, everything the parked thread did before
+    /// acknowledging happens-before `unpark_awaiting_ack` returns.
+    ///
+    /// [`park`]: #method.park
+    /// [`park_acknowledging`]: #method.park_acknowledging
+    /// [`unpark`]: #method.unpark
+    pub fn unpark_awaiting_ack(&self) {
+        // `ack` is private, and only ever modified through `Waiters`.
+        unsafe { self.ack.store_and_wake(ACK_PENDING) };
+        self.unpark();
+        self.ack.compare_and_wait(ACK_PENDING);
+        let _ = self.ack.load(Ordering::Acquire);
+    }
+
+    /// Transfer the thread parked on `self` to `other`, without waking it up.
+    ///
+    /// Afterwards [`unpark`] on `self` no longer wakes the thread, but `unpark` on `other` does.
+    /// If `other` was already unparked before the transfer, the thread is woken up right away, as
+    /// if it had parked on `other` itself. Returns `true` if a thread was transferred.
+    ///
+    /// Only supported on platforms where the parked thread is identified by a pointer stored in
+    /// the parker: the fallback implementation with pthreads, Solaris and illumos, Haiku, VxWorks
+    /// and Fortanix SGX. With futex-like backends the wakeup is keyed on the address of the
+    /// parker, and `transfer_waiter_to` always returns `false`.
+    /// A thread parked on `self` then remains parked on `self`.
+    ///
+    /// # Panics
+    /// Panics if `other` already has a thread parked on it.
+    ///
+    /// [`unpark`]: #method.unpark
+    #[allow(unused_unsafe)]
+    pub fn transfer_waiter_to(&self, other: &Parker) -> bool {
+        unsafe { imp::transfer_waiter(&self.inner, &other.inner) }
+    }
+
+    /// Reset the parker to the state of [`new`], clearing a pending notification.
+    ///
+    /// For designs that keep a fixed set of parkers and hand them out to one task after another:
+    /// an [`unpark`] meant for the previous task should not make the first `park` of the next one
+    /// return right away. Returns `true` if a notification was cleared.
+    ///
+    /// Only the notified state is replaced, with a compare-and-exchange. A parker with a thread
+    /// parked on it is left alone.
+    ///
+    /// # Safety
+    /// No thread may be parked on `self`, and no other thread may call [`unpark`],
+    /// [`unpark_awaiting_ack`] or [`transfer_waiter_to`] on it concurrently, for example because
+    /// the parker sits in a pool.
+    ///
+    /// The Posix, Solaris, VxWorks, Haiku and Fortanix SGX implementations keep a node on the stack
+    /// of the parked thread. Their notified state is also the state while an `unpark` is still
+    /// using that node. Clearing it then lets a parked thread that timed out return and free the
+    /// node, while the `unpark` writes to it.
+    ///
+    /// [`new`]: #method.new
+    /// [`unpark`]: #method.unpark
+    /// [`unpark_awaiting_ack`]: #method.unpark_awaiting_ack
+    /// [`transfer_waiter_to`]: #method.transfer_waiter_to
+    pub unsafe fn reset(&self) -> bool {
+        // `ack` is only `ACK_DONE` after the last rendezvous completed, and then has no waiters.
+        let _ = self
+            .ack
+            .compare_exchange(ACK_DONE, ACK_IDLE, Ordering::Relaxed, Ordering::Relaxed);
+        imp::clear_notification(&self.inner)
+    }
+
+    /// Prepare the parker for parking, without changing its state.
+    ///
+    /// Does a read-modify-write operation on the atomic to fault in its page, so the first `park`
+    /// does not take a page fault inside the kernel. On DragonFly BSD this also resynchronizes the
+    /// physical address the kernel uses to identify the atomic.
+    pub fn warm(&self) {
+        self.inner.fetch_or(0, Ordering::Relaxed);
+    }
+
+    /// Returns a handle that can only unpark the thread parked on `self`.
+    ///
+    /// The handle is a cheap `Copy` type, that can be handed to code that should be able to wake
+    /// the thread, such as a channel waker or a timer subsystem, without giving it the ability to
+    /// park. Similar to how `std::thread::Thread` separates unparking from the parked thread.
+    pub fn handle(&self) -> UnparkHandle<'_> {
+        UnparkHandle { parker: self }
+    }
+}
+
+/// A handle to unpark the thread parked on a [`Parker`], returned by [`Parker::handle`].
+///
+/// The handle borrows the parker, so a handle that has to live on its own, for example in a timer
+/// subsystem, needs a parker in a `static` (an `UnparkHandle<'static>`).
+///
+/// [`Parker`]: struct.Parker.html
+/// [`Parker::handle`]: struct.Parker.html#method.handle
+#[derive(Clone, Copy)]
+pub struct UnparkHandle<'a> {
+    parker: &'a Parker,
+}
+
+impl UnparkHandle<'_> {
+    /// Unparks the thread parked on the parker, if there is one. The same as [`Parker::unpark`].
+    ///
+    /// [`Parker::unpark`]: struct.Parker.html#method.unpark
+    pub fn unpark(&self) -> bool {
+        self.parker.unpark()
+    }
+
+    /// Returns `true` if both handles unpark the same parker.
+    pub fn will_unpark(&self, other: &UnparkHandle<'_>) -> bool {
+        ptr::eq(self.parker, other.parker)
+    }
+}
+
+impl fmt::Debug for UnparkHandle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnparkHandle")
+            .field("parker", &(self.parker as *const Parker))
+            .finish()
+    }
+}
+
+static DEFAULT_PARK_SPINS: AtomicU32 = AtomicU32::new(0);
+
+// Maximum number of spin loop hints between two checks in `Parker::park_with_spin`.
+const MAX_SPIN_BACKOFF: u32 = 64;
+
+/// Set the number of times [`Parker::park`] checks whether it got unparked, before putting the
+/// thread to sleep.
+///
+/// Spinning can avoid the cost of a syscall when the `unpark` is expected to follow shortly, but
+/// wastes CPU time that other threads could use otherwise. The best value depends on the
+/// workload and the number of cores, so this is a process-global setting the application can
+/// tune. The default is 0, no spinning. See [`Parker::park_with_spin`] for the details, and to
+/// choose the number of spins per call.
+///
+/// [`Parker::park`]: struct.Parker.html#method.park
+/// [`Parker::park_with_spin`]: struct.Parker.html#method.park_with_spin
+pub fn set_default_park_spins(spins: u32) {
+    DEFAULT_PARK_SPINS.store(spins, Ordering::Relaxed);
+}
+
+impl Default for Parker {
+    fn default() -> Parker {
+        Parker::new()
+    }
+}
+
+/// Number of high-order bits which are not reserved while using the
+/// [`Waiters`](trait.Waiters.html) trait.
+pub const FREE_BITS: usize = 5;
+/// Number of low-order bits which are reserved while using the [`Waiters`](trait.Waiters.html)
+/// trait.
+pub const RESERVED_BITS: usize = mem::size_of::<usize>() * 8 - FREE_BITS;
+/// Mask matching the bits which are reserved while using the [`Waiters`](trait.Waiters.html) trait.
+pub const RESERVED_MASK: usize = (1 << RESERVED_BITS) - 1;
+
+/// Shift `value` into the five free high-order bits, leaving all reserved bits zero.
+///
+/// The result can be passed as the `expected` or `new` value to the methods of
+/// [`Waiters`](trait.Waiters.html). The same as `FreeBits::new(value).bits()`, for code that
+/// doesn't want the [`FreeBits`](struct.FreeBits.html) type.
+///
+/// # Panics
+/// Panics if `value` does not fit in [`FREE_BITS`](constant.FREE_BITS.html) bits. In a `const`
+/// this is a compile-time error.
+///
+/// ```
+/// use valet_parking::{pack_free_bits, unpack_free_bits, RESERVED_BITS};
+///
+/// const RUNNING: usize = pack_free_bits(1);
+/// assert_eq!(RUNNING, 1 << RESERVED_BITS);
+/// assert_eq!(unpack_free_bits(RUNNING | 0x3), 1);
+/// ```
+pub const fn pack_free_bits(value: usize) -> usize {
+    FreeBits::new(value).bits()
+}
+
+/// Extract the value stored in the five free high-order bits of `atomic_value`, ignoring the
+/// reserved bits. The inverse of [`pack_free_bits`](fn.pack_free_bits.html).
+pub const fn unpack_free_bits(atomic_value: usize) -> usize {
+    atomic_value >> RESERVED_BITS
+}
+
+/// The maximum number of threads that can wait on a single atomic with the
+/// [`Waiters`](trait.Waiters.html) trait at the same time.
+///
+/// This is only limited on Windows versions before Windows 8, where the number of waiting threads
+/// is counted in the reserved bits (2^27 - 1 on 32-bit, 2^59 - 1 on 64-bit). On all other
+/// platforms it returns `usize::MAX`.
+pub fn max_supported_waiters() -> usize {
+    #[cfg(windows)]
+    {
+        windows::max_supported_waiters()
+    }
+    #[cfg(not(windows))]
+    {
+        usize::MAX
+    }
+}
+
+// With debug assertions enabled the implementations check the reserved bits where they can, to turn
+// a violation of the `Waiters` safety contract into a panic instead of undefined behavior.
+#[allow(unused)]
+pub(crate) const RESERVED_BITS_MODIFIED: &str =
+    "the reserved bits of an atomic used with `Waiters` were modified while threads may be waiting";
+// The fallback implementations store a pointer to the stack of the parked thread in the parker.
+#[allow(unused)]
+pub(crate) const PARKER_MOVED: &str =
+    "the stack of a parked thread was moved or freed while it was parked";
+const NEW_HAS_RESERVED_BITS: &str =
+    "the new value passed to `Waiters` must not have any of the reserved bits set";
+
+#[cfg(test)]
+mod test {
+    use crate::{Parker, Waiters, RESERVED_BITS, RESERVED_MASK};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread::spawn;
+    use std::time::Duration;
+
+    // Miri is far too slow for the full number of rounds.
+    const TEST_ROUNDS: usize = if cfg!(miri) { 100 } else { 20_000 };
 
     #[test]
     #[cfg(not(target_os = "redox"))] // currently causes a kernel panic
@@ -275,9 +1363,1051 @@ mod test {
         }
     }
 
+    #[test]
+    #[cfg(all(unix, feature = "fallback"))]
+    // `unpark_ordering` under contention for the Posix parker: several threads unpark at once,
+    // while the parked thread is somewhere between publishing its pointer and waiting on the
+    // condvar. A lost wakeup hangs this test.
+    fn unpark_ordering_posix_contended() {
+        const UNPARKERS: usize = 4;
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+        static PARKER: Parker = Parker::new();
+
+        for _ in 0..TEST_ROUNDS / 10 {
+            let unparkers: std::vec::Vec<_> = (0..UNPARKERS)
+                .map(|_| {
+                    spawn(|| {
+                        PARKER.unpark();
+                        DONE.fetch_add(1, Ordering::Relaxed);
+                        PARKER.unpark();
+                    })
+                })
+                .collect();
+            while DONE.load(Ordering::Relaxed) < UNPARKERS {
+                // Without spinning, so the thread really goes through the condvar.
+                PARKER.park_with_spin(0, None);
+            }
+            for unparker in unparkers {
+                unparker.join().unwrap();
+            }
+            DONE.store(0, Ordering::Relaxed);
+            // Leave no notification behind for the next round.
+            PARKER.park(Some(Duration::from_secs(0)));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "sanitize-thread")]
+    // Without the annotations TSan reports a data race on `DATA`, because it doesn't see the
+    // synchronization through the futex.
+    fn tsan_sees_unpark() {
+        use std::cell::UnsafeCell;
+
+        struct Data(UnsafeCell<usize>);
+        unsafe impl Sync for Data {}
+        static DATA: Data = Data(UnsafeCell::new(0));
+        static PARKER: Parker = Parker::new();
+
+        spawn(|| {
+            unsafe { *DATA.0.get() = 42 };
+            PARKER.unpark();
+        });
+        // Without a timeout `park` only returns after the `unpark`.
+        PARKER.park(None);
+        assert_eq!(unsafe { *DATA.0.get() }, 42);
+    }
+
+    // Every unpark is acknowledged before the next one, so none of them get coalesced, and the
+    // parked thread has always consumed the notification when `unpark_awaiting_ack` returns.
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn unpark_awaiting_ack_rendezvous() {
+        static PARKER: Parker = Parker::new();
+        const ROUNDS: usize = 100;
+
+        let thread = spawn(|| {
+            for _ in 0..ROUNDS {
+                PARKER.park_acknowledging(None);
+            }
+        });
+        for _ in 0..ROUNDS {
+            PARKER.unpark_awaiting_ack();
+            assert!(!crate::imp::is_notified(&PARKER.inner));
+        }
+        thread.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        not(feature = "fallback")
+    ))]
+    // Park with the current code, and unpark the way an independent copy of the crate would, using
+    // nothing but the documented layout and state encoding.
+    fn parker_stable_layout() {
+        use crate::Futex;
+        use core::sync::atomic::AtomicI32;
+        use std::thread::yield_now;
+
+        static PARKER: Parker = Parker::new();
+        let state = unsafe { &*(&PARKER as *const Parker as *const AtomicI32) };
+        assert_eq!(state.load(Ordering::Relaxed), 0);
+
+        let thread = spawn(|| PARKER.park(None));
+        while state.load(Ordering::Relaxed) != 1 {
+            yield_now();
+        }
+        assert_eq!(state.swap(2, Ordering::Release), 1);
+        let _ = state.wake();
+        thread.join().unwrap();
+        assert_eq!(state.load(Ordering::Relaxed), 0);
+
+        // A notification stored by the other copy is consumed by `park`.
+        state.store(2, Ordering::Release);
+        PARKER.park(None);
+        assert_eq!(state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Unsupported by Miri
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    // Map the same memory at two addresses, park through one mapping and unpark through the other.
+    // This only wakes the thread if the futex operations are process-shared.
+    fn shared_parker_aliased_mapping() {
+        use crate::SharedParker;
+        use std::thread::yield_now;
+
+        unsafe fn map(fd: libc::c_int) -> usize {
+            let ptr = libc::mmap(
+                core::ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            assert_ne!(ptr, libc::MAP_FAILED);
+            ptr as usize
+        }
+
+        let (a, b) = unsafe {
+            let fd = libc::memfd_create(b"valet_parking\0".as_ptr() as *const libc::c_char, 0);
+            assert!(fd >= 0);
+            assert_eq!(libc::ftruncate(fd, 4096), 0);
+            let mappings = (map(fd), map(fd));
+            libc::close(fd);
+            mappings
+        };
+        assert_ne!(a, b);
+        let thread = spawn(move || unsafe { SharedParker::from_ptr(a as *mut u8) }.park(None));
+        let parker_b = unsafe { SharedParker::from_ptr(b as *mut u8) };
+        let state = unsafe { &*(b as *const core::sync::atomic::AtomicI32) };
+        while state.load(Ordering::Relaxed) != 1 {
+            yield_now();
+        }
+        parker_b.unpark();
+        thread.join().unwrap();
+        unsafe {
+            libc::munmap(a as *mut libc::c_void, 4096);
+            libc::munmap(b as *mut libc::c_void, 4096);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "debug-registry")]
+    fn registry_dumps_parked_threads() {
+        use crate::registry::dump_parked;
+        use std::thread::yield_now;
+
+        static PARKER: Parker = Parker::new();
+        let address = &PARKER as *const Parker as usize;
+        let is_parked = |id| {
+            dump_parked()
+                .iter()
+                .any(|info| info.address == address && info.thread.id() == id)
+        };
+
+        let thread = spawn(|| PARKER.park(None));
+        let id = thread.thread().id();
+        while !is_parked(id) {
+            yield_now();
+        }
+        PARKER.unpark();
+        thread.join().unwrap();
+        assert!(!is_parked(id));
+    }
+
+    #[test]
+    fn park_until_flag() {
+        use crate::ParkResult;
+
+        static STOP: AtomicBool = AtomicBool::new(false);
+        static PARKER: Parker = Parker::new();
+        let timeout = Some(Duration::from_millis(1));
+        assert_eq!(PARKER.park_until_flag(&STOP, timeout), ParkResult::TimedOut);
+        PARKER.unpark();
+        assert_eq!(PARKER.park_until_flag(&STOP, None), ParkResult::Unparked);
+
+        let thread = spawn(|| PARKER.park_until_flag(&STOP, None));
+        STOP.store(true, Ordering::Release);
+        PARKER.unpark();
+        assert_eq!(thread.join().unwrap(), ParkResult::Stopped);
+        // Already stopped, returns without parking.
+        assert_eq!(PARKER.park_until_flag(&STOP, None), ParkResult::Stopped);
+    }
+
+    #[test]
+    fn park_if() {
+        let parker = Parker::new();
+        assert!(!parker.park_if(|| false, None));
+        parker.unpark();
+        assert!(parker.park_if(|| true, None));
+    }
+
+    #[test]
+    #[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+    fn eventfd_parker() {
+        use crate::EventfdParker;
+        use std::sync::Arc;
+
+        let parker = Arc::new(EventfdParker::new().unwrap());
+        // Unpark before park is not lost.
+        parker.unpark();
+        parker.park(None);
+        assert!(!parker.reset());
+
+        let unparker = parker.clone();
+        let thread = spawn(move || unparker.unpark());
+        parker.park(None);
+        thread.join().unwrap();
+
+        parker.park(Some(Duration::from_millis(10)));
+    }
+
     #[test]
     fn unpark_timeout() {
         let parker = Parker::new();
         parker.park(Some(Duration::from_millis(10)));
     }
+
+    #[test]
+    #[cfg(target_vendor = "fortanix")]
+    // `usercalls::wait` takes a timeout, a timed park must neither panic nor leave the stack
+    // pointer of the parked thread in the atomic.
+    fn park_timeout_sgx() {
+        use crate::WakeupReason;
+
+        static PARKER: Parker = Parker::new();
+        assert_eq!(
+            PARKER.park(Some(Duration::from_millis(10))),
+            WakeupReason::TimedOut
+        );
+        assert_eq!(
+            PARKER.park(Some(Duration::from_secs(0))),
+            WakeupReason::TimedOut
+        );
+        assert_eq!(PARKER.inner.load(Ordering::Relaxed) & RESERVED_MASK, 0);
+
+        let unparker = spawn(|| {
+            std::thread::sleep(Duration::from_millis(10));
+            PARKER.unpark();
+        });
+        assert_eq!(
+            PARKER.park(Some(Duration::from_secs(10))),
+            WakeupReason::WokenUp
+        );
+        unparker.join().unwrap();
+    }
+
+    #[test]
+    fn unpark_handle() {
+        use crate::UnparkHandle;
+        static PARKER: Parker = Parker::INIT;
+
+        let handle: UnparkHandle<'static> = PARKER.handle();
+        assert!(handle.will_unpark(&PARKER.handle()));
+        assert!(!handle.will_unpark(&Parker::new().handle()));
+        let other = spawn(move || handle.unpark());
+        PARKER.park(None);
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn unpark_reports_parked_thread() {
+        use std::sync::atomic::AtomicBool;
+        use std::thread::sleep;
+
+        static PARKER: Parker = Parker::new();
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        // Nobody parked, the notification stays pending.
+        assert!(!PARKER.unpark());
+        assert!(!PARKER.unpark());
+        PARKER.park(None);
+
+        let parked = spawn(|| {
+            while !DONE.load(Ordering::Acquire) {
+                PARKER.park_with_spin(0, None);
+            }
+        });
+        // Keep unparking until one of them hits the thread while it is parked.
+        while !PARKER.unpark() {
+            sleep(Duration::from_millis(1));
+        }
+        DONE.store(true, Ordering::Release);
+        PARKER.unpark();
+        parked.join().unwrap();
+    }
+
+    #[test]
+    fn park_zero_timeout() {
+        use crate::WakeupReason;
+
+        let zero = Some(Duration::from_secs(0));
+        let parker = Parker::new();
+        assert_eq!(parker.park(zero), WakeupReason::TimedOut);
+        parker.unpark();
+        assert_eq!(parker.park(zero), WakeupReason::WokenUp);
+        assert_eq!(parker.park(zero), WakeupReason::TimedOut);
+        assert_eq!(parker.park_with_spin(100, zero), WakeupReason::TimedOut);
+        parker.unpark();
+        assert_eq!(parker.park_with_spin(100, zero), WakeupReason::WokenUp);
+        assert!(!parker.park_if(|| false, zero));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    // Parking from the destructor of a thread-local must keep working, also when the state the
+    // backend keeps per thread is already destroyed.
+    fn park_in_thread_local_destructor() {
+        use crate::WakeupReason;
+
+        struct ParkOnDrop;
+        impl Drop for ParkOnDrop {
+            fn drop(&mut self) {
+                let parker = Parker::new();
+                let reason = parker.park_with_spin(0, Some(Duration::from_millis(1)));
+                assert_ne!(reason, WakeupReason::WokenUp);
+            }
+        }
+        std::thread_local! {
+            static PARK_ON_DROP: ParkOnDrop = const { ParkOnDrop };
+        }
+
+        spawn(|| {
+            PARK_ON_DROP.with(|_| {});
+            let _ = Parker::new().park(Some(Duration::from_millis(1)));
+            PARK_ON_DROP.with(|_| {});
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn wakeup_reason_helpers() {
+        use crate::WakeupReason;
+
+        assert!(WakeupReason::WokenUp.woken_up());
+        assert!(WakeupReason::Unknown.woken_up());
+        assert!(!WakeupReason::TimedOut.woken_up());
+        assert!(WakeupReason::TimedOut.timed_out());
+        assert!(!WakeupReason::Unknown.timed_out());
+    }
+
+    #[test]
+    fn reset_clears_notification() {
+        use crate::WakeupReason;
+
+        let parker = Parker::new();
+        assert!(!unsafe { parker.reset() });
+        parker.unpark();
+        assert!(unsafe { parker.reset() });
+        assert!(!crate::imp::is_notified(&parker.inner));
+        assert_eq!(
+            parker.park(Some(Duration::from_secs(0))),
+            WakeupReason::TimedOut
+        );
+        parker.unpark();
+        assert_eq!(parker.park(None), WakeupReason::WokenUp);
+    }
+
+    #[test]
+    fn new_notified() {
+        use crate::WakeupReason;
+
+        let parker = Parker::new_notified();
+        assert!(crate::imp::is_notified(&parker.inner));
+        assert_eq!(parker.park(None), WakeupReason::WokenUp);
+        assert_eq!(
+            parker.park(Some(Duration::from_secs(0))),
+            WakeupReason::TimedOut
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn park_timeout_remaining() {
+        use std::thread::sleep;
+        use std::time::Instant;
+
+        let parker = Parker::new();
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(parker.park_timeout(timeout), Duration::from_secs(0));
+        assert!(start.elapsed() >= timeout);
+
+        static PARKER: Parker = Parker::new();
+        let thread = spawn(|| PARKER.park_timeout(Duration::from_secs(60)));
+        sleep(Duration::from_millis(10));
+        PARKER.unpark();
+        let remaining = thread.join().unwrap();
+        assert!(remaining > Duration::from_secs(0) && remaining < Duration::from_secs(60));
+    }
+
+    #[test]
+    // A clock that advances one millisecond on every read. `park_until_with` keeps parking until
+    // the clock reaches the deadline, whatever the platform timeout did.
+    fn park_until_with_clock() {
+        use crate::{Clock, WakeupReason};
+        use std::cell::Cell;
+
+        struct TickClock(Cell<u64>);
+        impl Clock for TickClock {
+            type Instant = u64;
+
+            fn now(&self) -> u64 {
+                let now = self.0.get();
+                self.0.set(now + 1);
+                now
+            }
+
+            fn duration_since(&self, later: u64, earlier: u64) -> Duration {
+                Duration::from_millis(later.saturating_sub(earlier))
+            }
+        }
+
+        let clock = TickClock(Cell::new(0));
+        let parker = Parker::new();
+        assert_eq!(parker.park_until_with(5, &clock), WakeupReason::TimedOut);
+        assert!(clock.0.get() > 5);
+
+        parker.unpark();
+        assert_eq!(parker.park_until_with(100, &clock), WakeupReason::WokenUp);
+        // A pending `unpark` is reported even if the deadline already passed.
+        parker.unpark();
+        assert_eq!(parker.park_until_with(0, &clock), WakeupReason::WokenUp);
+    }
+
+    #[test]
+    fn park_with_spin() {
+        use crate::WakeupReason;
+        #[cfg(feature = "std")]
+        use std::time::Instant;
+        static PARKERS: [Parker; 2] = [Parker::INIT; 2];
+
+        let other = spawn(|| {
+            for _ in 0..1000 {
+                PARKERS[1].park_with_spin(100, None);
+                PARKERS[0].unpark();
+            }
+        });
+        for _ in 0..1000 {
+            PARKERS[1].unpark();
+            assert_eq!(PARKERS[0].park_with_spin(100, None), WakeupReason::WokenUp);
+        }
+        other.join().unwrap();
+
+        // Spinning stops when the timeout expires.
+        #[cfg(feature = "std")]
+        {
+            let parker = Parker::new();
+            let start = Instant::now();
+            let reason = parker.park_with_spin(u32::MAX, Some(Duration::from_millis(50)));
+            assert_eq!(reason, WakeupReason::TimedOut);
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            // `condition` is still checked after the deadline passed while spinning.
+            let mut checked = false;
+            let reason = parker.park_spinning(
+                u32::MAX,
+                || {
+                    checked = true;
+                    false
+                },
+                Some(Duration::from_millis(1)),
+            );
+            assert_eq!(reason, WakeupReason::NoMatch);
+            assert!(checked);
+        }
+    }
+
+    #[test]
+    #[cfg(any(all(unix, feature = "fallback"), target_vendor = "fortanix"))]
+    fn transfer_waiter() {
+        use std::thread::{sleep, yield_now};
+
+        static FROM: Parker = Parker::new();
+        static TO: Parker = Parker::new();
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        let waiter = spawn(|| {
+            FROM.park(None);
+            DONE.store(true, Ordering::Relaxed);
+        });
+        while !FROM.transfer_waiter_to(&TO) {
+            yield_now();
+        }
+        // No longer parked on `FROM`.
+        assert!(!FROM.transfer_waiter_to(&TO));
+        FROM.unpark();
+        sleep(Duration::from_millis(20));
+        assert!(!DONE.load(Ordering::Relaxed));
+        TO.unpark();
+        waiter.join().unwrap();
+        assert!(DONE.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(any(all(unix, feature = "fallback"), target_vendor = "fortanix"))]
+    fn transfer_waiter_with_timeout() {
+        use std::thread::yield_now;
+
+        static FROM: Parker = Parker::new();
+        static TO: Parker = Parker::new();
+
+        // Transfer the waiter while it is busy timing out.
+        for _ in 0..100 {
+            let waiter = spawn(|| FROM.park(Some(Duration::from_micros(100))));
+            while !FROM.transfer_waiter_to(&TO) {
+                if waiter.is_finished() {
+                    break;
+                }
+                yield_now();
+            }
+            waiter.join().unwrap();
+        }
+        // With a parker that was already unparked the waiter wakes up right away.
+        TO.unpark();
+        let waiter = spawn(|| FROM.park(None));
+        while !FROM.transfer_waiter_to(&TO) {
+            yield_now();
+        }
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "fallback", debug_assertions))]
+    #[should_panic(expected = "was moved or freed")]
+    // Simulate a parked thread whose stack was moved, by pointing the parker at memory that does
+    // not contain a parker.
+    fn detects_moved_parker() {
+        #[repr(align(64))]
+        #[allow(dead_code)]
+        struct NotAParker([u8; 256]);
+        static NOT_A_PARKER: NotAParker = NotAParker([0; 256]);
+
+        let parker = Parker::new();
+        let ptr = (&NOT_A_PARKER as *const NotAParker as usize) >> crate::FREE_BITS;
+        parker.inner.store(ptr, Ordering::Relaxed);
+        parker.unpark();
+    }
+
+    #[test]
+    fn unpark_huge_timeout() {
+        static PARKER: Parker = Parker::new();
+        let unparker = spawn(|| {
+            std::thread::sleep(Duration::from_millis(10));
+            PARKER.unpark();
+        });
+        PARKER.park(Some(Duration::from_secs(u64::MAX)));
+        unparker.join().unwrap();
+    }
+
+    #[test]
+    fn static_array_init() {
+        static PARKERS: [Parker; 64] = [Parker::INIT; 64];
+        PARKERS[63].unpark();
+        PARKERS[63].park(None);
+        PARKERS[0].park(Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn warm_preserves_value() {
+        let atomic = AtomicUsize::new(3 << RESERVED_BITS);
+        atomic.warm();
+        assert_eq!(atomic.load(Ordering::Relaxed), 3 << RESERVED_BITS);
+
+        let parker = Parker::new();
+        parker.warm();
+        parker.unpark();
+        parker.park(None);
+    }
+
+    #[test]
+    fn pack_free_bits_round_trip() {
+        use crate::{pack_free_bits, unpack_free_bits, FREE_BITS};
+        for value in 0..(1 << FREE_BITS) {
+            let packed = pack_free_bits(value);
+            assert_eq!(packed & RESERVED_MASK, 0);
+            assert_eq!(unpack_free_bits(packed | RESERVED_MASK), value);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in the free bits")]
+    fn pack_free_bits_overflow() {
+        crate::pack_free_bits(1 << crate::FREE_BITS);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn compare_and_wait_in_set() {
+        use crate::pack_free_bits;
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        const INCOMPLETE: usize = pack_free_bits(0);
+        const RUNNING: usize = pack_free_bits(1);
+        const COMPLETE: usize = pack_free_bits(2);
+
+        // Doesn't wait if the value is not in the set.
+        ATOMIC.compare_and_wait_in_set(&[RUNNING, COMPLETE]);
+
+        let waiter = spawn(|| {
+            ATOMIC.compare_and_wait_in_set(&[INCOMPLETE, RUNNING]);
+            assert_eq!(ATOMIC.load(Ordering::Relaxed) & !RESERVED_MASK, COMPLETE);
+        });
+        unsafe {
+            ATOMIC.store_and_wake(RUNNING);
+            ATOMIC.store_and_wake(COMPLETE);
+        }
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn compare_and_wait_masked() {
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        const FLAG: usize = 1 << RESERVED_BITS;
+        const GENERATION: usize = 0b110 << RESERVED_BITS;
+
+        // Doesn't wait if the masked bits differ, whatever the other bits are.
+        ATOMIC.compare_and_wait_masked(FLAG | GENERATION, GENERATION);
+
+        let waiter = spawn(|| {
+            ATOMIC.compare_and_wait_masked(0, GENERATION);
+            assert_eq!(
+                ATOMIC.load(Ordering::Relaxed) & GENERATION,
+                0b010 << RESERVED_BITS
+            );
+        });
+        unsafe {
+            // Only changes a bit outside the mask, the waiter keeps waiting.
+            ATOMIC.store_and_wake(FLAG);
+            ATOMIC.store_and_wake(FLAG | (0b010 << RESERVED_BITS));
+        }
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn compare_and_wait_timeout() {
+        use std::thread::sleep;
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        const WOKEN: usize = 1 << RESERVED_BITS;
+
+        // Doesn't wait if the value doesn't match.
+        assert!(ATOMIC.compare_and_wait_timeout(WOKEN, Duration::from_secs(10)));
+
+        // Times out, without taking the thread that waits without a timeout out of the queue.
+        let waiter = spawn(|| ATOMIC.compare_and_wait(0));
+        sleep(Duration::from_millis(10));
+        assert!(!ATOMIC.compare_and_wait_timeout(0, Duration::from_millis(10)));
+
+        let timed_waiter = spawn(|| ATOMIC.compare_and_wait_timeout(0, Duration::from_secs(10)));
+        sleep(Duration::from_millis(10));
+        unsafe { ATOMIC.store_and_wake(WOKEN) };
+        waiter.join().unwrap();
+        assert!(timed_waiter.join().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved bits")]
+    #[cfg(debug_assertions)]
+    fn compare_and_wait_masked_rejects_reserved_bits() {
+        AtomicUsize::new(1 << RESERVED_BITS).compare_and_wait_masked(0, RESERVED_MASK);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn wait_until_at_least_sequence() {
+        const ROUNDS: usize = 100; // Wraps around the free bits a couple of times.
+        static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+        static ACKNOWLEDGED: AtomicUsize = AtomicUsize::new(0);
+
+        let consumer = spawn(|| {
+            let mut seen = 0usize;
+            while seen < ROUNDS {
+                SEQUENCE.wait_until_at_least((seen + 1) << RESERVED_BITS);
+                let current = SEQUENCE.load(Ordering::Relaxed) & !RESERVED_MASK;
+                // May have advanced multiple steps.
+                seen += current.wrapping_sub(seen << RESERVED_BITS) >> RESERVED_BITS;
+                unsafe { ACKNOWLEDGED.store_and_wake(seen << RESERVED_BITS) };
+            }
+            seen
+        });
+        for i in 1..=ROUNDS {
+            // Don't run more than 8 steps ahead of the consumer.
+            ACKNOWLEDGED.wait_until_at_least(i.wrapping_sub(8) << RESERVED_BITS);
+            unsafe { SEQUENCE.store_and_wake(i << RESERVED_BITS) };
+        }
+        assert_eq!(consumer.join().unwrap(), ROUNDS);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn wait_then_claim_semaphore() {
+        const N_THREADS: usize = 8;
+        const PERMIT: usize = 1 << RESERVED_BITS;
+        static PERMITS: AtomicUsize = AtomicUsize::new(0);
+
+        let threads: Vec<_> = (0..N_THREADS)
+            .map(|_| spawn(|| PERMITS.wait_then_claim(|p| p != 0, |p| p - PERMIT)))
+            .collect();
+        // Release two permits at a time, after all previous ones have been claimed.
+        for _ in 0..N_THREADS / 2 {
+            while PERMITS.load(Ordering::Relaxed) & !RESERVED_MASK != 0 {}
+            unsafe { PERMITS.store_and_wake(2 * PERMIT) };
+        }
+        for thread in threads {
+            assert!(thread.join().unwrap() >= PERMIT);
+        }
+        assert_eq!(PERMITS.load(Ordering::Relaxed) & !RESERVED_MASK, 0);
+    }
+
+    #[test]
+    // Waking without waiters must not block, which is easy to get wrong with Keyed Events.
+    fn store_and_wake_without_waiters() {
+        let atomic = AtomicUsize::new(0);
+        unsafe {
+            atomic.store_and_wake(1 << RESERVED_BITS);
+            atomic.store_and_wake(2 << RESERVED_BITS);
+            assert!(!atomic.handoff_to_one(3 << RESERVED_BITS));
+            assert!(atomic.compare_store_and_wake(3 << RESERVED_BITS, 0));
+        }
+        let parker = Parker::new();
+        parker.unpark();
+        parker.unpark();
+    }
+
+    // A second wake right after the first one, while the waiter may still be leaving, must not
+    // block or wake the waiter up later.
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn double_store_and_wake() {
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        for _ in 0..100 {
+            let waiter = spawn(|| ATOMIC.compare_and_wait(0));
+            unsafe {
+                ATOMIC.store_and_wake(1 << RESERVED_BITS);
+                ATOMIC.store_and_wake(2 << RESERVED_BITS);
+            }
+            waiter.join().unwrap();
+            ATOMIC.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn enter_waiting_phase() {
+        let atomic = AtomicUsize::new(usize::MAX);
+        assert!(!atomic.enter_waiting_phase());
+        assert_eq!(atomic.load(Ordering::Relaxed), !RESERVED_MASK);
+        assert!(atomic.enter_waiting_phase());
+        unsafe { atomic.store_and_wake(0) };
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn waiter_count() {
+        use std::thread::yield_now;
+
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        assert_eq!(ATOMIC.waiter_count(), Some(0));
+        let thread = spawn(|| ATOMIC.compare_and_wait(0));
+        while ATOMIC.load(Ordering::Relaxed) & RESERVED_MASK == 0 {
+            yield_now();
+        }
+        assert_ne!(ATOMIC.waiter_count(), Some(0));
+        unsafe { ATOMIC.store_and_wake(1 << RESERVED_BITS) };
+        thread.join().unwrap();
+        assert_eq!(ATOMIC.waiter_count(), Some(0));
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn wake_only() {
+        use std::thread::yield_now;
+
+        static ATOMIC: AtomicUsize = AtomicUsize::new(3 << RESERVED_BITS);
+        static CONDITION: AtomicBool = AtomicBool::new(false);
+        let thread = spawn(|| {
+            while !CONDITION.load(Ordering::Acquire) {
+                ATOMIC.compare_and_wait(3 << RESERVED_BITS);
+            }
+        });
+        while ATOMIC.load(Ordering::Relaxed) & RESERVED_MASK == 0 {
+            yield_now();
+        }
+        CONDITION.store(true, Ordering::Relaxed);
+        unsafe { ATOMIC.wake_only() };
+        thread.join().unwrap();
+        // The free bits are left alone, and the waiter is no longer tracked.
+        assert_eq!(ATOMIC.load(Ordering::Relaxed), 3 << RESERVED_BITS);
+        assert_eq!(ATOMIC.waiter_count(), Some(0));
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn wake_all() {
+        use std::thread::yield_now;
+
+        // Preserve the highest and lowest free bit, the generation uses the three in between.
+        let preserve = (1 << (usize::BITS - 1)) | (1 << RESERVED_BITS);
+        static ATOMIC: AtomicUsize = AtomicUsize::new(1 << RESERVED_BITS);
+        let thread = spawn(|| ATOMIC.compare_and_wait(1 << RESERVED_BITS));
+        while ATOMIC.load(Ordering::Relaxed) & RESERVED_MASK == 0 {
+            yield_now();
+        }
+        unsafe { ATOMIC.wake_all(preserve) };
+        thread.join().unwrap();
+        assert_eq!(ATOMIC.load(Ordering::Relaxed), 3 << RESERVED_BITS);
+
+        // The generation wraps around within its bits, and leaves the preserved bits alone.
+        let atomic = AtomicUsize::new(!RESERVED_MASK);
+        unsafe { atomic.wake_all(preserve) };
+        assert_eq!(atomic.load(Ordering::Relaxed), preserve);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn event_wakes_all_waiters() {
+        use crate::Event;
+
+        static EVENT: Event = Event::new();
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                spawn(|| {
+                    EVENT.wait_until_set();
+                    assert_eq!(EVENT.user_bits(), 5);
+                    WOKEN.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        assert!(!EVENT.is_set());
+        // Changing the user bits wakes nobody.
+        EVENT.set_user_bits(5);
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 0);
+        EVENT.set_and_notify_all();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 4);
+
+        // Stays set, also when the user bits change.
+        EVENT.set_and_notify_all();
+        EVENT.set_user_bits((1 << Event::USER_BITS) - 1);
+        assert!(EVENT.is_set());
+        EVENT.wait_until_set();
+        assert_eq!(EVENT.user_bits(), (1 << Event::USER_BITS) - 1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn scope_joins_children() {
+        use crate::Scope;
+        use std::sync::Arc;
+
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+        let scope = Arc::new(Scope::new());
+        // More children than the free bits can count.
+        let children: Vec<_> = (0..64)
+            .map(|_| {
+                let guard = scope.clone().enter_owned();
+                spawn(move || {
+                    DONE.fetch_add(1, Ordering::Relaxed);
+                    drop(guard);
+                })
+            })
+            .collect();
+        assert!(scope.join());
+        assert_eq!(DONE.load(Ordering::Relaxed), 64);
+        for child in children {
+            child.join().unwrap();
+        }
+
+        // A panicking child still completes, and `join` reports it.
+        let guard = scope.clone().enter_owned();
+        let child = spawn(move || {
+            let _guard = guard;
+            panic!("child panicked");
+        });
+        assert!(!scope.join());
+        assert!(child.join().is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "stats", feature = "std", target_has_atomic = "64"))]
+    fn stats_park_durations() {
+        const TIMEOUT: Duration = Duration::from_millis(20);
+        let parker = Parker::new();
+        // Other tests park concurrently, so the counters can only be checked for a lower bound.
+        let before = crate::stats::snapshot();
+        // `park` may return spuriously, but a timeout means it slept for at least `TIMEOUT`.
+        while parker.park(Some(TIMEOUT)) != crate::WakeupReason::TimedOut {}
+        let after = crate::stats::snapshot();
+        assert!(after.max_park >= TIMEOUT);
+        assert!(after.total_park - before.total_park >= TIMEOUT);
+        assert!(after.total_park >= after.max_park);
+    }
+
+    #[test]
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        not(feature = "fallback")
+    ))]
+    fn waiters32() {
+        use crate::{Waiters32, RESERVED_BITS_32, RESERVED_MASK_32};
+        use std::sync::atomic::AtomicU32;
+        use std::thread::yield_now;
+
+        static ATOMIC: AtomicU32 = AtomicU32::new(0);
+        // Doesn't wait, the value does not match.
+        ATOMIC.compare_and_wait(1 << RESERVED_BITS_32);
+
+        let waiters: Vec<_> = (0..4)
+            .map(|_| spawn(|| ATOMIC.compare_and_wait(0)))
+            .collect();
+        while ATOMIC.waiter_count() == Some(0) {
+            yield_now();
+        }
+        assert!(!unsafe { ATOMIC.compare_store_and_wake(2 << RESERVED_BITS_32, 0) });
+        unsafe { ATOMIC.store_and_wake(1 << RESERVED_BITS_32) };
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(ATOMIC.load(Ordering::Relaxed) & RESERVED_MASK_32, 0);
+
+        let waiter = spawn(|| ATOMIC.compare_and_wait(1 << RESERVED_BITS_32));
+        while ATOMIC.waiter_count() == Some(0) {
+            yield_now();
+        }
+        assert!(unsafe { ATOMIC.compare_store_and_wake(1 << RESERVED_BITS_32, 0) });
+        waiter.join().unwrap();
+        assert_eq!(ATOMIC.waiter_count(), Some(0));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn park_returns_reason() {
+        use crate::WakeupReason;
+
+        let parker = Parker::new();
+        parker.unpark();
+        assert_eq!(parker.park(None), WakeupReason::WokenUp);
+        assert_eq!(
+            parker.park(Some(Duration::from_millis(10))),
+            WakeupReason::TimedOut
+        );
+    }
+
+    #[test]
+    fn try_park() {
+        static PARKER: Parker = Parker::new();
+        PARKER.unpark();
+        assert!(!PARKER.try_park());
+        // The notification is consumed, and the state is reset to not parked.
+        assert!(!crate::imp::is_notified(&PARKER.inner));
+
+        // Every implementation uses 0 for the not-parked state. Nothing else unparks `PARKER`, so
+        // any other state means the thread is parked.
+        let thread = spawn(|| PARKER.try_park());
+        while PARKER.inner.load(Ordering::Relaxed) == 0 {
+            std::thread::yield_now();
+        }
+        PARKER.unpark();
+        assert!(thread.join().unwrap());
+    }
+
+    #[test]
+    // `park_cooperative` would hang if it parked before calling `yield_fn`.
+    fn park_cooperative() {
+        let parker = Parker::new();
+        let mut yields = 0;
+        parker.park_cooperative(|| {
+            yields += 1;
+            parker.unpark();
+        });
+        assert_eq!(yields, 1);
+
+        // Already notified, there is no need to yield.
+        parker.unpark();
+        parker.park_cooperative(|| panic!("yielded while notified"));
+    }
+
+    #[test]
+    fn with_free_value() {
+        let atomics: Vec<AtomicUsize> = (0..8).map(|_| Waiters::with_free_value(3)).collect();
+        for atomic in atomics.iter() {
+            assert_eq!(atomic.load(Ordering::Relaxed), 3 << RESERVED_BITS);
+            // Doesn't wait, the value does not match.
+            atomic.compare_and_wait(0);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn with_free_value_too_large() {
+        let _: AtomicUsize = Waiters::with_free_value(1 << crate::FREE_BITS);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "reserved bits")]
+    fn store_and_wake_checks_reserved_bits() {
+        let atomic = AtomicUsize::new(0);
+        unsafe { atomic.store_and_wake(1) };
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn compare_store_and_wake() {
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        const RUNNING: usize = 1 << RESERVED_BITS;
+        const COMPLETE: usize = 2 << RESERVED_BITS;
+
+        let waiter = spawn(|| ATOMIC.compare_and_wait(0));
+        unsafe {
+            // The state already moved on, so this should not store.
+            assert!(!ATOMIC.compare_store_and_wake(RUNNING, COMPLETE));
+            assert!(ATOMIC.compare_store_and_wake(0, RUNNING));
+        }
+        waiter.join().unwrap();
+        assert_eq!(ATOMIC.load(Ordering::Relaxed) & !RESERVED_MASK, RUNNING);
+    }
+
+    #[test]
+    #[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+    fn handoff_to_one() {
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        const HANDOFF: usize = 1 << RESERVED_BITS;
+
+        let waiter = spawn(|| ATOMIC.compare_and_wait(0));
+        // Wait until the waiting thread has registered itself in the reserved bits.
+        while ATOMIC.load(Ordering::Relaxed) & RESERVED_MASK == 0 {}
+        assert!(unsafe { ATOMIC.handoff_to_one(HANDOFF) });
+        waiter.join().unwrap();
+        assert_eq!(ATOMIC.load(Ordering::Relaxed) & !RESERVED_MASK, HANDOFF);
+
+        // No thread is waiting anymore.
+        assert!(!unsafe { ATOMIC.handoff_to_one(0) });
+    }
 }
@@ -1,17 +1,59 @@
 use core::cell::UnsafeCell;
+use core::ptr;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::time::Duration;
 
 use crate::waiter_queue;
-use crate::{FREE_BITS, RESERVED_MASK};
+use crate::{WakeupReason, FREE_BITS, PARKER_MOVED, RESERVED_MASK};
 
-pub(crate) use waiter_queue::{compare_and_wait, store_and_wake};
+pub(crate) use waiter_queue::{
+    compare_and_wait, compare_and_wait_timeout, compare_store_and_wake, handoff_to_one,
+    store_and_wake, waiter_count, wake_only,
+};
 
 // `UnsafeCell` because Posix needs mutable references to these types.
 #[repr(align(64))]
 pub struct PosixParker {
     mutex: UnsafeCell<libc::pthread_mutex_t>,
     condvar: UnsafeCell<libc::pthread_cond_t>,
+    // The atomic that currently holds the pointer to this `PosixParker`. Changes when the waiter is
+    // transferred to another parker, only accessed while holding `mutex`.
+    owner: UnsafeCell<*const AtomicUsize>,
+    // Lets us detect a pointer to a `PosixParker` that was moved or freed while parked.
+    #[cfg(debug_assertions)]
+    canary: usize,
+}
+
+#[cfg(debug_assertions)]
+const CANARY: usize = 0x7061_726b;
+
+impl PosixParker {
+    const fn new() -> PosixParker {
+        PosixParker {
+            mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+            condvar: UnsafeCell::new(libc::PTHREAD_COND_INITIALIZER),
+            owner: UnsafeCell::new(ptr::null()),
+            #[cfg(debug_assertions)]
+            canary: CANARY,
+        }
+    }
+
+    #[inline]
+    unsafe fn debug_check_canary(ptr: *const PosixParker) {
+        #[cfg(debug_assertions)]
+        assert_eq!((*ptr).canary, CANARY, "{}", PARKER_MOVED);
+    }
+}
+
+impl Drop for PosixParker {
+    fn drop(&mut self) {
+        unsafe {
+            let r = libc::pthread_mutex_destroy(self.mutex.get());
+            debug_assert_eq!(r, 0);
+            let r = libc::pthread_cond_destroy(self.condvar.get());
+            debug_assert_eq!(r, 0);
+        }
+    }
 }
 
 // # State table (of the reserved bits):
@@ -23,26 +65,47 @@ pub struct PosixParker {
 //          |            | the wakeup must be spurious and it should park itself again.
 // ---------+------------+------------------------------------------------------------------
 //   some   |     1      | Thread is still parked, but some thread is in the process of
-//          |            | waking it up.
+//          |            | waking it up or transferring it to another parker.
 // ---------+------------+------------------------------------------------------------------
 //     0    |     1      | Thread got woken up by another thread.
 // ---------+------------+------------------------------------------------------------------
 const NOTIFY_BIT: usize = 1;
 const PTR_BITS: usize = RESERVED_MASK ^ NOTIFY_BIT;
 
+#[cfg(valet_backend = "posix")]
 pub(crate) type Parker = AtomicUsize;
 
-// Returns false if the wakeup was because of the timeout, or spurious.
-pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) {
-    let parker = PosixParker {
-        mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
-        condvar: UnsafeCell::new(libc::PTHREAD_COND_INITIALIZER),
-    };
-    let ptr = (&parker as *const PosixParker as usize) >> FREE_BITS;
+// Creating and destroying a mutex and condvar on every `park` is not free. With `std` every thread
+// reuses the pair in its own `PosixParker`, which is destroyed on thread exit. Reusing is safe
+// because no other thread can still access it after we return from `park_on`. A signal on a
+// condvar that nobody waits on is lost, so there is no state left over from the previous park.
+#[cfg(feature = "std")]
+std::thread_local! {
+    static THREAD_PARKER: PosixParker = const { PosixParker::new() };
+}
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    #[cfg(feature = "std")]
+    {
+        // Fails while the thread-locals of this thread are being destroyed.
+        if let Ok(reason) = THREAD_PARKER.try_with(|parker| park_on(parker, atomic, timeout)) {
+            return reason;
+        }
+    }
+    let parker = PosixParker::new();
+    park_on(&parker, atomic, timeout)
+}
+
+fn park_on(parker: &PosixParker, atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    let ptr = (parker as *const PosixParker as usize) >> FREE_BITS;
 
     let ts = convert_timeout(timeout);
+    let mut reason = WakeupReason::WokenUp;
 
     unsafe {
+        // No other thread has a pointer to `parker` yet.
+        *parker.owner.get() = atomic;
+
         // Lock the mutex before making a pointer to `parker` available to other threads.
         let r = libc::pthread_mutex_lock(parker.mutex.get());
         debug_assert_eq!(r, 0);
@@ -56,42 +119,57 @@ pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) {
                 break;
             }
 
-            let old = atomic.compare_and_swap(current, current | ptr, Ordering::SeqCst);
-            if old != current {
+            if let Err(old) =
+                atomic.compare_exchange(current, current | ptr, Ordering::SeqCst, Ordering::SeqCst)
+            {
                 current = old;
                 continue;
             }
 
+            // An `unpark` that sees our pointer now has to lock our mutex before it can clear
+            // `PTR_BITS` and signal the condvar. We hold the mutex until `pthread_cond_wait`
+            // atomically releases it, so the signal can't get in before we wait. There is no need
+            // to recheck the state here.
             if let Some(timeout) = ts {
-                condvar_wait_timed(atomic, &parker, &timeout);
+                reason = condvar_wait_timed(parker, &timeout);
             } else {
-                condvar_wait(atomic, &parker);
+                condvar_wait(parker);
             }
             break;
         }
 
-        // Done, clean up.
+        // Done, clean up. We may have been transferred to another parker while waiting.
+        let owner = owner(parker);
         let r = libc::pthread_mutex_unlock(parker.mutex.get());
         debug_assert_eq!(r, 0);
-        let r = libc::pthread_mutex_destroy(parker.mutex.get());
-        debug_assert_eq!(r, 0);
-        let r = libc::pthread_cond_destroy(parker.condvar.get());
-        debug_assert_eq!(r, 0);
-        atomic.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+        owner.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
     }
+    reason
+}
+
+pub(crate) fn is_notified(atomic: &AtomicUsize) -> bool {
+    atomic.load(Ordering::Relaxed) & RESERVED_MASK == NOTIFY_BIT
 }
 
-pub(crate) unsafe fn unpark(atomic: &AtomicUsize) {
+// Only compares the reserved bits of a parker without a pointer in them, so it never removes a
+// published node.
+pub(crate) fn clear_notification(atomic: &AtomicUsize) -> bool {
+    atomic
+        .compare_exchange(NOTIFY_BIT, 0, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+}
+
+pub(crate) unsafe fn unpark(atomic: &AtomicUsize) -> bool {
     let old = atomic.fetch_or(NOTIFY_BIT, Ordering::SeqCst);
     match (old & PTR_BITS, old & NOTIFY_BIT == NOTIFY_BIT) {
         (_, true) => {
             // Some other thread must be in the process of unparking the suspended thread.
             // There is nothing for us to do.
-            return;
+            return false;
         }
         (0, false) => {
             // There is no thread to wake up, maybe it didn't even get to parking itself yet.
-            return;
+            return false;
         }
         (_, false) => {} // Good to go.
     }
@@ -99,6 +177,7 @@ pub(crate) unsafe fn unpark(atomic: &AtomicUsize) {
     // The parked thread will not return from `self.park` while `NOTIFY_BIT` and a pointer is
     // set, so we can safely access data on its stack through the pointer encoded in `self`.
     let ptr = ((old & PTR_BITS) << FREE_BITS) as *const PosixParker;
+    PosixParker::debug_check_canary(ptr);
 
     // Lock a mutex, set the signal, and release the mutex.
     // The parked thread will be woken up after releasing the mutex.
@@ -112,9 +191,80 @@ pub(crate) unsafe fn unpark(atomic: &AtomicUsize) {
     debug_assert_eq!(r, 0);
     let r = libc::pthread_mutex_unlock((*ptr).mutex.get());
     debug_assert_eq!(r, 0);
+    true
 }
 
-fn condvar_wait(atomic: &AtomicUsize, parker: &PosixParker) {
+pub(crate) unsafe fn transfer_waiter(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    // Set the `NOTIFY_BIT`, just like `unpark`. This keeps the parked thread from returning while
+    // we access its `PosixParker`, and keeps other threads from unparking it concurrently.
+    let mut current = from.load(Ordering::SeqCst);
+    loop {
+        if current & PTR_BITS == 0 || current & NOTIFY_BIT == NOTIFY_BIT {
+            // No thread parked, or it is already being unparked.
+            return false;
+        }
+        match from.compare_exchange(
+            current,
+            current | NOTIFY_BIT,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => break,
+            Err(x) => current = x,
+        }
+    }
+    let ptr_bits = current & PTR_BITS;
+    let ptr = (ptr_bits << FREE_BITS) as *const PosixParker;
+    PosixParker::debug_check_canary(ptr);
+
+    let r = libc::pthread_mutex_lock((*ptr).mutex.get());
+    debug_assert_eq!(r, 0);
+    let mut to_current = to.load(Ordering::SeqCst);
+    let moved = loop {
+        let (new, moved) = match to_current & RESERVED_MASK {
+            0 => (to_current | ptr_bits, true),
+            // `to` was unparked before a thread parked on it. Consume the notification and wake
+            // the thread, as if it parked on `to` itself.
+            NOTIFY_BIT => (to_current & !NOTIFY_BIT, false),
+            _ => {
+                // Undo, and let the parked thread recheck the state if it is waiting for us.
+                from.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+                let r = libc::pthread_cond_signal((*ptr).condvar.get());
+                debug_assert_eq!(r, 0);
+                let r = libc::pthread_mutex_unlock((*ptr).mutex.get());
+                debug_assert_eq!(r, 0);
+                panic!(
+                    "Tried to transfer a waiter to a parker that already has a thread parked on it"
+                );
+            }
+        };
+        match to.compare_exchange(to_current, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break moved,
+            Err(x) => to_current = x,
+        }
+    };
+    if moved {
+        *(*ptr).owner.get() = to;
+        from.fetch_and(!RESERVED_MASK, Ordering::SeqCst);
+    } else {
+        from.fetch_and(!PTR_BITS, Ordering::SeqCst);
+    }
+    // If the waiting thread woke up from a timeout it is waiting for this signal, because it saw
+    // the `NOTIFY_BIT`. After a transfer it will check the state of `to`.
+    let r = libc::pthread_cond_signal((*ptr).condvar.get());
+    debug_assert_eq!(r, 0);
+    let r = libc::pthread_mutex_unlock((*ptr).mutex.get());
+    debug_assert_eq!(r, 0);
+    true
+}
+
+// Returns the atomic that currently holds the pointer to `parker`. Must be called while holding
+// the mutex.
+unsafe fn owner(parker: &PosixParker) -> &AtomicUsize {
+    &**parker.owner.get()
+}
+
+fn condvar_wait(parker: &PosixParker) {
     unsafe {
         loop {
             // Wait on a signal through the condvar; mutex gets released
@@ -123,43 +273,52 @@ fn condvar_wait(atomic: &AtomicUsize, parker: &PosixParker) {
             debug_assert_eq!(r, 0);
             // Make sure this wakeup was not spurious, otherwise park again.
             // The pointer must be gone, and the `NOTIFY_BIT` must be set.
-            if atomic.load(Ordering::SeqCst) & RESERVED_MASK == NOTIFY_BIT {
+            if owner(parker).load(Ordering::SeqCst) & RESERVED_MASK == NOTIFY_BIT {
                 break;
             }
         }
     }
 }
 
-fn condvar_wait_timed(atomic: &AtomicUsize, parker: &PosixParker, ts: &libc::timespec) -> bool {
+fn condvar_wait_timed(parker: &PosixParker, ts: &libc::timespec) -> WakeupReason {
     unsafe {
         // Wait on a signal through the condvar; mutex gets released
         let r = libc::pthread_cond_timedwait(parker.condvar.get(), parker.mutex.get(), ts);
         // We got woken up; mutex is locked again.
         debug_assert!(r == 0 || r == libc::ETIMEDOUT);
-        let current = atomic.load(Ordering::SeqCst);
-        if current & NOTIFY_BIT != NOTIFY_BIT {
+        loop {
+            let atomic = owner(parker);
+            let current = atomic.load(Ordering::SeqCst);
+            if current & RESERVED_MASK == NOTIFY_BIT {
+                return WakeupReason::WokenUp;
+            } else if current & NOTIFY_BIT == NOTIFY_BIT {
+                // Some other thread just happened to try waking us or transferring us right now,
+                // while we were already woken up by the timeout. It is now blocked on our mutex.
+                // We have to let it take the mutex and signal us, otherwise it will read through
+                // a dangling pointer when we return.
+                let r = libc::pthread_cond_wait(parker.condvar.get(), parker.mutex.get());
+                debug_assert_eq!(r, 0);
+                continue;
+            }
             // If this wakeup was not caused by another thread waking us, but was spurious or
-            // because the timeout expired.
-            loop {
-                // Try to set the state to not parked (and not notified).
-                let old =
-                    atomic.compare_and_swap(current, current & !RESERVED_MASK, Ordering::SeqCst);
-                if old == current {
-                    return true;
-                } else if old & NOTIFY_BIT == NOTIFY_BIT {
-                    // Some other thread just happened to try waking us right now, while we were
-                    // already woken up by the timeout. It is now blocked on our mutex. We have
-                    // to let it take the mutex and wake us, otherwise it will read through a
-                    // dangling pointer when we return.
-                    let r = libc::pthread_cond_wait(parker.condvar.get(), parker.mutex.get());
-                    debug_assert_eq!(r, 0);
-                    debug_assert_eq!(atomic.load(Ordering::SeqCst) & RESERVED_MASK, NOTIFY_BIT);
-                    return false;
-                }
+            // because the timeout expired, try to set the state to not parked (and not notified).
+            if atomic
+                .compare_exchange(
+                    current,
+                    current & !RESERVED_MASK,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                return if r == libc::ETIMEDOUT {
+                    WakeupReason::TimedOut
+                } else {
+                    WakeupReason::Unknown
+                };
             }
         }
     }
-    true
 }
 
 // x32 Linux uses a non-standard type for tv_nsec in timespec.
@@ -171,17 +330,28 @@ type tv_nsec_t = i64;
 #[allow(non_camel_case_types)]
 type tv_nsec_t = libc::c_long;
 
+// `pthread_cond_timedwait` takes an absolute deadline on the realtime clock. `gettimeofday` is
+// available on all platforms that use this module, including MacOS before 10.12.
+// Returns `None` if the deadline overflows, which we treat as an infinite timeout.
 fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
-    match timeout {
-        Some(duration) => {
-            if duration.as_secs() > libc::time_t::max_value() as u64 {
-                return None;
-            }
-            Some(libc::timespec {
-                tv_sec: duration.as_secs() as libc::time_t,
-                tv_nsec: duration.subsec_nanos() as tv_nsec_t,
-            })
-        }
-        None => None,
+    let duration = timeout?;
+    let mut now = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    let r = unsafe { libc::gettimeofday(&mut now, ptr::null_mut()) };
+    debug_assert_eq!(r, 0);
+    let mut secs = duration.as_secs().checked_add(now.tv_sec as u64)?;
+    let mut nanos = duration.subsec_nanos() + now.tv_usec as u32 * 1000;
+    if nanos >= 1_000_000_000 {
+        secs = secs.checked_add(1)?;
+        nanos -= 1_000_000_000;
+    }
+    if secs > libc::time_t::MAX as u64 {
+        return None;
     }
+    Some(libc::timespec {
+        tv_sec: secs as libc::time_t,
+        tv_nsec: nanos as tv_nsec_t,
+    })
 }
@@ -0,0 +1,159 @@
+//! A registry of parked threads for debugging deadlocks, enabled with the `debug-registry`
+//! feature.
+//!
+//! Every thread that parks on a [`Parker`] records the address of that `Parker` in a node that
+//! belongs to the thread, and clears it again when it returns from `park`. The nodes form a
+//! lock-free linked list that is only ever appended to, so the registry does not itself depend on
+//! thread parking. One node is allocated for every thread that parks, and never freed.
+//!
+//! The registry can also be used to wake every parked thread with [`wake_all_parkers`], for
+//! example to let worker threads observe a shutdown flag.
+//!
+//! [`Parker`]: ../struct.Parker.html
+//! [`wake_all_parkers`]: fn.wake_all_parkers.html
+use core::ptr;
+use core::sync::atomic::{self, AtomicPtr, AtomicUsize, Ordering};
+use std::thread::{self, Thread};
+use std::vec::Vec;
+
+use crate::{Parker, WakeupReason};
+
+/// A thread that is currently parked, as returned by [`dump_parked`](fn.dump_parked.html).
+#[derive(Clone, Debug)]
+pub struct ParkerInfo {
+    /// Address of the `Parker` the thread is parked on.
+    pub address: usize,
+    /// The parked thread.
+    pub thread: Thread,
+}
+
+/// Returns all threads that are currently parked on a [`Parker`], together with the address of
+/// the `Parker`.
+///
+/// Threads that are just about to park, or that are just waking up, may or may not be included.
+///
+/// [`Parker`]: ../struct.Parker.html
+pub fn dump_parked() -> Vec<ParkerInfo> {
+    let mut parked = Vec::new();
+    let mut current = HEAD.load(Ordering::Acquire) as *const Node;
+    while let Some(node) = unsafe { current.as_ref() } {
+        let address = node.parked_on.load(Ordering::Relaxed) & !PINNED;
+        if address != 0 {
+            parked.push(ParkerInfo {
+                address,
+                thread: node.thread.clone(),
+            });
+        }
+        current = node.next;
+    }
+    parked
+}
+
+/// Unpark every thread that is currently parked on a [`Parker`].
+///
+/// Parkers are independent of each other, so there is no other way to wake all of them at once.
+/// This is meant for shutting down: set a shutdown flag, call `wake_all_parkers`, and have the
+/// workers park with [`Parker::park_if`] on the condition that the flag is not set. A thread that
+/// parks concurrently with `wake_all_parkers` then either sees the flag and doesn't park, or is
+/// unparked by the sweep.
+///
+/// Threads that use plain [`Parker::park`] and start parking after the sweep passed them are not
+/// woken up.
+///
+/// [`Parker`]: ../struct.Parker.html
+/// [`Parker::park`]: ../struct.Parker.html#method.park
+/// [`Parker::park_if`]: ../struct.Parker.html#method.park_if
+pub fn wake_all_parkers() {
+    // Pairs with the fence in `park`: either we see the registration, or the parking thread sees
+    // the stores made before this call.
+    atomic::fence(Ordering::SeqCst);
+    let mut current = HEAD.load(Ordering::Acquire) as *const Node;
+    while let Some(node) = unsafe { current.as_ref() } {
+        let address = node.parked_on.load(Ordering::Relaxed);
+        // Pin the `Parker`, so the thread can't return from `park` and free it while we unpark it.
+        if address & PINNED == 0
+            && address != 0
+            && node
+                .parked_on
+                .compare_exchange(
+                    address,
+                    address | PINNED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            let parker = address as *const Parker;
+            unsafe { (*parker).unpark() };
+            node.parked_on.store(address, Ordering::Release);
+        }
+        current = node.next;
+    }
+}
+
+struct Node {
+    thread: Thread,
+    // Address of the `Parker` this thread is parked on, or 0. The `PINNED` bit is set while
+    // `wake_all_parkers` is unparking it.
+    parked_on: AtomicUsize,
+    next: *const Node,
+}
+
+// A `Parker` is at least 4-byte aligned, so the lowest bit of its address is free.
+const PINNED: usize = 1;
+
+static HEAD: AtomicPtr<Node> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+    static NODE: &'static Node = register_thread();
+}
+
+fn register_thread() -> &'static Node {
+    let node = Box::leak(Box::new(Node {
+        thread: thread::current(),
+        parked_on: AtomicUsize::new(0),
+        next: ptr::null(),
+    }));
+    let mut head = HEAD.load(Ordering::Relaxed);
+    loop {
+        node.next = head;
+        match HEAD.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return node,
+            Err(x) => head = x,
+        }
+    }
+}
+
+// Clears the registration, also if `park` panics.
+struct Unregister(&'static Node);
+
+impl Drop for Unregister {
+    fn drop(&mut self) {
+        // If `wake_all_parkers` is unparking us, our `Parker` must stay alive until it is done.
+        let parked_on = &self.0.parked_on;
+        let address = parked_on.load(Ordering::Relaxed) & !PINNED;
+        while parked_on
+            .compare_exchange(address, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Register the current thread as parked on `parker` for the duration of `park`.
+#[inline]
+pub(crate) fn park<F>(parker: &Parker, park: F) -> WakeupReason
+where
+    F: FnOnce() -> WakeupReason,
+{
+    // The thread-local may already be destroyed if we park from another thread-local destructor.
+    let _guard = NODE.try_with(|&node| {
+        let address = parker as *const Parker as usize;
+        node.parked_on.store(address, Ordering::Relaxed);
+        // Pairs with the fence in `wake_all_parkers`, see there.
+        atomic::fence(Ordering::SeqCst);
+        Unregister(node)
+    });
+    park()
+}
@@ -0,0 +1,35 @@
+//! Annotations for ThreadSanitizer, enabled with the `sanitize-thread` feature.
+//!
+//! TSan does not know a thread that wakes from a futex or other wait primitive was woken by
+//! another thread, so it does not see a happens-before edge between `unpark` and the return of
+//! `park` (or between `store_and_wake` and the return of `compare_and_wait`). These annotations
+//! make the edge explicit. Without the feature they compile to nothing.
+//!
+//! The feature should only be enabled when building with `-Zsanitizer=thread`, otherwise the TSan
+//! runtime will not be linked in.
+
+#[cfg(feature = "sanitize-thread")]
+extern "C" {
+    fn __tsan_acquire(addr: *mut u8);
+    fn __tsan_release(addr: *mut u8);
+}
+
+/// Mark the point where a thread returns from waiting on `addr`.
+#[inline(always)]
+#[allow(unused)]
+pub(crate) fn acquire<T>(addr: &T) {
+    #[cfg(feature = "sanitize-thread")]
+    unsafe {
+        __tsan_acquire(addr as *const T as *mut u8)
+    }
+}
+
+/// Mark the point where a thread is about to wake the threads waiting on `addr`.
+#[inline(always)]
+#[allow(unused)]
+pub(crate) fn release<T>(addr: &T) {
+    #[cfg(feature = "sanitize-thread")]
+    unsafe {
+        __tsan_release(addr as *const T as *mut u8)
+    }
+}
@@ -0,0 +1,122 @@
+//! A join primitive built on `Waiters`, enabled with the `std` feature.
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::{FreeBits, Waiters, FREE_BITS, RESERVED_MASK};
+
+/// Lets a parent thread wait for any number of child tasks to complete.
+///
+/// Every child task holds a [`ScopeGuard`] returned by [`enter`], and signals completion by
+/// dropping it. The guard is also dropped when the child panics, so [`join`] can't hang on a
+/// panicking child. The parent calls [`join`] to park until all guards are dropped.
+///
+/// `Scope` only joins, it doesn't spawn threads. This makes it usable with any kind of thread or
+/// task, also with ones that don't support `std::thread::scope`. Put it in an `Arc` or a `static`
+/// to share it with the children.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use valet_parking::Scope;
+///
+/// let scope = Arc::new(Scope::new());
+/// for _ in 0..4 {
+///     let guard = scope.clone().enter_owned();
+///     thread::spawn(move || {
+///         // do some work...
+///         drop(guard);
+///     });
+/// }
+/// assert!(scope.join());
+/// ```
+///
+/// [`ScopeGuard`]: struct.ScopeGuard.html
+/// [`enter`]: #method.enter
+/// [`join`]: #method.join
+pub struct Scope {
+    // Number of children that have not completed yet.
+    pending: AtomicUsize,
+    // Used with `Waiters`. The last child to complete advances the generation in the free bits.
+    generation: AtomicUsize,
+    panicked: AtomicBool,
+}
+
+impl Scope {
+    pub const fn new() -> Scope {
+        Scope {
+            pending: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            panicked: AtomicBool::new(false),
+        }
+    }
+
+    /// Register a child task. The child signals completion by dropping the returned guard.
+    pub fn enter(&self) -> ScopeGuard<&Scope> {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        ScopeGuard { scope: self }
+    }
+
+    /// Register a child task, with a guard that owns a reference-counted `Scope`. Useful with
+    /// threads that require `'static` closures.
+    pub fn enter_owned(self: Arc<Self>) -> ScopeGuard<Arc<Scope>> {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        ScopeGuard { scope: self }
+    }
+
+    /// Parks the current thread until every child task registered with [`enter`] has completed.
+    ///
+    /// Returns `false` if a child panicked while holding its guard.
+    ///
+    /// # Atomic ordering
+    /// `join` synchronizes with the completion of every child: all writes a child made before
+    /// dropping its guard are visible after `join` returns.
+    ///
+    /// [`enter`]: #method.enter
+    pub fn join(&self) -> bool {
+        loop {
+            // Read the generation before checking `pending`. If the last child completes in
+            // between, the generation has moved on and `compare_and_wait` returns immediately.
+            // `Acquire` keeps the load of `pending` after this one, and makes the decrement of
+            // `pending` visible if we see the new generation, which `store_and_wake` released.
+            let generation = self.generation.load(Ordering::Acquire) & !RESERVED_MASK;
+            if self.pending.load(Ordering::Acquire) == 0 {
+                break;
+            }
+            self.generation.compare_and_wait(generation);
+        }
+        !self.panicked.load(Ordering::Relaxed)
+    }
+
+    fn complete(&self) {
+        if thread::panicking() {
+            self.panicked.store(true, Ordering::Relaxed);
+        }
+        if self.pending.fetch_sub(1, Ordering::Release) == 1 {
+            let generation = FreeBits::from_atomic(self.generation.load(Ordering::Relaxed));
+            let next = FreeBits::new((generation.get() + 1) & ((1 << FREE_BITS) - 1));
+            // The reserved bits of `generation` are only touched by `Waiters`.
+            unsafe { self.generation.store_and_wake(next.bits()) };
+        }
+    }
+}
+
+impl Default for Scope {
+    fn default() -> Scope {
+        Scope::new()
+    }
+}
+
+/// Signals the completion of a child task of a [`Scope`] when dropped, also when the child panics.
+///
+/// [`Scope`]: struct.Scope.html
+pub struct ScopeGuard<S: Deref<Target = Scope>> {
+    scope: S,
+}
+
+impl<S: Deref<Target = Scope>> Drop for ScopeGuard<S> {
+    fn drop(&mut self) {
+        self.scope.complete();
+    }
+}
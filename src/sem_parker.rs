@@ -0,0 +1,278 @@
+use core::hint;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::{WakeupReason, FREE_BITS, PARKER_MOVED, RESERVED_MASK};
+
+// The `Parker` implementation for platforms without a futex, but with cheap kernel semaphores that
+// never wake up spuriously: VxWorks and Haiku, and Solaris with a semaphore built on `lwp_park`.
+// The parked thread keeps a node on its stack with a semaphore created for this park, and the
+// atomic holds a pointer to that node. The thread that unparks us gives the semaphore.
+//
+// A successful `take` means the semaphore was given, but the thread that gave it may still be about
+// to mark the node as woken. We wait for that before deleting the semaphore, so the other thread
+// never touches a deleted semaphore or a node that is gone.
+
+/// A semaphore of the platform, created for a single park.
+pub(crate) trait Semaphore: Sized {
+    /// Creates an empty semaphore. Panics if that fails.
+    fn create() -> Self;
+
+    /// Waits until the semaphore is given, or until the timeout expires. `None` waits forever.
+    ///
+    /// On failure returns `WakeupReason::TimedOut` if the timeout expired, also for a timeout of
+    /// zero, and `WakeupReason::Unknown` otherwise.
+    fn take(&self, timeout: Option<Duration>) -> Result<(), WakeupReason>;
+
+    fn give(&self);
+
+    fn delete(&mut self);
+
+    /// Lets other threads run while we wait for the thread that is busy with our node.
+    fn yield_now();
+}
+
+#[repr(align(64))]
+struct SemParker<S: Semaphore> {
+    sem: S,
+    // `NOT_WOKEN` until the thread that unparks us is done with this node.
+    state: AtomicU32,
+    // The atomic that currently holds the pointer to this `SemParker`. Changes when the waiter is
+    // transferred to another parker, only changed while `NOTIFY_BIT` is set on the old owner.
+    owner: AtomicPtr<AtomicUsize>,
+    // Lets us detect a pointer to a `SemParker` that was moved or freed while parked.
+    #[cfg(debug_assertions)]
+    canary: usize,
+}
+
+#[cfg(debug_assertions)]
+const CANARY: usize = 0x7365_6d70;
+
+const NOT_WOKEN: u32 = 0;
+const WOKEN: u32 = 1;
+
+impl<S: Semaphore> SemParker<S> {
+    #[inline]
+    unsafe fn debug_check_canary(ptr: *const SemParker<S>) {
+        #[cfg(debug_assertions)]
+        assert_eq!((*ptr).canary, CANARY, "{}", PARKER_MOVED);
+    }
+
+    fn owner(&self) -> &AtomicUsize {
+        unsafe { &*self.owner.load(Ordering::SeqCst) }
+    }
+
+    // Wait until the thread that gave our semaphore is done with this node.
+    fn wait_until_woken(&self) {
+        while self.state.load(Ordering::Acquire) != WOKEN {
+            S::yield_now();
+            hint::spin_loop();
+        }
+    }
+}
+
+impl<S: Semaphore> Drop for SemParker<S> {
+    fn drop(&mut self) {
+        self.sem.delete();
+    }
+}
+
+// # State table (of the reserved bits):
+//
+// PTR_BITS | NOTIFY_BIT | Description
+//     0    |     0      | Thread is not parked, and also not just woken up.
+// ---------+------------+------------------------------------------------------------------
+//   some   |     0      | Thread is parked.
+// ---------+------------+------------------------------------------------------------------
+//   some   |     1      | Thread is still parked, but some thread is in the process of
+//          |            | waking it up or transferring it to another parker.
+// ---------+------------+------------------------------------------------------------------
+//     0    |     1      | Thread got woken up by another thread.
+// ---------+------------+------------------------------------------------------------------
+const NOTIFY_BIT: usize = 1;
+const PTR_BITS: usize = RESERVED_MASK ^ NOTIFY_BIT;
+
+pub(crate) fn park<S: Semaphore>(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    // Check for a pending unpark before creating a semaphore.
+    let mut current = atomic.load(Ordering::SeqCst);
+    if current & RESERVED_MASK == NOTIFY_BIT {
+        atomic.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+        return WakeupReason::WokenUp;
+    }
+
+    let parker = SemParker {
+        sem: S::create(),
+        state: AtomicU32::new(NOT_WOKEN),
+        owner: AtomicPtr::new(atomic as *const AtomicUsize as *mut AtomicUsize),
+        #[cfg(debug_assertions)]
+        canary: CANARY,
+    };
+    let ptr = (&parker as *const SemParker<S> as usize) >> FREE_BITS;
+    loop {
+        // If the old state had its `NOTIFY_BIT` set, some other thread unparked us even before we
+        // were able to park ourselves.
+        if current & RESERVED_MASK == NOTIFY_BIT {
+            atomic.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+            return WakeupReason::WokenUp;
+        }
+        match atomic.compare_exchange(current, current | ptr, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(old) => current = old,
+        }
+    }
+
+    let reason = loop {
+        let failed = match parker.sem.take(timeout) {
+            Ok(()) => {
+                parker.wait_until_woken();
+                break WakeupReason::WokenUp;
+            }
+            Err(reason) => reason,
+        };
+        if timeout.is_none() {
+            // Can't time out, and the wait is not interrupted by signals. Something is badly
+            // wrong, but all we can do is try again.
+            continue;
+        }
+        // Stop waiting if no other thread is busy with our node.
+        if try_cancel(&parker, ptr) {
+            break failed;
+        }
+        parker.wait_until_woken();
+        break WakeupReason::WokenUp;
+    };
+
+    // Other threads may have accessed `parker` through `ptr`, which must still be its address.
+    debug_assert_eq!(
+        (&parker as *const SemParker<S> as usize) >> FREE_BITS,
+        ptr,
+        "{}",
+        PARKER_MOVED
+    );
+    if reason == WakeupReason::WokenUp {
+        // We may have been transferred to another parker while waiting.
+        parker.owner().fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+    }
+    reason
+}
+
+// Try to remove our node from the atomic we are parked on after `take` failed. Returns `false` if
+// another thread is waking us after all.
+fn try_cancel<S: Semaphore>(parker: &SemParker<S>, ptr: usize) -> bool {
+    loop {
+        if parker.state.load(Ordering::Acquire) == WOKEN {
+            return false;
+        }
+        let atomic = parker.owner();
+        let current = atomic.load(Ordering::SeqCst);
+        if current & NOTIFY_BIT == NOTIFY_BIT {
+            // Some other thread is waking us or transferring us right now. If it is waking us it
+            // gives our semaphore, which we must not delete before it is done.
+            S::yield_now();
+            hint::spin_loop();
+            continue;
+        }
+        debug_assert_eq!(current & PTR_BITS, ptr);
+        if atomic
+            .compare_exchange(
+                current,
+                current & !RESERVED_MASK,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        {
+            return true;
+        }
+    }
+}
+
+pub(crate) fn is_notified(atomic: &AtomicUsize) -> bool {
+    atomic.load(Ordering::Relaxed) & RESERVED_MASK == NOTIFY_BIT
+}
+
+pub(crate) fn clear_notification(atomic: &AtomicUsize) -> bool {
+    atomic
+        .compare_exchange(NOTIFY_BIT, 0, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+}
+
+pub(crate) unsafe fn unpark<S: Semaphore>(atomic: &AtomicUsize) -> bool {
+    let old = atomic.fetch_or(NOTIFY_BIT, Ordering::SeqCst);
+    if old & NOTIFY_BIT == NOTIFY_BIT || old & PTR_BITS == 0 {
+        // Some other thread is already unparking the parked thread, or there is no thread to wake
+        // up yet.
+        return false;
+    }
+    // The parked thread will not return from `park` while its node is not marked as woken, so we
+    // can safely access data on its stack.
+    let ptr = ((old & PTR_BITS) << FREE_BITS) as *const SemParker<S>;
+    SemParker::debug_check_canary(ptr);
+    atomic.fetch_and(!PTR_BITS, Ordering::SeqCst);
+    wake(ptr);
+    true
+}
+
+// Give the semaphore of the node and mark it as woken. The node may be gone after the store.
+unsafe fn wake<S: Semaphore>(ptr: *const SemParker<S>) {
+    (*ptr).sem.give();
+    (*ptr).state.store(WOKEN, Ordering::Release);
+}
+
+pub(crate) unsafe fn transfer_waiter<S: Semaphore>(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    // Set the `NOTIFY_BIT`, just like `unpark`. This keeps the parked thread from returning while
+    // we access its `SemParker`, and keeps other threads from unparking it concurrently.
+    let mut current = from.load(Ordering::SeqCst);
+    loop {
+        if current & PTR_BITS == 0 || current & NOTIFY_BIT == NOTIFY_BIT {
+            // No thread parked, or it is already being unparked.
+            return false;
+        }
+        match from.compare_exchange(
+            current,
+            current | NOTIFY_BIT,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => break,
+            Err(x) => current = x,
+        }
+    }
+    let ptr_bits = current & PTR_BITS;
+    let ptr = (ptr_bits << FREE_BITS) as *const SemParker<S>;
+    SemParker::debug_check_canary(ptr);
+
+    let mut to_current = to.load(Ordering::SeqCst);
+    let moved = loop {
+        let (new, moved) = match to_current & RESERVED_MASK {
+            0 => (to_current | ptr_bits, true),
+            // `to` was unparked before a thread parked on it. Consume the notification and wake
+            // the thread, as if it parked on `to` itself.
+            NOTIFY_BIT => (to_current & !NOTIFY_BIT, false),
+            _ => {
+                // Undo, and let the parked thread continue if it is waiting for us.
+                from.fetch_and(!NOTIFY_BIT, Ordering::SeqCst);
+                panic!(
+                    "Tried to transfer a waiter to a parker that already has a thread parked on it"
+                );
+            }
+        };
+        match to.compare_exchange(to_current, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break moved,
+            Err(x) => to_current = x,
+        }
+    };
+    if moved {
+        // A thread that timed out waits until `NOTIFY_BIT` is cleared, and then checks the state
+        // of its new owner.
+        (*ptr).owner.store(
+            to as *const AtomicUsize as *mut AtomicUsize,
+            Ordering::SeqCst,
+        );
+        from.fetch_and(!RESERVED_MASK, Ordering::SeqCst);
+    } else {
+        from.fetch_and(!PTR_BITS, Ordering::SeqCst);
+        wake(ptr);
+    }
+    true
+}
@@ -0,0 +1,118 @@
+//! A parker in memory shared between processes. Available on Linux, Android, DragonFly BSD and
+//! FreeBSD, which have process-shared futex operations.
+use core::mem;
+use core::sync::atomic::AtomicI32;
+use core::sync::atomic::Ordering::{Acquire, Release};
+use core::time::Duration;
+
+use crate::futex::{wait_shared, wake_shared};
+
+// States for `SharedParker`, the same as for the futex-based `Parker`. Processes running different
+// versions of this crate may share a parker, so these must not change.
+const NOT_PARKED: i32 = 0x0;
+const PARKED: i32 = 0x1;
+const NOTIFIED: i32 = 0x2;
+
+/// A parker for one thread, that can be unparked from another process.
+///
+/// [`Parker`] uses process-private futex operations, which the kernel keys on the virtual address
+/// of the atomic. A `SharedParker` uses the process-shared operations instead (on Linux
+/// `FUTEX_WAIT` without `FUTEX_PRIVATE_FLAG`), which the kernel keys on the underlying memory
+/// object. This makes it possible for a supervisor process to wake a worker process parked on an
+/// atomic in shared memory, even if the memory is mapped at different addresses in both processes.
+///
+/// The `SharedParker` must live in a region mapped with `MAP_SHARED`, provided by the user. Use
+/// [`from_ptr`] to get a reference to a parker in that region, and [`new`] or [`INIT`] to
+/// initialize it. A `SharedParker` is a single `AtomicI32` (0 for not parked, 1 for parked and 2
+/// for notified), so an all-zero mapping is a valid parker.
+///
+/// Process-shared operations are a little more expensive than private ones, so use [`Parker`]
+/// within a process.
+///
+/// [`Parker`]: struct.Parker.html
+/// [`from_ptr`]: #method.from_ptr
+/// [`new`]: #method.new
+/// [`INIT`]: #associatedconstant.INIT
+#[repr(transparent)]
+pub struct SharedParker {
+    state: AtomicI32,
+}
+
+impl SharedParker {
+    /// A `SharedParker` in its initial state.
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: SharedParker = SharedParker::new();
+
+    /// Create a parker in the not-parked state. To initialize a parker that is already in shared
+    /// memory, write this (or [`INIT`]) to it before any process starts using it.
+    ///
+    /// [`INIT`]: #associatedconstant.INIT
+    pub const fn new() -> SharedParker {
+        SharedParker {
+            state: AtomicI32::new(NOT_PARKED),
+        }
+    }
+
+    /// Get a reference to a `SharedParker` at `ptr`, typically inside a shared memory mapping.
+    ///
+    /// # Safety
+    /// `ptr` must be valid for reads and writes, aligned to 4 bytes, and point to an initialized
+    /// `SharedParker` (or 4 zero bytes). The memory must remain mapped for the lifetime `'a`, and
+    /// must only be accessed atomically while it is in use as a parker.
+    pub unsafe fn from_ptr<'a>(ptr: *mut u8) -> &'a SharedParker {
+        let ptr = ptr as *const SharedParker;
+        debug_assert!(ptr as usize % mem::align_of::<SharedParker>() == 0);
+        &*ptr
+    }
+
+    /// Parks the current thread until a thread in this or another process calls [`unpark`], or
+    /// until the timeout expires.
+    ///
+    /// Only one thread can park on `self`. If `park` is called on a parker that already has a
+    /// thread parked on it, it will panic. If `timeout` is `None` this function will only return
+    /// after an [`unpark`]. With a timeout it may also return spuriously.
+    ///
+    /// [`unpark`]: #method.unpark
+    pub fn park(&self, timeout: Option<Duration>) {
+        let atomic = &self.state;
+        loop {
+            // On DragonFly BSD a spurious wakeup can be caused by the physical address of the
+            // atomic changing, and the thread has to do a write on it before waiting again. The
+            // `swap` below and this `compare_exchange` both are such a write.
+            match atomic.compare_exchange(NOT_PARKED, PARKED, Release, Acquire) {
+                Ok(_) => {}
+                Err(NOTIFIED) => {
+                    atomic.swap(NOT_PARKED, Acquire);
+                    return;
+                }
+                Err(_) => panic!(
+                    "Tried to call park on a shared parker while \
+                     another thread is already parked on it"
+                ),
+            };
+            let _ = wait_shared(atomic, PARKED, timeout);
+            let wakeup_state = atomic.swap(NOT_PARKED, Acquire);
+            if wakeup_state == NOTIFIED {
+                return;
+            } else if timeout.is_some() {
+                // There was a timeout supplied, in which case we don't guarantee there are no
+                // spurious wakeups.
+                return;
+            }
+        }
+    }
+
+    /// Unparks the thread parked on `self`, which may be in another process. If no thread is
+    /// parked yet, the next `park` returns immediately.
+    pub fn unpark(&self) {
+        if self.state.swap(NOTIFIED, Release) == PARKED {
+            let _ = wake_shared(&self.state);
+        }
+    }
+}
+
+impl Default for SharedParker {
+    fn default() -> SharedParker {
+        SharedParker::new()
+    }
+}
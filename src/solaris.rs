@@ -0,0 +1,116 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::sem_parker::{self, Semaphore};
+use crate::waiter_queue;
+use crate::WakeupReason;
+
+pub(crate) use sem_parker::{clear_notification, is_notified};
+pub(crate) use waiter_queue::{
+    compare_and_wait, compare_and_wait_timeout, compare_store_and_wake, handoff_to_one,
+    store_and_wake, waiter_count, wake_only,
+};
+
+// Solaris and illumos can park and unpark a thread (LWP) by its id. That is all a semaphore for a
+// single park needs, see `sem_parker` for how it is shared with the thread that unparks us.
+//
+// An unpark that arrives after the thread stopped waiting is remembered by the kernel, and causes
+// a spurious wakeup on the next `__lwp_park` of that thread. So `take` doesn't trust a wakeup, but
+// checks whether `give` was called.
+struct LwpSem {
+    lwpid: lwpid_t,
+    given: AtomicBool,
+}
+
+impl Semaphore for LwpSem {
+    fn create() -> LwpSem {
+        LwpSem {
+            lwpid: unsafe { _lwp_self() },
+            given: AtomicBool::new(false),
+        }
+    }
+
+    fn take(&self, timeout: Option<Duration>) -> Result<(), WakeupReason> {
+        let ts = convert_timeout(timeout);
+        loop {
+            if self.given.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            let mut ts_copy = ts;
+            let ts_ptr = ts_copy
+                .as_mut()
+                .map(|ts_ref| ts_ref as *mut libc::timespec)
+                .unwrap_or(ptr::null_mut());
+            let r = unsafe { __lwp_park(ts_ptr, 0) };
+            if self.given.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            if ts.is_none() {
+                continue;
+            }
+            // With a timeout we don't guarantee there are no spurious wakeups.
+            return Err(if r == libc::ETIME || r == libc::ETIMEDOUT {
+                WakeupReason::TimedOut
+            } else if r == libc::EINTR {
+                WakeupReason::Interrupt
+            } else {
+                WakeupReason::Unknown
+            });
+        }
+    }
+
+    fn give(&self) {
+        // `sem_parker` keeps our node alive until it is marked as woken, which happens after we
+        // return.
+        self.given.store(true, Ordering::Release);
+        let _r = unsafe { __lwp_unpark(self.lwpid) };
+        debug_assert!(_r == 0 || _r == libc::ESRCH);
+    }
+
+    fn delete(&mut self) {}
+
+    fn yield_now() {
+        unsafe { libc::sched_yield() };
+    }
+}
+
+pub(crate) type Parker = AtomicUsize;
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    sem_parker::park::<LwpSem>(atomic, timeout)
+}
+
+pub(crate) unsafe fn unpark(atomic: &AtomicUsize) -> bool {
+    sem_parker::unpark::<LwpSem>(atomic)
+}
+
+pub(crate) unsafe fn transfer_waiter(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    sem_parker::transfer_waiter::<LwpSem>(from, to)
+}
+
+#[allow(non_camel_case_types)]
+type lwpid_t = libc::c_uint;
+
+extern "C" {
+    fn _lwp_self() -> lwpid_t;
+    // Takes a relative timeout, and returns an error number instead of setting `errno`. If `lwpid`
+    // is not 0, that thread is unparked first.
+    fn __lwp_park(timeout: *mut libc::timespec, lwpid: lwpid_t) -> libc::c_int;
+    fn __lwp_unpark(lwpid: lwpid_t) -> libc::c_int;
+}
+
+fn convert_timeout(timeout: Option<Duration>) -> Option<libc::timespec> {
+    match timeout {
+        Some(duration) => {
+            if duration.as_secs() > libc::time_t::MAX as u64 {
+                return None;
+            }
+            Some(libc::timespec {
+                tv_sec: duration.as_secs() as libc::time_t,
+                tv_nsec: duration.subsec_nanos() as libc::c_long,
+            })
+        }
+        None => None,
+    }
+}
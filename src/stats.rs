@@ -0,0 +1,99 @@
+//! Counters for diagnosing the behaviour of the parking primitives, enabled with the `stats`
+//! feature.
+//!
+//! All counters are process-global and updated with relaxed atomic operations. They are only
+//! intended as a hint for tuning, not for synchronization.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+use core::{sync::atomic::AtomicU64, time::Duration};
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+use std::time::Instant;
+
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+use crate::WakeupReason;
+
+static WAKE_CALLS: AtomicUsize = AtomicUsize::new(0);
+static WASTED_WAKES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+static MAX_PARK_NANOS: AtomicU64 = AtomicU64::new(0);
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+static TOTAL_PARK_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// A snapshot of the counters, as returned by [`snapshot`](fn.snapshot.html).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of wake syscalls issued by `store_and_wake` and `handoff_to_one`.
+    pub wake_calls: usize,
+    /// Number of wake syscalls that woke zero threads.
+    ///
+    /// This happens when the `HAS_WAITERS` bit was set, but the waiting thread already left before
+    /// the wake was issued. Only counted on platforms where the kernel reports the number of woken
+    /// threads (Linux, Android, FreeBSD, OpenBSD, DragonFly BSD, Redox and WASM atomics).
+    pub wasted_wakes: usize,
+    /// The longest time a thread spent in `Parker::park`, requires the `std` feature and 64-bit
+    /// atomics.
+    ///
+    /// A park that lasts much longer than expected can indicate a missing `unpark`.
+    #[cfg(all(feature = "std", target_has_atomic = "64"))]
+    pub max_park: Duration,
+    /// The total time threads spent in `Parker::park`, requires the `std` feature and 64-bit
+    /// atomics.
+    ///
+    /// This includes parks that return right away because they consume a pending `unpark`, but
+    /// not a `park_if` whose condition didn't hold.
+    #[cfg(all(feature = "std", target_has_atomic = "64"))]
+    pub total_park: Duration,
+}
+
+/// Take a snapshot of the current value of all counters.
+pub fn snapshot() -> Stats {
+    Stats {
+        wake_calls: WAKE_CALLS.load(Ordering::Relaxed),
+        wasted_wakes: WASTED_WAKES.load(Ordering::Relaxed),
+        #[cfg(all(feature = "std", target_has_atomic = "64"))]
+        max_park: Duration::from_nanos(MAX_PARK_NANOS.load(Ordering::Relaxed)),
+        #[cfg(all(feature = "std", target_has_atomic = "64"))]
+        total_park: Duration::from_nanos(TOTAL_PARK_NANOS.load(Ordering::Relaxed)),
+    }
+}
+
+// Platforms where `Futex::wake` returns the number of threads that where woken.
+const WAKE_REPORTS_COUNT: bool = cfg!(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "emscripten",
+    target_os = "freebsd",
+    target_os = "hermit",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "redox",
+    all(target_arch = "wasm32", target_feature = "atomics")
+));
+
+#[allow(unused)]
+pub(crate) fn record_wake<E>(result: Result<usize, E>) {
+    WAKE_CALLS.fetch_add(1, Ordering::Relaxed);
+    if WAKE_REPORTS_COUNT && result.ok() == Some(0) {
+        WASTED_WAKES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Run `park` and record how long it took, unless it returned `NoMatch` because the condition of
+/// `park_if` didn't hold.
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+#[inline]
+pub(crate) fn park<F>(park: F) -> WakeupReason
+where
+    F: FnOnce() -> WakeupReason,
+{
+    let start = Instant::now();
+    let reason = park();
+    if reason != WakeupReason::NoMatch {
+        let nanos = start.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+        TOTAL_PARK_NANOS.fetch_add(nanos, Ordering::Relaxed);
+        MAX_PARK_NANOS.fetch_max(nanos, Ordering::Relaxed);
+    }
+    reason
+}
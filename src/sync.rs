@@ -0,0 +1,143 @@
+//! A one-shot event built on `Waiters`.
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{FreeBits, Waiters, FREE_BITS};
+
+// The highest free bit marks the event as set, the others are for the user.
+const SET: usize = FreeBits::new(1 << (FREE_BITS - 1)).bits();
+const USER_MASK: usize = FreeBits::new((1 << (FREE_BITS - 1)) - 1).bits();
+
+/// A one-shot event: threads wait until some thread sets it.
+///
+/// Wraps an `AtomicUsize` used with [`Waiters`], and takes care of the reserved bits. Once set, an
+/// event stays set. Setting the event synchronizes with every thread that sees it set, so writes
+/// done before [`set_and_notify_all`] are visible after [`wait_until_set`] returns.
+///
+/// Besides the flag, the event holds a small value of [`USER_BITS`] bits for the caller's own
+/// state, for example to record why an event was set.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use valet_parking::Event;
+///
+/// let event = Arc::new(Event::new());
+/// let waiter = {
+///     let event = event.clone();
+///     thread::spawn(move || {
+///         event.wait_until_set();
+///         event.user_bits()
+///     })
+/// };
+/// event.set_user_bits(3);
+/// event.set_and_notify_all();
+/// assert_eq!(waiter.join().unwrap(), 3);
+/// ```
+///
+/// [`Waiters`]: trait.Waiters.html
+/// [`set_and_notify_all`]: #method.set_and_notify_all
+/// [`wait_until_set`]: #method.wait_until_set
+/// [`USER_BITS`]: #associatedconstant.USER_BITS
+pub struct Event {
+    state: AtomicUsize,
+}
+
+impl Event {
+    /// The number of bits available for the value returned by [`user_bits`].
+    ///
+    /// [`user_bits`]: #method.user_bits
+    pub const USER_BITS: usize = FREE_BITS - 1;
+
+    /// Create a new event that is not set, with all user bits zero.
+    pub const fn new() -> Event {
+        Event {
+            state: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `true` if the event is set.
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Acquire) & SET == SET
+    }
+
+    /// Park the current thread until the event is set. Returns immediately if it already is.
+    pub fn wait_until_set(&self) {
+        loop {
+            let current = self.state.load(Ordering::Acquire);
+            if current & SET == SET {
+                return;
+            }
+            // Returns right away if the user bits changed in the meantime, then we try again.
+            self.state
+                .compare_and_wait(FreeBits::from_atomic(current).bits());
+        }
+    }
+
+    /// Set the event, and wake all threads waiting on it. Setting an event that is already set
+    /// does nothing.
+    pub fn set_and_notify_all(&self) {
+        let mut current = self.state.load(Ordering::Relaxed);
+        loop {
+            if current & SET == SET {
+                return;
+            }
+            let expected = FreeBits::from_atomic(current).bits();
+            // We never set any of the reserved bits, only `Waiters` touches them.
+            if unsafe { self.state.compare_store_and_wake(expected, expected | SET) } {
+                return;
+            }
+            current = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    /// The value in the user bits.
+    pub fn user_bits(&self) -> usize {
+        FreeBits::from_atomic(self.state.load(Ordering::Acquire) & USER_MASK).get()
+    }
+
+    /// Store `value` in the user bits. Does not wake waiting threads, and does not change whether
+    /// the event is set.
+    ///
+    /// # Panics
+    /// Panics if `value` does not fit in [`USER_BITS`] bits.
+    ///
+    /// [`USER_BITS`]: #associatedconstant.USER_BITS
+    pub fn set_user_bits(&self, value: usize) {
+        assert!(
+            value < (1 << Event::USER_BITS),
+            "value does not fit in the user bits of an `Event`"
+        );
+        let bits = FreeBits::new(value).bits();
+        // Keeps the reserved bits as they are. A waiting thread compares the free bits only before
+        // it goes to sleep, so this can't cause a lost wakeup.
+        let mut current = self.state.load(Ordering::Relaxed);
+        loop {
+            let new = (current & !USER_MASK) | bits;
+            match self.state.compare_exchange_weak(
+                current,
+                new,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => current = x,
+            }
+        }
+    }
+}
+
+impl Default for Event {
+    fn default() -> Event {
+        Event::new()
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Event")
+            .field("set", &self.is_set())
+            .field("user_bits", &self.user_bits())
+            .finish()
+    }
+}
@@ -0,0 +1,21 @@
+//! Instrumentation of `Parker::park` with the `tracing` crate, enabled with the `tracing` feature.
+use core::time::Duration;
+use std::time::Instant;
+
+use crate::WakeupReason;
+
+/// Run `park` inside a `park` span, emitting a `park.enter` event before the thread blocks and a
+/// `park.exit` event with the wakeup reason and time spent parked after it returns.
+#[inline]
+pub(crate) fn park<F>(timeout: Option<Duration>, park: F) -> WakeupReason
+where
+    F: FnOnce() -> WakeupReason,
+{
+    let span = tracing::trace_span!("park", ?timeout);
+    let _enter = span.enter();
+    tracing::trace!("park.enter");
+    let start = Instant::now();
+    let reason = park();
+    tracing::trace!(?reason, elapsed = ?start.elapsed(), "park.exit");
+    reason
+}
@@ -1,9 +1,8 @@
-#![allow(unused)]
 use core::cell::UnsafeCell;
 use core::sync::atomic::*;
 
 // Copied from `libstd/sys/unix/os.rs`.
-#[cfg(unix)]
+#[cfg(all(unix, not(any(target_os = "fuchsia", target_os = "redox"))))]
 extern "C" {
     #[cfg(not(target_os = "dragonfly"))]
     #[cfg_attr(
@@ -34,9 +33,12 @@ extern "C" {
     fn errno_location() -> *mut libc::c_int;
 }
 
-#[cfg(all(unix, not(target_os = "dragonfly")))]
+#[cfg(all(
+    unix,
+    not(any(target_os = "dragonfly", target_os = "fuchsia", target_os = "redox"))
+))]
 pub(crate) fn errno() -> i32 {
-    unsafe { (*errno_location()) as i32 }
+    unsafe { *errno_location() }
 }
 
 #[cfg(target_os = "dragonfly")]
@@ -49,6 +51,23 @@ pub(crate) fn errno() -> i32 {
     unsafe { errno as i32 }
 }
 
+// Clamp the count passed to `Futex::wake_n` to the `int` the futex-like syscalls take.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "emscripten",
+    target_os = "freebsd",
+    target_os = "hermit",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "redox"
+))]
+#[inline]
+pub(crate) fn clamp_count(count: usize) -> i32 {
+    count.min(i32::MAX as usize) as i32
+}
+
 pub(crate) trait AtomicAsMutPtr {
     type Integer;
 
@@ -66,14 +85,36 @@ macro_rules! imp_as_mut_ptr {
         }
     };
 }
-imp_as_mut_ptr!(AtomicUsize, usize);
-imp_as_mut_ptr!(AtomicIsize, isize);
-imp_as_mut_ptr!(AtomicU64, u64);
-imp_as_mut_ptr!(AtomicI64, i64);
+// Every futex backend works on 32-bit atomics. FreeBSD also supports 64-bit atomics, and Windows
+// supports all sizes.
 imp_as_mut_ptr!(AtomicU32, u32);
 imp_as_mut_ptr!(AtomicI32, i32);
+#[cfg(any(all(target_os = "freebsd", target_pointer_width = "64"), windows))]
+imp_as_mut_ptr!(AtomicU64, u64);
+#[cfg(any(all(target_os = "freebsd", target_pointer_width = "64"), windows))]
+imp_as_mut_ptr!(AtomicI64, i64);
+// On 32-bit targets pointer-sized atomics are 32-bit futexes on every backend.
+#[cfg(any(target_pointer_width = "32", windows))]
+imp_as_mut_ptr!(AtomicUsize, usize);
+#[cfg(any(target_pointer_width = "32", windows))]
+imp_as_mut_ptr!(AtomicIsize, isize);
+#[cfg(windows)]
 imp_as_mut_ptr!(AtomicU16, u16);
+#[cfg(windows)]
 imp_as_mut_ptr!(AtomicI16, i16);
+#[cfg(windows)]
 imp_as_mut_ptr!(AtomicU8, u8);
+#[cfg(windows)]
 imp_as_mut_ptr!(AtomicI8, i8);
+#[cfg(windows)]
 imp_as_mut_ptr!(AtomicBool, bool);
+// Pointers are pointer-sized futexes on 32-bit targets and Windows, and the `Futex` impl for
+// `AtomicPtr` on other 64-bit targets waits on the first 4 bytes.
+#[cfg(any(target_pointer_width = "32", target_endian = "little", windows))]
+impl<T> AtomicAsMutPtr for AtomicPtr<T> {
+    type Integer = *mut T;
+
+    fn as_mut_ptr(&self) -> *mut Self::Integer {
+        unsafe { (&*(self as *const AtomicPtr<T> as *const UnsafeCell<*mut T>)).get() }
+    }
+}
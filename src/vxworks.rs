@@ -0,0 +1,121 @@
+use core::sync::atomic::AtomicUsize;
+use core::time::Duration;
+
+use crate::sem_parker::{self, Semaphore};
+use crate::waiter_queue;
+use crate::WakeupReason;
+
+pub(crate) use sem_parker::{clear_notification, is_notified};
+pub(crate) use waiter_queue::{
+    compare_and_wait, compare_and_wait_timeout, compare_store_and_wake, handoff_to_one,
+    store_and_wake, waiter_count, wake_only,
+};
+
+// VxWorks has no futex, but its binary semaphores are cheap and never wake up spuriously. The
+// parked thread creates one for every park, see `sem_parker` for how it is shared with the thread
+// that unparks us.
+struct BinarySem(SEM_ID);
+
+impl Semaphore for BinarySem {
+    fn create() -> BinarySem {
+        let sem = unsafe { semBCreate(SEM_Q_FIFO, SEM_EMPTY) };
+        assert!(!sem.is_null(), "failed to create a semaphore to park on");
+        BinarySem(sem)
+    }
+
+    fn take(&self, timeout: Option<Duration>) -> Result<(), WakeupReason> {
+        let ticks = convert_timeout_ticks(timeout);
+        if unsafe { semTake(self.0, ticks) } == OK {
+            return Ok(());
+        }
+        let errno = unsafe { errnoGet() };
+        if errno == S_objLib_OBJ_TIMEOUT || errno == S_objLib_OBJ_UNAVAILABLE {
+            return Err(WakeupReason::TimedOut);
+        }
+        debug_assert!(
+            ticks != WAIT_FOREVER,
+            "semTake failed with error {:#x}",
+            errno
+        );
+        Err(WakeupReason::Unknown)
+    }
+
+    fn give(&self) {
+        let _r = unsafe { semGive(self.0) };
+        debug_assert_eq!(_r, OK);
+    }
+
+    fn delete(&mut self) {
+        let _r = unsafe { semDelete(self.0) };
+        debug_assert_eq!(_r, OK);
+    }
+
+    fn yield_now() {
+        unsafe { taskDelay(0) };
+    }
+}
+
+pub(crate) type Parker = AtomicUsize;
+
+pub(crate) fn park(atomic: &AtomicUsize, timeout: Option<Duration>) -> WakeupReason {
+    sem_parker::park::<BinarySem>(atomic, timeout)
+}
+
+pub(crate) unsafe fn unpark(atomic: &AtomicUsize) -> bool {
+    sem_parker::unpark::<BinarySem>(atomic)
+}
+
+pub(crate) unsafe fn transfer_waiter(from: &AtomicUsize, to: &AtomicUsize) -> bool {
+    sem_parker::transfer_waiter::<BinarySem>(from, to)
+}
+
+// Not exported by the `libc` crate.
+#[allow(non_camel_case_types)]
+type SEM_ID = *mut libc::c_void;
+#[allow(clippy::upper_case_acronyms)]
+type STATUS = libc::c_int;
+#[allow(non_camel_case_types)]
+type _Vx_ticks_t = libc::c_int;
+
+const OK: STATUS = 0;
+const SEM_Q_FIFO: libc::c_int = 0x00;
+const SEM_EMPTY: libc::c_int = 0;
+const WAIT_FOREVER: _Vx_ticks_t = -1;
+const NO_WAIT: _Vx_ticks_t = 0;
+// `M_objLib | 2` and `M_objLib | 4`, where `M_objLib` is module number 61.
+#[allow(non_upper_case_globals)]
+const S_objLib_OBJ_UNAVAILABLE: libc::c_int = (61 << 16) | 2;
+#[allow(non_upper_case_globals)]
+const S_objLib_OBJ_TIMEOUT: libc::c_int = (61 << 16) | 4;
+
+extern "C" {
+    fn semBCreate(options: libc::c_int, initial_state: libc::c_int) -> SEM_ID;
+    // Returns `ERROR` with `errno` set to `S_objLib_OBJ_TIMEOUT` if the timeout expired, or to
+    // `S_objLib_OBJ_UNAVAILABLE` with `NO_WAIT`.
+    fn semTake(sem_id: SEM_ID, timeout: _Vx_ticks_t) -> STATUS;
+    fn semGive(sem_id: SEM_ID) -> STATUS;
+    fn semDelete(sem_id: SEM_ID) -> STATUS;
+    fn sysClkRateGet() -> libc::c_int;
+    fn taskDelay(ticks: _Vx_ticks_t) -> STATUS;
+    fn errnoGet() -> libc::c_int;
+}
+
+// Timeout in system clock ticks, rounded up so we never wake up early. Durations too long to count
+// in ticks become `WAIT_FOREVER`.
+#[allow(clippy::manual_div_ceil)]
+fn convert_timeout_ticks(timeout: Option<Duration>) -> _Vx_ticks_t {
+    let duration = match timeout {
+        Some(duration) => duration,
+        None => return WAIT_FOREVER,
+    };
+    if duration == Duration::from_secs(0) {
+        return NO_WAIT;
+    }
+    let rate = unsafe { sysClkRateGet() }.max(1) as u128;
+    let ticks = (duration.as_nanos() * rate + 999_999_999) / 1_000_000_000;
+    if ticks > _Vx_ticks_t::MAX as u128 {
+        WAIT_FOREVER
+    } else {
+        ticks as _Vx_ticks_t
+    }
+}
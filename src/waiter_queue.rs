@@ -1,16 +1,76 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
 
-use crate::{Parker, FREE_BITS, RESERVED_MASK};
+use crate::clock::Deadline;
+use crate::{imp, sanitizer};
+use crate::{WakeupReason, FREE_BITS, RESERVED_BITS_MODIFIED, RESERVED_MASK};
 
 // Align so that the 5 lower bits are free for other uses.
 #[repr(align(32))]
 struct Waiter {
-    parker: Parker,
+    // Not a `crate::Parker`: that would register the waiter with the `debug-registry`, and
+    // `wake_all_parkers` could then wake it while its node is still in the queue. It would also
+    // show up in the tracing and stats of user parkers, and spin first.
+    parker: imp::Parker,
     next: usize,
+    // Set by a thread that timed out and had to take the whole queue to get out of it. The waiter
+    // did not get a real wakeup, and has to enqueue itself again.
+    requeue: AtomicBool,
+    // Lets us detect a pointer in the reserved bits that was not put there by us.
+    #[cfg(debug_assertions)]
+    canary: usize,
+}
+
+#[cfg(debug_assertions)]
+const CANARY: usize = 0x7661_6c65;
+
+impl Waiter {
+    fn new(next: usize) -> Waiter {
+        Waiter {
+            parker: imp::Parker::new(0),
+            next,
+            requeue: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            canary: CANARY,
+        }
+    }
+
+    fn park(&self, timeout: Option<Duration>) -> WakeupReason {
+        let reason = if timeout == Some(Duration::from_secs(0)) {
+            // Poll, the platforms don't agree on what a zero timeout means.
+            if imp::is_notified(&self.parker) {
+                imp::park(&self.parker, None);
+                WakeupReason::WokenUp
+            } else {
+                WakeupReason::TimedOut
+            }
+        } else {
+            imp::park(&self.parker, timeout)
+        };
+        sanitizer::acquire(&self.parker);
+        reason
+    }
+
+    // Takes a raw pointer, not `&self`: the waiting thread may return and free its node while we
+    // are still in `imp::unpark`. Only the parker, which is all atomics, is borrowed.
+    #[allow(unused_unsafe)]
+    unsafe fn unpark(node: *const Waiter) {
+        let parker = &(*node).parker;
+        sanitizer::release(parker);
+        unsafe { imp::unpark(parker) };
+    }
+
+    #[inline]
+    unsafe fn debug_check_canary(node: *const Waiter) {
+        #[cfg(debug_assertions)]
+        assert_eq!((*node).canary, CANARY, "{}", RESERVED_BITS_MODIFIED);
+    }
 }
 
 pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
-    let mut current = atomic.load(Ordering::Relaxed);
+    // Start by assuming the queue is empty and the value matches, the `compare_exchange` below
+    // does the load if that turns out to be wrong.
+    let mut current = expected;
     loop {
         let pub_bits = current & !RESERVED_MASK;
         let next = (current & RESERVED_MASK) << FREE_BITS;
@@ -18,36 +78,181 @@ pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
             break;
         }
         // Create a node for our current thread.
-        let node = Waiter {
-            parker: Parker::new(),
-            next,
-        };
+        let node = Waiter::new(next);
         let me = pub_bits | ((&node as *const Waiter as usize) >> FREE_BITS);
 
         // Try to slide in the node at the head of the linked list, making sure
         // that another thread didn't just replace the head of the linked list.
-        let old = atomic.compare_and_swap(current, me, Ordering::Release);
-        if old != current {
+        if let Err(old) = atomic.compare_exchange(current, me, Ordering::Release, Ordering::Relaxed)
+        {
             current = old;
             continue;
         }
 
         // We have enqueued ourselves, now lets wait.
-        // The parker will not park our thread if we got unparked just now.
-        node.parker.park(None);
+        // The parker will not park our thread if we got unparked just now. Our node only gets
+        // unparked after the queue is detached from the atomic, so this is a real wakeup even if
+        // the value still matches after a `wake_only`. Unless a thread that timed out detached it.
+        node.park(None);
+        if !node.requeue.load(Ordering::Relaxed) {
+            break;
+        }
         current = atomic.load(Ordering::Relaxed);
     }
 }
 
+// Waits at most once. Returns `false` if we are not woken and the value still matches, because of
+// the timeout or a spurious wakeup.
+//
+// The nodes live on the stacks of the waiting threads, so we can't return while our node is still
+// in the queue. Taking a node out of the middle of the linked list is not possible while other
+// threads may be walking it, but taking the whole queue is: detach it just like `wake_only`. Then
+// unpark every other waiter with `requeue` set, and they enqueue themselves again.
+pub(crate) fn compare_and_wait_timeout(
+    atomic: &AtomicUsize,
+    expected: usize,
+    deadline: &mut Deadline,
+) -> bool {
+    let mut current = expected;
+    loop {
+        let pub_bits = current & !RESERVED_MASK;
+        let next = (current & RESERVED_MASK) << FREE_BITS;
+        if pub_bits != expected {
+            return true;
+        }
+        let node = Waiter::new(next);
+        let me = pub_bits | ((&node as *const Waiter as usize) >> FREE_BITS);
+        if let Err(old) = atomic.compare_exchange(current, me, Ordering::Release, Ordering::Relaxed)
+        {
+            current = old;
+            continue;
+        }
+
+        // Under contention the loop above may have taken a while, count it against the timeout.
+        if node.park(Some(deadline.remaining())) == WakeupReason::WokenUp {
+            return !node.requeue.load(Ordering::Relaxed)
+                || atomic.load(Ordering::Relaxed) & !RESERVED_MASK != expected;
+        }
+        let queue = atomic.fetch_and(!RESERVED_MASK, Ordering::AcqRel);
+        let found = unsafe { requeue_all_except(queue, &node) };
+        if !found {
+            // A waker detached the queue with our node before us, and will unpark us.
+            node.park(None);
+            if !node.requeue.load(Ordering::Relaxed) {
+                return true;
+            }
+        }
+        return queue & !RESERVED_MASK != expected;
+    }
+}
 pub(crate) unsafe fn store_and_wake(atomic: &AtomicUsize, new: usize) {
     let queue = atomic.swap(new, Ordering::AcqRel);
+    wake_queue(queue);
+}
+
+pub(crate) unsafe fn compare_store_and_wake(
+    atomic: &AtomicUsize,
+    expected: usize,
+    new: usize,
+) -> bool {
+    let mut current = atomic.load(Ordering::Relaxed);
+    loop {
+        if current & !RESERVED_MASK != expected {
+            return false;
+        }
+        match atomic.compare_exchange_weak(current, new, Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(x) => current = x,
+        }
+    }
+    wake_queue(current);
+    true
+}
+
+// Detach the queue, leaving the non-reserved bits alone.
+pub(crate) unsafe fn wake_only(atomic: &AtomicUsize) {
+    let queue = atomic.fetch_and(!RESERVED_MASK, Ordering::AcqRel);
+    wake_queue(queue);
+}
+
+// Walk the entire linked list of waiters and wake them up (in lifo order, last to register is
+// first to wake up). `queue` must be detached from the atomic.
+//
+// Detaching is what makes this a batch: a thread that wakes up and has to wait again enqueues a new
+// node on the atomic, never on the list we are walking. The walk itself can't be split into a pass
+// that marks every node as notified and a pass that wakes them. A thread whose parker is notified
+// may return as soon as it wakes up spuriously, and its node is gone with it, so we could not
+// reach the nodes behind it. Without allocating there is also no place to keep their addresses.
+// So each node gets a single `unpark`, after we read its `next` pointer.
+unsafe fn wake_queue(queue: usize) {
+    let mut next = ((queue & RESERVED_MASK) << FREE_BITS) as *const Waiter;
+    while !next.is_null() {
+        let current = next;
+        Waiter::debug_check_canary(current);
+        next = (*current).next as *const Waiter;
+        Waiter::unpark(current);
+    }
+}
 
-    // Walk the entire linked list of waiters and wake them up (in lifo
-    // order, last to register is first to wake up).
+// Like `wake_queue`, but marks every waiter to enqueue itself again, and skips `me`. Returns
+// whether `me` was in the queue.
+unsafe fn requeue_all_except(queue: usize, me: *const Waiter) -> bool {
+    let mut found = false;
     let mut next = ((queue & RESERVED_MASK) << FREE_BITS) as *const Waiter;
     while !next.is_null() {
         let current = next;
+        Waiter::debug_check_canary(current);
         next = (*current).next as *const Waiter;
-        (*current).parker.unpark();
+        if current == me {
+            found = true;
+            continue;
+        }
+        // Published to the waiter by the release-acquire pair of `unpark` and `park`.
+        (*current).requeue.store(true, Ordering::Relaxed);
+        Waiter::unpark(current);
+    }
+    found
+}
+
+// Walking the queue is not possible without synchronizing with `store_and_wake`: the nodes live on
+// the stacks of the waiting threads, and are gone as soon as they get unparked.
+pub(crate) fn waiter_count(atomic: &AtomicUsize) -> Option<usize> {
+    if atomic.load(Ordering::Relaxed) & RESERVED_MASK == 0 {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+// Only wakes the head of the queue (the last thread to register). The remaining waiters stay in the
+// queue, so a later `store_and_wake` can still find them.
+pub(crate) unsafe fn handoff_to_one(atomic: &AtomicUsize, new: usize) -> bool {
+    // Acquire, to see the head node that was published with a `Release` by its thread.
+    let mut current = atomic.load(Ordering::Acquire);
+    loop {
+        let head = ((current & RESERVED_MASK) << FREE_BITS) as *const Waiter;
+        if head.is_null() {
+            match atomic.compare_exchange_weak(current, new, Ordering::Release, Ordering::Acquire) {
+                Ok(_) => return false,
+                Err(x) => current = x,
+            }
+            continue;
+        }
+        // The head can not go away while it is in the queue, because the waiting thread only
+        // returns after being unparked.
+        Waiter::debug_check_canary(head);
+        let rest = (*head).next >> FREE_BITS;
+        match atomic.compare_exchange_weak(
+            current,
+            new | rest,
+            Ordering::Release,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                Waiter::unpark(head);
+                return true;
+            }
+            Err(x) => current = x,
+        }
     }
 }
@@ -0,0 +1,149 @@
+//! `Waiters32`, a variant of `Waiters` on an `AtomicU32`. Only available on platforms where the
+//! futex interface natively operates on 32-bit integers, and not with the `fallback` feature.
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::{Relaxed, Release};
+
+use crate::{sanitizer, Futex, FREE_BITS, RESERVED_BITS_MODIFIED};
+
+/// Number of low-order bits which are reserved while using the
+/// [`Waiters32`](trait.Waiters32.html) trait.
+pub const RESERVED_BITS_32: u32 = 32 - FREE_BITS as u32;
+/// Mask matching the bits which are reserved while using the [`Waiters32`](trait.Waiters32.html)
+/// trait.
+pub const RESERVED_MASK_32: u32 = (1 << RESERVED_BITS_32) - 1;
+
+// The only reserved bit that is ever set.
+const HAS_WAITERS: u32 = 0x1;
+
+/// Multiple threads can wait on a single [`AtomicU32`] until one thread wakes them all up at once.
+///
+/// The same as [`Waiters`], but on a 32-bit atomic that is passed to the operating system as is.
+/// There is no need to pick the part of a pointer-sized atomic the kernel can compare, so the
+/// whole value is compared, independent of endianness. Use it if a pointer-sized atomic is not
+/// needed for other reasons.
+///
+/// The five high-order bits are free. All other bits are reserved, see the [`RESERVED_BITS_32`]
+/// and [`RESERVED_MASK_32`] constants.
+///
+/// # Safety
+/// The same as for [`Waiters`]: the reserved bits must be zero before the first use of
+/// [`compare_and_wait`], and must not be changed while threads may be waiting.
+///
+/// [`AtomicU32`]: https://doc.rust-lang.org/core/sync/atomic/struct.AtomicU32.html
+/// [`Waiters`]: trait.Waiters.html
+/// [`RESERVED_BITS_32`]: constant.RESERVED_BITS_32.html
+/// [`RESERVED_MASK_32`]: constant.RESERVED_MASK_32.html
+/// [`compare_and_wait`]: #tymethod.compare_and_wait
+pub trait Waiters32 {
+    /// Make the current thread wait until it receives a wake signal. Guaranteed not to wake up
+    /// spuriously. Only the five non-reserved high order bits will be compared.
+    ///
+    /// See [`Waiters::compare_and_wait`] for the details.
+    ///
+    /// [`Waiters::compare_and_wait`]: trait.Waiters.html#tymethod.compare_and_wait
+    fn compare_and_wait(&self, expected: u32);
+
+    /// Wake up all waiting threads, and set `self` to `new`.
+    ///
+    /// See [`Waiters::store_and_wake`] for the details.
+    ///
+    /// # Safety
+    /// If any of the reserved bits where changed while there where threads waiting, this function
+    /// may fail to wake threads.
+    ///
+    /// [`Waiters::store_and_wake`]: trait.Waiters.html#tymethod.store_and_wake
+    unsafe fn store_and_wake(&self, new: u32);
+
+    /// Wake up all waiting threads and set `self` to `new`, but only if the value of `self` still
+    /// matches `expected`. Returns whether the value was stored.
+    ///
+    /// See [`Waiters::compare_store_and_wake`] for the details.
+    ///
+    /// # Safety
+    /// The same as for [`store_and_wake`].
+    ///
+    /// [`Waiters::compare_store_and_wake`]: trait.Waiters.html#tymethod.compare_store_and_wake
+    /// [`store_and_wake`]: #tymethod.store_and_wake
+    unsafe fn compare_store_and_wake(&self, expected: u32, new: u32) -> bool;
+
+    /// Returns `Some(0)` if no thread is waiting on `self`, and `None` if there may be threads
+    /// waiting. The operating system owns the queue of waiting threads, so we don't know more.
+    fn waiter_count(&self) -> Option<usize>;
+}
+
+impl Waiters32 for AtomicU32 {
+    fn compare_and_wait(&self, expected: u32) {
+        let expected = expected & !RESERVED_MASK_32;
+        let old = match self.compare_exchange(expected, expected | HAS_WAITERS, Relaxed, Relaxed) {
+            Ok(x) | Err(x) => x,
+        };
+        debug_check_reserved_bits(old);
+        if old & !RESERVED_MASK_32 != expected {
+            return;
+        }
+        loop {
+            let _ = self.wait_checked(expected | HAS_WAITERS, None);
+            if self.load(Relaxed) != expected | HAS_WAITERS {
+                break;
+            }
+        }
+        sanitizer::acquire(self);
+    }
+
+    unsafe fn store_and_wake(&self, new: u32) {
+        debug_assert!(new & RESERVED_MASK_32 == 0, "{}", NEW_HAS_RESERVED_BITS);
+        sanitizer::release(self);
+        let old = self.swap(new, Release);
+        debug_check_reserved_bits(old);
+        if old & HAS_WAITERS == HAS_WAITERS {
+            wake_waiters(self);
+        }
+    }
+
+    unsafe fn compare_store_and_wake(&self, expected: u32, new: u32) -> bool {
+        debug_assert!(new & RESERVED_MASK_32 == 0, "{}", NEW_HAS_RESERVED_BITS);
+        sanitizer::release(self);
+        let expected = expected & !RESERVED_MASK_32;
+        let mut current = self.load(Relaxed);
+        loop {
+            debug_check_reserved_bits(current);
+            if current & !RESERVED_MASK_32 != expected {
+                return false;
+            }
+            match self.compare_exchange_weak(current, new, Release, Relaxed) {
+                Ok(_) => break,
+                Err(x) => current = x,
+            }
+        }
+        if current & HAS_WAITERS == HAS_WAITERS {
+            wake_waiters(self);
+        }
+        true
+    }
+
+    fn waiter_count(&self) -> Option<usize> {
+        if self.load(Relaxed) & HAS_WAITERS == 0 {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+fn wake_waiters(atomic: &AtomicU32) {
+    let _r = atomic.wake();
+    #[cfg(feature = "stats")]
+    crate::stats::record_wake(_r);
+}
+
+#[inline]
+fn debug_check_reserved_bits(value: u32) {
+    debug_assert!(
+        value & RESERVED_MASK_32 & !HAS_WAITERS == 0,
+        "{}",
+        RESERVED_BITS_MODIFIED
+    );
+}
+
+const NEW_HAS_RESERVED_BITS: &str =
+    "the new value passed to `Waiters32` must not have any of the reserved bits set";
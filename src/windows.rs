@@ -23,6 +23,10 @@
 //! - A race when a thread wakes up for some reason just before another issues a release event;
 //!   the thread issueing the release can recover by setting a not too large timeout.
 //! A timeout of 100ms seems like a nice compromise, 1000 * 100ns.
+//!
+//! The waiter count is only kept in the reserved bits with Keyed Events. The `WaitOnAddress`
+//! backend only sets a single `HAS_WAITERS` flag, so registering or deregistering waiters never
+//! changes the watched word and can't cause `WaitOnAddress` to return early.
 
 #![allow(non_snake_case)]
 
@@ -40,6 +44,7 @@ use winapi::shared::ntstatus::{STATUS_ALERTED, STATUS_SUCCESS, STATUS_TIMEOUT, S
 use winapi::um::libloaderapi::{GetModuleHandleA, GetProcAddress};
 use winapi::um::winnt::{ACCESS_MASK, BOOLEAN, EVENT_ALL_ACCESS, HANDLE, LPCSTR, PHANDLE, PVOID};
 
+use crate::clock::Deadline;
 use crate::futex::{self, WakeupReason};
 use crate::utils::AtomicAsMutPtr;
 use crate::RESERVED_MASK;
@@ -50,63 +55,190 @@ use crate::RESERVED_MASK;
 pub(crate) fn compare_and_wait(atomic: &AtomicUsize, expected: usize) {
     match BACKEND.get() {
         Backend::Wait(_) => futex::compare_and_wait(atomic, expected),
-        Backend::Keyed(_) => {
-            // Register the number of threads waiting. In theory we should be careful not to
-            // overflow out of our counter bits. But it is impossible to have so many
-            // threads waiting that it doesn't fit in 2^27 on 32-bit and 2^59 on 64-bit
-            // (there would not be enough memory to hold their stacks).
+        Backend::Keyed(f) => {
+            let key = atomic.as_mut_ptr() as PVOID;
+            // Every released event is a real wakeup, that also removed this thread from the
+            // waiter count. This includes `wake_only`, which leaves the value unchanged.
+            if register_keyed_waiter(atomic, expected) {
+                f.wait(key, None);
+            }
+        }
+        Backend::None => unreachable!(),
+    }
+}
+
+pub(crate) fn compare_and_wait_timeout(
+    atomic: &AtomicUsize,
+    expected: usize,
+    deadline: &mut Deadline,
+) -> bool {
+    match BACKEND.get() {
+        Backend::Wait(_) => futex::compare_and_wait_timeout(atomic, expected, deadline),
+        Backend::Keyed(f) => {
+            let key = atomic.as_mut_ptr() as PVOID;
+            if !register_keyed_waiter(atomic, expected) {
+                return true;
+            }
+            if let WakeupReason::Unknown = f.wait(key, Some(deadline.remaining())) {
+                return true;
+            }
+            // Timed out or interrupted, take ourselves out of the waiter count again. If a waker
+            // took the whole count first, it is blocked in `release_n` until we take its release
+            // event, just like the keyed `park` after a racing `unpark`. So wait for it, that
+            // can't take long.
+            //
+            // The count doesn't say which threads it holds, so as long as it is not zero we
+            // remove one. The number of waiting threads and pending release events stays the
+            // same, but a thread that registered after a wake may take the event meant for us,
+            // and return early.
+            let mut current = atomic.load(Relaxed);
+            loop {
+                if current & RESERVED_MASK == 0 {
+                    f.wait(key, None);
+                    return true;
+                }
+                match atomic.compare_exchange_weak(current, current - 1, Relaxed, Relaxed) {
+                    Ok(_) => return current & !RESERVED_MASK != expected,
+                    Err(x) => current = x,
+                }
+            }
+        }
+        Backend::None => unreachable!(),
+    }
+}
+
+// Register the number of threads waiting. It should be impossible to have so many threads waiting
+// that it doesn't fit in 2^27 on 32-bit and 2^59 on 64-bit (there would not be enough memory to
+// hold their stacks). But an overflow would silently corrupt the free bits, so check for it with
+// debug assertions enabled.
+//
+// Returns `false` if the value no longer matches `expected`.
+fn register_keyed_waiter(atomic: &AtomicUsize, expected: usize) -> bool {
+    // Start by assuming there are no waiters yet, the `compare_exchange_weak` below does the load
+    // if that turns out to be wrong.
+    let mut current = expected;
+    loop {
+        if current & !RESERVED_MASK != expected {
+            return false;
+        }
+        debug_assert!(
+            current & RESERVED_MASK < RESERVED_MASK,
+            "too many waiters on a single atomic"
+        );
+        match atomic.compare_exchange_weak(current, current + 1, Relaxed, Relaxed) {
+            Ok(_) => return true,
+            Err(x) => current = x,
+        }
+    }
+}
+
+pub(crate) fn store_and_wake(atomic: &AtomicUsize, new: usize) {
+    match BACKEND.get() {
+        Backend::Wait(_) => futex::store_and_wake(atomic, new),
+        Backend::Keyed(f) => {
+            let wake_count = atomic.swap(new, Release) & RESERVED_MASK;
+            let key = atomic.as_mut_ptr() as PVOID;
+            f.release_n(key, wake_count);
+        }
+        Backend::None => unreachable!(),
+    }
+}
+
+pub(crate) fn compare_store_and_wake(atomic: &AtomicUsize, expected: usize, new: usize) -> bool {
+    match BACKEND.get() {
+        Backend::Wait(_) => futex::compare_store_and_wake(atomic, expected, new),
+        Backend::Keyed(f) => {
             let mut current = atomic.load(Relaxed);
             loop {
                 if current & !RESERVED_MASK != expected {
-                    return;
+                    return false;
                 }
-                match atomic.compare_exchange_weak(current, current + 1, Relaxed, Relaxed) {
+                match atomic.compare_exchange_weak(current, new, Release, Relaxed) {
                     Ok(_) => break,
                     Err(x) => current = x,
                 }
             }
             let key = atomic.as_mut_ptr() as PVOID;
+            f.release_n(key, current & RESERVED_MASK);
+            true
+        }
+        Backend::None => unreachable!(),
+    }
+}
+
+pub(crate) fn handoff_to_one(atomic: &AtomicUsize, new: usize) -> bool {
+    match BACKEND.get() {
+        Backend::Wait(_) => futex::handoff_to_one(atomic, new),
+        Backend::Keyed(f) => {
+            // Every release event wakes exactly one thread. Decrement the number of waiters by
+            // one, the others will keep waiting until the next `store_and_wake`.
+            let mut current = atomic.load(Relaxed);
             loop {
-                wait_for_keyed_event(key, None);
-                if atomic.load(Relaxed) & !RESERVED_MASK != expected {
-                    break;
+                let wake_count = current & RESERVED_MASK;
+                let remaining = wake_count.saturating_sub(1);
+                match atomic.compare_exchange_weak(current, new | remaining, Release, Relaxed) {
+                    Ok(_) => break,
+                    Err(x) => current = x,
                 }
             }
+            if current & RESERVED_MASK == 0 {
+                return false;
+            }
+            let key = atomic.as_mut_ptr() as PVOID;
+            f.release_n(key, 1);
+            true
         }
         Backend::None => unreachable!(),
     }
 }
 
-pub(crate) fn store_and_wake(atomic: &AtomicUsize, new: usize) {
+pub(crate) fn wake_only(atomic: &AtomicUsize) {
     match BACKEND.get() {
-        Backend::Wait(_) => futex::store_and_wake(atomic, new),
-        Backend::Keyed(_) => {
-            let wake_count = atomic.swap(new, Release) & RESERVED_MASK;
+        Backend::Wait(_) => futex::wake_only(atomic),
+        Backend::Keyed(f) => {
+            let wake_count = atomic.fetch_and(!RESERVED_MASK, Release) & RESERVED_MASK;
             let key = atomic.as_mut_ptr() as PVOID;
-            release_keyed_events(key, wake_count);
+            f.release_n(key, wake_count);
         }
         Backend::None => unreachable!(),
     }
 }
 
+pub(crate) fn waiter_count(atomic: &AtomicUsize) -> Option<usize> {
+    match BACKEND.get() {
+        Backend::Wait(_) => futex::waiter_count(atomic),
+        Backend::Keyed(_) => Some(atomic.load(Relaxed) & RESERVED_MASK),
+        Backend::None => unreachable!(),
+    }
+}
+
+// The keyed events backend keeps a count of the waiting threads in the reserved bits.
+pub(crate) fn max_supported_waiters() -> usize {
+    match BACKEND.get() {
+        Backend::Keyed(_) => RESERVED_MASK,
+        _ => usize::MAX,
+    }
+}
+
 //
 // Implementation of the Parker trait
 //
 pub(crate) type Parker = AtomicI32;
 
+// States for Parker. These are part of the stable layout of `crate::Parker`, and must not change.
 const NOT_PARKED: i32 = 0x0;
 const PARKED: i32 = 0x1;
 const NOTIFIED: i32 = 0x2;
 
-pub(crate) fn park(atomic: &AtomicI32, timeout: Option<Duration>) {
+pub(crate) fn park(atomic: &AtomicI32, timeout: Option<Duration>) -> WakeupReason {
     match BACKEND.get() {
         Backend::Wait(_) => futex::park(atomic, timeout),
-        Backend::Keyed(_) => {
-            match atomic.compare_exchange(NOT_PARKED, PARKED, Release, Relaxed) {
+        Backend::Keyed(f) => {
+            match atomic.compare_exchange(NOT_PARKED, PARKED, Release, Acquire) {
                 Ok(_) => {}
                 Err(NOTIFIED) => {
-                    atomic.store(NOT_PARKED, Relaxed);
-                    return;
+                    atomic.swap(NOT_PARKED, Acquire);
+                    return WakeupReason::WokenUp;
                 }
                 Err(_) => panic!(
                     "Tried to call park on an atomic while \
@@ -115,22 +247,24 @@ pub(crate) fn park(atomic: &AtomicI32, timeout: Option<Duration>) {
             };
             let key = atomic.as_mut_ptr() as PVOID;
             loop {
-                let r = wait_for_keyed_event(key, timeout);
+                let r = f.wait(key, timeout);
                 if timeout.is_some() {
                     // We don't guarantee there are no spurious wakeups when there was a timeout
                     // supplied.
-                    atomic.store(NOT_PARKED, Relaxed);
-                    break;
+                    if atomic.swap(NOT_PARKED, Acquire) == NOTIFIED {
+                        return WakeupReason::WokenUp;
+                    }
+                    return r;
                 }
                 if let WakeupReason::Unknown = r {
                     // The wakeup was not caused by an alert ot timeout, we know (almost) for sure
                     // if the status is set to NOTIFIED. But this remains inherently racy, see
                     // the `compare_and_wait` implementation.
                     if atomic
-                        .compare_exchange(NOTIFIED, NOT_PARKED, Relaxed, Relaxed)
+                        .compare_exchange(NOTIFIED, NOT_PARKED, Acquire, Relaxed)
                         .is_ok()
                     {
-                        break;
+                        return WakeupReason::WokenUp;
                     }
                 }
             }
@@ -139,28 +273,44 @@ pub(crate) fn park(atomic: &AtomicI32, timeout: Option<Duration>) {
     }
 }
 
-pub(crate) fn unpark(atomic: &AtomicI32) {
+// Both backends use the same states as the futex implementation.
+pub(crate) use futex::{clear_notification, is_notified, transfer_waiter};
+
+pub(crate) fn unpark(atomic: &AtomicI32) -> bool {
     match BACKEND.get() {
-        Backend::Wait(_) => futex::unpark(atomic),
-        Backend::Keyed(_) => {
+        Backend::Wait(f) => {
+            // Only one thread can be parked on a `Parker`, so there is no need to wake all.
             if atomic.swap(NOTIFIED, Release) == PARKED {
+                (f.WakeByAddressSingle)(atomic.as_mut_ptr() as PVOID);
+                true
+            } else {
+                false
+            }
+        }
+        Backend::Keyed(f) => {
+            if atomic.swap(NOTIFIED, Release) == PARKED {
+                // If the release times out, the parked thread returned because of its own timeout.
                 let key = atomic.as_mut_ptr() as PVOID;
-                release_keyed_events(key, 1);
+                f.release(key)
+            } else {
+                false
             }
         }
         Backend::None => unreachable!(),
     }
 }
 
-fn wait_for_keyed_event(key: PVOID, timeout: Option<Duration>) -> WakeupReason {
-    debug_assert!(mem::align_of::<AtomicUsize>() >= 4);
-    if let Backend::Keyed(f) = BACKEND.get() {
+// The functions take the resolved `KeyedEvent`, so the loops that release many events don't check
+// the backend on every iteration.
+impl KeyedEvent {
+    fn wait(&self, key: PVOID, timeout: Option<Duration>) -> WakeupReason {
+        debug_assert!(mem::align_of::<AtomicUsize>() >= 4);
         let mut nt_timeout = convert_timeout_100ns(timeout);
         let timeout_ptr = nt_timeout
             .as_mut()
             .map(|t_ref| t_ref as PLARGE_INTEGER)
             .unwrap_or(ptr::null_mut());
-        let r = (f.NtWaitForKeyedEvent)(f.handle, key, FALSE, timeout_ptr);
+        let r = (self.NtWaitForKeyedEvent)(self.handle, key, FALSE, timeout_ptr);
         // `NtWaitForKeyedEvent` is an undocumented API where we don't known the possible
         // return values, but they are most likely similar to `NtWaitForSingleObject`.
         match r {
@@ -176,23 +326,39 @@ fn wait_for_keyed_event(key: PVOID, timeout: Option<Duration>) -> WakeupReason {
                 WakeupReason::Unknown
             }
         }
-    } else {
-        unreachable!();
     }
-}
 
-fn release_keyed_events(key: PVOID, wake_count: usize) {
-    debug_assert!(mem::align_of::<AtomicUsize>() >= 4);
-    let mut timeout: LARGE_INTEGER = -1000; // relative duration of 1000 * 100ns.
-    if let Backend::Keyed(f) = BACKEND.get() {
+    // `NtReleaseKeyedEvent` blocks until a thread waits on the key, or until the timeout expires.
+    // Returns `false` if no thread started waiting before the timeout.
+    fn release(&self, key: PVOID) -> bool {
+        debug_assert!(mem::align_of::<AtomicUsize>() >= 4);
+        let mut timeout: LARGE_INTEGER = -1000; // relative duration of 1000 * 100ns.
+        (self.NtReleaseKeyedEvent)(self.handle, key, 0, &mut timeout) != STATUS_TIMEOUT
+    }
+
+    // Release `wake_count` threads registered as waiting by `compare_and_wait`.
+    //
+    // There is no broadcast for keyed events, every release wakes exactly one thread. But a release
+    // returns as soon as it is matched with a waiting thread, so a broadcast only blocks on threads
+    // that registered themselves but did not reach `NtWaitForKeyedEvent` yet. `compare_and_wait`
+    // waits without a timeout, and `compare_and_wait_timeout` waits for the event if it finds
+    // itself counted after a timeout, so such a thread will get there eventually: retry on a
+    // timeout instead of losing its wakeup.
+    //
+    // That only holds if `wake_count` is never more than the number of threads registered. Give up
+    // on a release after `RELEASE_ATTEMPTS`, so a broken count makes the waker slow instead of
+    // blocking it forever.
+    fn release_n(&self, key: PVOID, wake_count: usize) {
         for _ in 0..wake_count {
-            (f.NtReleaseKeyedEvent)(f.handle, key, 0, &mut timeout);
+            let released = (0..RELEASE_ATTEMPTS).any(|_| self.release(key));
+            debug_assert!(released, "no thread waited for a keyed event release");
         }
-    } else {
-        unreachable!();
     }
 }
 
+// The number of 100ms release attempts before `release_n` assumes there is no thread to wake.
+const RELEASE_ATTEMPTS: usize = 100;
+
 // NT uses a timeout in units of 100ns, where positive values are absolute and negative values are
 // relative.
 // The value of an absolute timeout matches `FILETIME` as used by `GetSystemTimeAsFileTimeAbsolute`;
@@ -201,7 +367,7 @@ fn release_keyed_events(key: PVOID, wake_count: usize) {
 fn convert_timeout_100ns(timeout: Option<Duration>) -> Option<LARGE_INTEGER> {
     match timeout {
         Some(duration) => {
-            if duration.as_secs() > i64::max_value() as u64 {
+            if duration.as_secs() > i64::MAX as u64 {
                 return None;
             }
             // Checked operations that return `None` on overflow.
@@ -233,6 +399,7 @@ impl BackendStatic {
         }
     }
 
+    #[inline]
     pub(crate) fn get(&self) -> Backend {
         if self.status.load(Acquire) == READY {
             return self.backend.get();
@@ -240,9 +407,17 @@ impl BackendStatic {
         self.init()
     }
 
+    // Only the thread that moves the status from `EMPTY` to `INITIALIZING` probes the backends,
+    // all other threads wait until it is done. So there is never more than one global keyed event
+    // handle created, and as it lives until the process exits it is never leaked.
     #[inline(never)]
     fn init(&self) -> Backend {
-        let mut status = self.status.compare_and_swap(EMPTY, INITIALIZING, Acquire);
+        let mut status = match self
+            .status
+            .compare_exchange(EMPTY, INITIALIZING, Acquire, Acquire)
+        {
+            Ok(x) | Err(x) => x,
+        };
         if status == EMPTY {
             let backend = if let Some(res) = ProbeWaitAddress() {
                 Backend::Wait(res)
@@ -292,6 +467,7 @@ pub(crate) struct WaitAddress {
         dwMilliseconds: DWORD,
     ) -> BOOL,
     pub(crate) WakeByAddressAll: extern "system" fn(Address: PVOID),
+    pub(crate) WakeByAddressSingle: extern "system" fn(Address: PVOID),
 }
 
 #[derive(Clone, Copy)]
@@ -314,7 +490,7 @@ pub(crate) struct KeyedEvent {
 #[cfg(not(feature = "fallback"))]
 fn ProbeWaitAddress() -> Option<WaitAddress> {
     unsafe {
-        // MSDN claims that that WaitOnAddress and WakeByAddressAll are
+        // MSDN claims that that WaitOnAddress and WakeByAddress* are
         // located in kernel32.dll, but they aren't...
         let synch_dll = GetModuleHandleA(b"api-ms-win-core-synch-l1-2-0.dll\0".as_ptr() as LPCSTR);
         if synch_dll.is_null() {
@@ -329,10 +505,16 @@ fn ProbeWaitAddress() -> Option<WaitAddress> {
         if WakeByAddressAll.is_null() {
             return None;
         }
+        let WakeByAddressSingle =
+            GetProcAddress(synch_dll, b"WakeByAddressSingle\0".as_ptr() as LPCSTR);
+        if WakeByAddressSingle.is_null() {
+            return None;
+        }
 
         Some(WaitAddress {
             WaitOnAddress: mem::transmute(WaitOnAddress),
             WakeByAddressAll: mem::transmute(WakeByAddressAll),
+            WakeByAddressSingle: mem::transmute(WakeByAddressSingle),
         })
     }
 }
@@ -375,11 +557,161 @@ fn ProbeKeyedEvent() -> Option<KeyedEvent> {
         if status != STATUS_SUCCESS {
             return None;
         }
+        #[cfg(test)]
+        test::KEYED_EVENT_HANDLES.fetch_add(1, Relaxed);
 
         Some(KeyedEvent {
-            handle: handle,
+            handle,
             NtReleaseKeyedEvent: mem::transmute(NtReleaseKeyedEvent),
             NtWaitForKeyedEvent: mem::transmute(NtWaitForKeyedEvent),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::BACKEND;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread::spawn;
+
+    pub(super) static KEYED_EVENT_HANDLES: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    // Multiple waiters must not change the word watched by `WaitOnAddress`, or they would keep
+    // waking each other up.
+    fn wait_address_does_not_count_waiters() {
+        use super::Backend;
+        use crate::{Waiters, RESERVED_BITS, RESERVED_MASK};
+        use std::thread::sleep;
+        use std::time::Duration;
+
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        if let Backend::Keyed(_) = BACKEND.get() {
+            return;
+        }
+        let threads: Vec<_> = (0..4)
+            .map(|_| spawn(|| ATOMIC.compare_and_wait(0)))
+            .collect();
+        let mut reserved = 0;
+        for _ in 0..100 {
+            reserved = ATOMIC.load(Relaxed) & RESERVED_MASK;
+            assert!(reserved.count_ones() <= 1);
+            sleep(Duration::from_millis(1));
+        }
+        assert_ne!(reserved, 0);
+        unsafe { ATOMIC.store_and_wake(1 << RESERVED_BITS) };
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    // A broadcast to many waiters does one release per waiter, but each of them should return
+    // right away once the waiters are sleeping.
+    fn keyed_broadcast_latency() {
+        use super::Backend;
+        use crate::{Waiters, RESERVED_BITS, RESERVED_MASK};
+        use std::thread::yield_now;
+        use std::time::{Duration, Instant};
+
+        const WAITERS: usize = 64;
+        static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+        if let Backend::Wait(_) = BACKEND.get() {
+            return;
+        }
+        let threads: Vec<_> = (0..WAITERS)
+            .map(|_| spawn(|| ATOMIC.compare_and_wait(0)))
+            .collect();
+        while ATOMIC.load(Relaxed) & RESERVED_MASK < WAITERS {
+            yield_now();
+        }
+        let start = Instant::now();
+        unsafe { ATOMIC.store_and_wake(1 << RESERVED_BITS) };
+        let elapsed = start.elapsed();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(
+            elapsed < Duration::from_secs(1),
+            "broadcast took {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    // The `Futex` impls report that they are unsupported with keyed events, instead of panicking.
+    fn futex_unsupported_with_keyed_events() {
+        use super::Backend;
+        use crate::{Futex, FutexError};
+        use std::sync::atomic::AtomicU32;
+
+        if let Backend::Wait(_) = BACKEND.get() {
+            return;
+        }
+        let futex = AtomicU32::new(0);
+        assert_eq!(futex.wait(0, None), Err(FutexError::Unsupported));
+        assert_eq!(futex.wake(), Err(FutexError::Unsupported));
+        assert_eq!(futex.wake_one(), Err(FutexError::Unsupported));
+        assert_eq!(futex.wake_n(2), Err(FutexError::Unsupported));
+    }
+
+    #[test]
+    fn futex_atomic_bool() {
+        use super::Backend;
+        use crate::Futex;
+        use std::sync::atomic::AtomicBool;
+
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        if let Backend::Keyed(_) = BACKEND.get() {
+            return;
+        }
+        FLAG.store(true, Relaxed);
+        // Returns right away, `WaitOnAddress` compares a single byte.
+        assert!(FLAG.wait(false, None).is_ok());
+        FLAG.store(false, Relaxed);
+        let thread = spawn(|| {
+            while !FLAG.load(Relaxed) {
+                FLAG.wait(false, None).unwrap();
+            }
+        });
+        FLAG.store(true, Relaxed);
+        FLAG.wake().unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn single_keyed_event_handle() {
+        let threads: Vec<_> = (0..32)
+            .map(|_| {
+                spawn(|| {
+                    BACKEND.get();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(KEYED_EVENT_HANDLES.load(Relaxed) <= 1);
+    }
+
+    #[test]
+    // Relative timeouts are negative, and round up to 100 ns. Durations that overflow become
+    // infinite instead of wrapping around to a positive, absolute, deadline.
+    fn timeout_100ns_bounds() {
+        use super::convert_timeout_100ns;
+        use std::time::Duration;
+
+        let convert = |duration| convert_timeout_100ns(Some(duration));
+        assert_eq!(convert_timeout_100ns(None), None);
+        assert_eq!(convert(Duration::from_nanos(0)), Some(0));
+        assert_eq!(convert(Duration::from_nanos(1)), Some(-1));
+        assert_eq!(convert(Duration::from_nanos(101)), Some(-2));
+        assert_eq!(convert(Duration::from_secs(1)), Some(-10_000_000));
+        let max_secs = i64::MAX as u64 / 10_000_000;
+        assert!(convert(Duration::from_secs(max_secs)).is_some());
+        assert_eq!(convert(Duration::from_secs(max_secs + 1)), None);
+        assert_eq!(convert(Duration::from_secs(i64::MAX as u64 + 1)), None);
+        assert_eq!(convert(Duration::new(u64::MAX, 999_999_999)), None);
+    }
+}
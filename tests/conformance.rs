@@ -0,0 +1,110 @@
+//! Conformance tests for the documented contract of `Parker` and `Waiters`.
+//!
+//! These tests only use the public API, so they exercise whichever backend is selected for the
+//! target (and for `--features fallback`). A new backend is validated by running `cargo test` on
+//! its target in CI.
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use valet_parking::{Parker, Waiters, RESERVED_BITS, RESERVED_MASK};
+
+// Generous upper bound on how late a timeout may expire, to not fail on busy CI machines.
+const TOLERANCE: Duration = Duration::from_secs(1);
+
+#[test]
+fn park_without_timeout_only_returns_after_unpark() {
+    static PARKER: Parker = Parker::new();
+    static UNPARKED: AtomicBool = AtomicBool::new(false);
+
+    let unparker = spawn(|| {
+        sleep(Duration::from_millis(50));
+        UNPARKED.store(true, Ordering::Relaxed);
+        PARKER.unpark();
+    });
+    PARKER.park(None);
+    assert!(UNPARKED.load(Ordering::Relaxed));
+    unparker.join().unwrap();
+}
+
+#[test]
+fn park_timeout_expires_within_tolerance() {
+    let parker = Parker::new();
+    for &timeout in &[Duration::from_millis(1), Duration::from_millis(50)] {
+        let start = Instant::now();
+        parker.park(Some(timeout));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= timeout, "returned early after {:?}", elapsed);
+        assert!(
+            elapsed < timeout + TOLERANCE,
+            "returned late after {:?}",
+            elapsed
+        );
+    }
+}
+
+#[test]
+fn unpark_before_park_is_consumed() {
+    let parker = Parker::new();
+    parker.unpark();
+    // Returns immediately.
+    parker.park(None);
+    // The notification is consumed, so this has to wait for the timeout.
+    let timeout = Duration::from_millis(20);
+    let start = Instant::now();
+    parker.park(Some(timeout));
+    assert!(start.elapsed() >= timeout);
+}
+
+#[test]
+#[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+fn store_and_wake_wakes_all_waiters() {
+    let atomic = Arc::new(AtomicUsize::new(0));
+    let woken = Arc::new(AtomicUsize::new(0));
+    let waiters: Vec<_> = (0..8)
+        .map(|_| {
+            let atomic = atomic.clone();
+            let woken = woken.clone();
+            spawn(move || {
+                atomic.compare_and_wait(0);
+                woken.fetch_add(1, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    sleep(Duration::from_millis(50));
+    assert_eq!(woken.load(Ordering::Relaxed), 0);
+    unsafe { atomic.store_and_wake(1 << RESERVED_BITS) };
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert_eq!(woken.load(Ordering::Relaxed), 8);
+}
+
+#[test]
+fn compare_and_wait_returns_on_mismatch() {
+    let atomic = AtomicUsize::new(1 << RESERVED_BITS);
+    // Would hang if it parked.
+    atomic.compare_and_wait(0);
+    assert_eq!(atomic.load(Ordering::Relaxed), 1 << RESERVED_BITS);
+}
+
+#[test]
+#[cfg_attr(all(miri, not(feature = "fallback")), ignore)] // Mixed-size atomic access
+fn free_bits_are_preserved() {
+    let atomic = Arc::new(AtomicUsize::new(0b10110 << RESERVED_BITS));
+    let waiter = {
+        let atomic = atomic.clone();
+        spawn(move || atomic.compare_and_wait(0b10110 << RESERVED_BITS))
+    };
+    sleep(Duration::from_millis(50));
+    // Waiting threads may only use the reserved bits.
+    assert_eq!(
+        atomic.load(Ordering::Relaxed) & !RESERVED_MASK,
+        0b10110 << RESERVED_BITS
+    );
+    unsafe { atomic.store_and_wake(0b01001 << RESERVED_BITS) };
+    waiter.join().unwrap();
+    // With no threads waiting all reserved bits are clear again.
+    assert_eq!(atomic.load(Ordering::Relaxed), 0b01001 << RESERVED_BITS);
+}
@@ -0,0 +1,33 @@
+//! `set_default_park_spins` changes a process-global setting, so it gets a test binary of its own.
+use std::sync::atomic::{fence, AtomicBool, Ordering};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use valet_parking::{set_default_park_spins, Parker};
+
+// With an unbounded number of spins `park_if` only stops spinning once it got unparked, so its
+// condition is checked after the `unpark`. Without spinning it would be checked right away.
+#[test]
+fn park_spins_until_unparked() {
+    static PARKER: Parker = Parker::new();
+    static UNPARKED: AtomicBool = AtomicBool::new(false);
+
+    set_default_park_spins(u32::MAX);
+    let unparker = spawn(|| {
+        sleep(Duration::from_millis(10));
+        UNPARKED.store(true, Ordering::Relaxed);
+        PARKER.unpark();
+    });
+    let mut unparked_before_check = false;
+    PARKER.park_if(
+        || {
+            // Synchronizes with the `unpark` the spinning observed.
+            fence(Ordering::Acquire);
+            unparked_before_check = UNPARKED.load(Ordering::Relaxed);
+            true
+        },
+        None,
+    );
+    assert!(unparked_before_check);
+    unparker.join().unwrap();
+}
@@ -0,0 +1,55 @@
+//! `wake_all_parkers` unparks every thread in the process, so it gets a test binary of its own.
+#![cfg(feature = "debug-registry")]
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{sleep, spawn, yield_now};
+use std::time::Duration;
+
+use valet_parking::registry::wake_all_parkers;
+use valet_parking::{Parker, Waiters, RESERVED_BITS, RESERVED_MASK};
+
+#[test]
+fn wake_all_parkers_at_shutdown() {
+    static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+    let workers: Vec<_> = (0..8)
+        .map(|_| {
+            spawn(|| {
+                // Every worker has its own parker, which is freed when it exits.
+                let parker = Parker::new();
+                while !SHUTDOWN.load(Ordering::Relaxed) {
+                    parker.park_if(|| !SHUTDOWN.load(Ordering::Relaxed), None);
+                }
+            })
+        })
+        .collect();
+    sleep(Duration::from_millis(10));
+    SHUTDOWN.store(true, Ordering::Relaxed);
+    wake_all_parkers();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[test]
+// The fallback implementation of `Waiters` parks every waiter on a parker in a node on its stack.
+// `wake_all_parkers` must not reach those: the waiter would return while its node is still queued.
+fn wake_all_parkers_skips_waiters() {
+    static ATOMIC: AtomicUsize = AtomicUsize::new(0);
+    static RETURNED: AtomicBool = AtomicBool::new(false);
+
+    let waiter = spawn(|| {
+        ATOMIC.compare_and_wait(0);
+        RETURNED.store(true, Ordering::Relaxed);
+    });
+    while ATOMIC.load(Ordering::Relaxed) & RESERVED_MASK == 0 {
+        yield_now();
+    }
+    sleep(Duration::from_millis(10));
+    wake_all_parkers();
+    sleep(Duration::from_millis(10));
+    assert!(!RETURNED.load(Ordering::Relaxed));
+    unsafe { ATOMIC.store_and_wake(1 << RESERVED_BITS) };
+    waiter.join().unwrap();
+    assert!(RETURNED.load(Ordering::Relaxed));
+}